@@ -1,26 +1,32 @@
+use arc_swap::ArcSwap;
 use axum::{
     Router,
-    body::Bytes,
-    extract::{ConnectInfo, State},
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Query, State},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::post,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post},
 };
+use futures_util::{FutureExt, StreamExt};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use subtle::ConstantTimeEq;
-use tracing::{error, info, warn};
+use tracing::{Instrument, debug, error, info, warn};
 
 /// Normalizes a domain name by removing the trailing dot if present.
 ///
 /// In DNS, `example.com.` and `example.com` should be treated as the same domain.
-/// This function normalizes domain names for internal comparisons while we maintain
-/// proper FQDN format (with trailing dot) when writing to Unbound config.
+/// This function normalizes domain names for internal comparisons. Entries are
+/// written without the trailing dot unless `trailing_dot` is set, and existing
+/// entries are matched in either form.
 ///
 /// # Arguments
 /// * `domain` - The domain name to normalize
@@ -35,705 +41,12937 @@ fn normalize_domain(domain: &str) -> String {
 struct Config {
     unbound_config_path: PathBuf,
     domains: Vec<DomainConfig>,
+    /// Shared secret a trusted reverse proxy sends in `X-Proxy-Token`. When set,
+    /// forwarded client IP headers are only honored if the token matches.
+    #[serde(default)]
+    proxy_token: Option<String>,
+    /// Minimum free space (in bytes) required on the Unbound config's filesystem before
+    /// an update is written.
+    #[serde(default)]
+    min_free_bytes: Option<u64>,
+    /// Address (or list of addresses) the HTTP server listens on. May also be spelled
+    /// `listen`.
+    #[serde(default, alias = "listen")]
+    listen_addr: ListenAddr,
+    /// Whether to reload Unbound after writing. Disable when something else watches
+    /// the file and reloads Unbound itself.
+    #[serde(default = "default_reload_enabled")]
+    reload_enabled: bool,
+    /// Command run instead of `unbound-control reload`, as a program followed by its
+    /// arguments. A domain's own `reload_command` takes precedence.
+    #[serde(default)]
+    reload_command: Option<Vec<String>>,
+    /// Minimum time between two reloads across the whole service. Reloads requested
+    /// sooner are deferred until the interval has passed.
+    #[serde(default)]
+    min_reload_interval_secs: Option<u64>,
+    /// How long to wait after a successful reload before responding, for Unbound
+    /// setups that apply the reload asynchronously.
+    #[serde(default)]
+    post_reload_delay_ms: u64,
+    /// After reloading, query `verify_resolution_server` until the domain resolves to
+    /// the new IP, failing the update if it doesn't within `verify_resolution_timeout_ms`.
+    #[serde(default)]
+    verify_resolution: bool,
+    /// DNS server (`host:port`) queried by `verify_resolution`.
+    #[serde(default = "default_verify_resolution_server")]
+    verify_resolution_server: String,
+    #[serde(default = "default_verify_resolution_timeout_ms")]
+    verify_resolution_timeout_ms: u64,
+    /// Whether internal error details (file paths, OS errors, unbound-control output)
+    /// are returned to clients. They are always logged.
+    #[serde(default)]
+    verbose_errors: bool,
+    /// Maximum updates accepted per domain in any one-minute window.
+    #[serde(default)]
+    max_updates_per_minute: Option<u32>,
+    /// Whether to serve Prometheus metrics at `/metrics`.
+    #[serde(default)]
+    metrics_enabled: bool,
+    /// Whether to convert Unicode (IDN) domain names to their ASCII punycode form,
+    /// which is what Unbound expects.
+    #[serde(default)]
+    normalize_idn: bool,
+    /// Whether to serve `/server-ip`, which makes outbound requests to an IP echo service.
+    #[serde(default)]
+    server_ip_enabled: bool,
+    /// Plain-HTTP service that responds with the caller's IP address as text.
+    #[serde(default = "default_server_ip_echo_url")]
+    server_ip_echo_url: String,
+    /// How long a resolved server IP is cached.
+    #[serde(default = "default_server_ip_cache_secs")]
+    server_ip_cache_secs: u64,
+    /// Maximum number of simultaneously open client connections across all listeners.
+    /// Further connections wait in the accept backlog until a slot frees up.
+    #[serde(default)]
+    max_connections: Option<usize>,
+    /// Seconds a connection may take to send a request's headers, counted from when it
+    /// opens or finishes its previous request, so idle connections don't hold a slot.
+    #[serde(default = "default_header_read_timeout_secs")]
+    header_read_timeout_secs: u64,
+    /// Expect a PROXY protocol (v1 or v2) header on every public connection and
+    /// use the client address it carries instead of the peer address.
+    #[serde(default)]
+    proxy_protocol: bool,
+    /// Maximum combined size of a request's headers in bytes. Larger requests are
+    /// rejected with 431 before reaching a handler.
+    #[serde(default)]
+    max_header_bytes: Option<usize>,
+    /// Seconds after startup during which the public endpoints answer 503, to let
+    /// dependencies settle before traffic is handled.
+    #[serde(default)]
+    startup_delay_secs: u64,
+    /// `Retry-After` seconds sent with the 503 answering an update while another
+    /// process holds a lock on the Unbound config.
+    #[serde(default = "default_locked_retry_after_secs")]
+    locked_retry_after_secs: u64,
+    /// Separate address for admin endpoints such as `/metrics`. When set, those
+    /// endpoints are only served here and not on `listen_addr`.
+    #[serde(default)]
+    admin_listen_addr: Option<String>,
+    /// Answer updates that leave the record unchanged with a bare `nochg` and log them
+    /// at debug level, so frequent pollers don't flood the logs.
+    #[serde(default)]
+    quiet_noop: bool,
+    /// Answer updates that leave the record unchanged with 304 Not Modified and no body.
+    #[serde(default)]
+    noop_status_304: bool,
+    /// Custom line format for written entries. See [`EntryFormat`].
+    #[serde(default)]
+    entry_template: Option<String>,
+    /// Regex recognizing entries written with `entry_template`. See [`EntryFormat`].
+    #[serde(default)]
+    entry_match_regex: Option<String>,
+    /// Whether written names end in a dot (`home.example.com.`). Existing entries are
+    /// found in either form.
+    #[serde(default)]
+    trailing_dot: bool,
+    /// Read the Unbound config back after each write and fail the update with 500
+    /// unless the new entries are there.
+    #[serde(default)]
+    verify_after_write: bool,
+    /// Put the `local-data` entries of configured domains in a canonical order on every
+    /// write, sorted by domain, record type and value, for stable diffs.
+    #[serde(default)]
+    sort_managed_entries: bool,
+    /// Add a domain's first AAAA (or A) entry after the last entry of that type in the
+    /// file, keeping the record types in blocks, rather than beside the domain's own entry.
+    #[serde(default)]
+    group_by_type: bool,
+    /// Accept the domain and IP from `X-Ddns-Domain` / `X-Ddns-IP` headers when the
+    /// request body is empty, for clients that cannot send a body.
+    #[serde(default)]
+    header_params: bool,
+    /// HTTP status for successful updates: 200 with a JSON body, or 204 with no body.
+    #[serde(default = "default_success_status")]
+    success_status: u16,
+    /// Bearer token for `/diagnostics`. The endpoint is only served when this is set.
+    #[serde(default)]
+    diagnostics_token: Option<String>,
+    /// Serve `GET /events`, a Server-Sent Events stream of update outcomes authenticated
+    /// with `diagnostics_token`.
+    #[serde(default)]
+    events_enabled: bool,
+    /// Whether `/diagnostics` also reports the installed Unbound version.
+    #[serde(default)]
+    diagnostics_unbound_version: bool,
+    /// Maximum number of A records written for one domain. A `backup_ip` needs at least 2.
+    #[serde(default = "default_max_records_per_domain")]
+    max_records_per_domain: usize,
+    /// Create the Unbound config at startup, with placeholder entries for every domain,
+    /// if it doesn't exist yet.
+    #[serde(default)]
+    create_unbound_file_if_missing: bool,
+    /// Change-freeze windows during which updates are rejected with 503.
+    #[serde(default)]
+    freeze_schedule: Vec<FreezeWindow>,
+    /// Largest TTL a client may request with the `ttl` update parameter.
+    #[serde(default)]
+    max_request_ttl: Option<u32>,
+    /// Serve an unauthenticated HTML status page at `/`, listing domains and their IPs.
+    #[serde(default)]
+    status_page_enabled: bool,
+    /// File where the last-known records of each domain are kept across restarts.
+    #[serde(default)]
+    state_path: Option<PathBuf>,
+    /// Write IPv4-mapped IPv6 addresses (e.g. `::ffff:203.0.113.5`, as seen behind NAT64)
+    /// as their embedded IPv4 address.
+    #[serde(default)]
+    unwrap_mapped_v4: bool,
+    /// Separate TOML file mapping domain names to keys, so that this file can be
+    /// kept free of secrets.
+    #[serde(default)]
+    keys_file: Option<PathBuf>,
+    /// Format of the access log line emitted for every request.
+    #[serde(default)]
+    access_log_format: AccessLogFormat,
+    /// Whether update responses echo back the domain, IP source and record type.
+    #[serde(default)]
+    verbose_response: bool,
+    /// Whether a missing `unbound-control` binary only skips the reload (with a warning)
+    /// instead of failing the update.
+    #[serde(default)]
+    ignore_missing_unbound_control: bool,
+    /// Whether validation requires each domain to fall under a `local-zone` in its
+    /// Unbound file, so we only manage names this Unbound instance serves.
+    #[serde(default)]
+    require_local_zone: bool,
+    /// Domains whose updates are refused with 403 without removing their config.
+    #[serde(default)]
+    denied_domains: Vec<String>,
+    /// When set, only these domains may be updated; others are refused with 403.
+    #[serde(default)]
+    allowed_domains: Option<Vec<String>>,
+    /// Named sets of domains updated together by one request.
+    #[serde(default)]
+    domain_groups: Vec<DomainGroup>,
+    /// Whether `/update` bodies without a Content-Type are rejected instead of being
+    /// parsed as form data.
+    #[serde(default)]
+    require_content_type: bool,
+    /// Whether whitespace around the submitted key is ignored, for keys pasted with a
+    /// stray space or newline. Configured keys are never trimmed.
+    #[serde(default = "default_trim_submitted_key")]
+    trim_submitted_key: bool,
+    /// `Cache-Control` value sent by read endpoints. Empty to send none.
+    #[serde(default = "default_cache_control")]
+    cache_control: String,
+    /// How long the response to an `/update` carrying an `Idempotency-Key` header is
+    /// replayed for repeats of that key. 0 disables replaying.
+    #[serde(default = "default_idempotency_ttl_secs")]
+    idempotency_ttl_secs: u64,
+    /// Let identical `/update` requests that arrive while one is being applied wait
+    /// for its result instead of applying the update again.
+    #[serde(default)]
+    coalesce_updates: bool,
+    /// Command (program and arguments) that prints a domain's key on stdout, given the
+    /// domain as a final argument. Used instead of the configured keys when set.
+    #[serde(default)]
+    key_lookup_command: Option<Vec<String>>,
+    /// How long keys returned by `key_lookup_command` are reused.
+    #[serde(default = "default_key_lookup_cache_secs")]
+    key_lookup_cache_secs: u64,
+    /// DNS name whose TXT records list further domains to manage, next to `domains`.
+    #[serde(default)]
+    domain_discovery_txt: Option<String>,
+    /// How often `domain_discovery_txt` is resolved again.
+    #[serde(default = "default_domain_discovery_interval_secs")]
+    domain_discovery_interval_secs: u64,
+    /// Keys from `keys_file` for domains that aren't configured, kept for the domains
+    /// `domain_discovery_txt` may add.
+    #[serde(skip)]
+    discovery_keys: HashMap<String, String>,
+    /// When set, only one update per domain is in flight at a time; others wait up to
+    /// this long for it and are then refused with 409.
+    #[serde(default)]
+    domain_lock_timeout_ms: Option<u64>,
+    /// Path of the health check endpoint.
+    #[serde(default = "default_health_path")]
+    health_path: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum AccessLogFormat {
+    /// Structured tracing fields, like the rest of the logs.
+    #[default]
+    Tracing,
+    /// Apache common log format.
+    Common,
+    /// Apache combined log format (common plus referer and user agent).
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+fn default_max_records_per_domain() -> usize {
+    1
+}
+
+fn default_success_status() -> u16 {
+    200
+}
+
+fn default_server_ip_echo_url() -> String {
+    "http://checkip.amazonaws.com/".to_string()
+}
+
+fn default_server_ip_cache_secs() -> u64 {
+    300
+}
+
+fn default_cache_control() -> String {
+    "no-store".to_string()
+}
+
+fn default_key_lookup_cache_secs() -> u64 {
+    60
+}
+
+fn default_domain_discovery_interval_secs() -> u64 {
+    300
+}
+
+fn default_health_path() -> String {
+    "/health".to_string()
+}
+
+fn default_idempotency_ttl_secs() -> u64 {
+    300
+}
+
+fn default_locked_retry_after_secs() -> u64 {
+    5
+}
+
+fn default_header_read_timeout_secs() -> u64 {
+    30
+}
+
+fn default_verify_resolution_server() -> String {
+    "127.0.0.1:53".to_string()
+}
+
+fn default_verify_resolution_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_reload_enabled() -> bool {
+    true
+}
+
+fn default_trim_submitted_key() -> bool {
+    true
+}
+
+/// One or more socket addresses to bind, e.g. `"0.0.0.0:3000"` or
+/// `["0.0.0.0:3000", "[::]:3000"]`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+enum ListenAddr {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Default for ListenAddr {
+    fn default() -> Self {
+        ListenAddr::Single("0.0.0.0:3000".to_string())
+    }
+}
+
+impl ListenAddr {
+    fn addrs(&self) -> Vec<&str> {
+        match self {
+            ListenAddr::Single(addr) => vec![addr.as_str()],
+            ListenAddr::Multiple(addrs) => addrs.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
 struct DomainConfig {
+    name: String,
+    /// May be omitted when the key is provided by `keys_file` instead.
+    #[serde(default)]
+    key: String,
+    /// Record TTL in seconds. `Some(0)` is written explicitly; `None` omits the TTL.
+    #[serde(default)]
+    ttl: Option<u32>,
+    /// TTL for A records, overriding `ttl`.
+    #[serde(default)]
+    ttl_v4: Option<u32>,
+    /// TTL for AAAA records, overriding `ttl`.
+    #[serde(default)]
+    ttl_v6: Option<u32>,
+    /// Unbound file holding this domain's entries, overriding `unbound_config_path`.
+    #[serde(default)]
+    config_path: Option<PathBuf>,
+    /// Updates per minute allowed for this domain, overriding `max_updates_per_minute`.
+    #[serde(default)]
+    rate_limit: Option<u32>,
+    /// Command run instead of `unbound-control reload` after this domain is written,
+    /// as a program followed by its arguments.
+    #[serde(default)]
+    reload_command: Option<Vec<String>>,
+    /// Address family this domain accepts.
+    #[serde(default)]
+    ip_version: IpVersion,
+    /// Name of the `view:` clause holding this domain's entries. Updates only touch
+    /// entries inside that view; `None` uses the whole file.
+    #[serde(default)]
+    section: Option<String>,
+    /// Whether writing one record family removes the other, so the domain holds only
+    /// A or only AAAA entries.
+    #[serde(default)]
+    exclusive_family: bool,
+    /// Added from `domain_discovery_txt` rather than configured.
+    #[serde(skip)]
+    discovered: bool,
+}
+
+/// Domains that always share one IP. An update for the group's `name`, authorized with
+/// the group's `key`, writes the IP for every member and reloads Unbound once.
+#[derive(Debug, Deserialize, Clone)]
+struct DomainGroup {
     name: String,
     key: String,
+    domains: Vec<String>,
+}
+
+/// Address family a domain accepts, regardless of how the address was obtained.
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum IpVersion {
+    V4,
+    V6,
+    #[default]
+    Any,
+}
+
+impl IpVersion {
+    /// Whether `ip` may be written. Strings that aren't IPs are left to the IP validation.
+    fn allows(self, ip: &str) -> bool {
+        !matches!(
+            (self, ip.parse::<IpAddr>()),
+            (IpVersion::V4, Ok(IpAddr::V6(_))) | (IpVersion::V6, Ok(IpAddr::V4(_)))
+        )
+    }
+}
+
+impl DomainConfig {
+    /// Returns the TTL to write for a record holding `ip`, preferring the
+    /// family-specific TTL and falling back to the shared `ttl`.
+    fn ttl_for(&self, ip: &IpAddr) -> Option<u32> {
+        let family_ttl = match ip {
+            IpAddr::V4(_) => self.ttl_v4,
+            IpAddr::V6(_) => self.ttl_v6,
+        };
+        family_ttl.or(self.ttl)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// A weekly window, in UTC, during which updates are frozen.
+#[derive(Debug, Deserialize, Clone)]
+struct FreezeWindow {
+    days: Vec<Weekday>,
+    /// First frozen hour of the day (0-23).
+    #[serde(default)]
+    start_hour: u8,
+    /// Hour at which the freeze ends (1-24, exclusive).
+    #[serde(default = "default_freeze_end_hour")]
+    end_hour: u8,
+}
+
+fn default_freeze_end_hour() -> u8 {
+    24
+}
+
+impl FreezeWindow {
+    fn contains(&self, weekday: Weekday, hour: u8) -> bool {
+        self.days.contains(&weekday) && (self.start_hour..self.end_hour).contains(&hour)
+    }
+}
+
+/// Returns the UTC weekday and hour of `time`.
+fn utc_weekday_and_hour(time: SystemTime) -> (Weekday, u8) {
+    const WEEKDAYS: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    // 1970-01-01 was a Thursday
+    let weekday = WEEKDAYS[((days + 3) % 7) as usize];
+    (weekday, ((secs % 86_400) / 3600) as u8)
+}
+
+/// Returns true if `now` falls inside any window of the freeze schedule.
+fn is_frozen(schedule: &[FreezeWindow], now: SystemTime) -> bool {
+    let (weekday, hour) = utc_weekday_and_hour(now);
+    schedule.iter().any(|w| w.contains(weekday, hour))
+}
+
+/// Returns `ip` in canonical form (no leading zeros, compressed IPv6, no port), or
+/// unchanged if it isn't an IP address. Zero-padded IPv4 octets such as `042` are read as
+/// decimal, and a port (`203.0.113.5:8080`, `[2001:db8::1]:443`) is dropped.
+fn canonical_ip(ip: &str) -> String {
+    if let Ok(addr) = ip.parse::<IpAddr>() {
+        return addr.to_string();
+    }
+    if let Ok(addr) = ip.parse::<SocketAddr>() {
+        return addr.ip().to_string();
+    }
+    let octets: Option<Vec<u8>> = ip
+        .split('.')
+        .map(|octet| match octet.len() {
+            1..=3 if octet.bytes().all(|b| b.is_ascii_digit()) => octet.parse().ok(),
+            _ => None,
+        })
+        .collect();
+    match octets.as_deref() {
+        Some(&[a, b, c, d]) => Ipv4Addr::new(a, b, c, d).to_string(),
+        _ => ip.to_string(),
+    }
 }
 
 impl Config {
     fn load(path: &str) -> Result<Self, String> {
+        let config = Self::read(path)?;
+        if config.create_unbound_file_if_missing {
+            for unbound_path in config.unbound_paths() {
+                if unbound_path.exists() {
+                    continue;
+                }
+                let domains: Vec<DomainConfig> = config
+                    .domains
+                    .iter()
+                    .filter(|d| config.unbound_path_for(d) == unbound_path)
+                    .cloned()
+                    .collect();
+                let added = init_unbound_config(unbound_path, &domains, config.entry_format())?;
+                info!(path = ?unbound_path, domains = ?added, "Created missing Unbound config");
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reads and parses the config file without validating it against the Unbound config.
+    fn read(path: &str) -> Result<Self, String> {
         let content =
             fs::read_to_string(path).map_err(|e| format!("Failed to read config file: {}", e))?;
+        Self::parse(&content)
+    }
+
+    /// Parses a config from TOML and normalizes it, merging in `keys_file` if set.
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut config = Self::parse_inline(content)?;
+        if let Some(keys_file) = config.keys_file.clone() {
+            config.merge_keys_file(&keys_file)?;
+        }
+        Ok(config)
+    }
 
+    /// Parses a config from TOML and normalizes it without reading any other file.
+    fn parse_inline(content: &str) -> Result<Self, String> {
         let mut config: Config =
-            toml::from_str(&content).map_err(|e| format!("Failed to parse config file: {}", e))?;
+            toml::from_str(content).map_err(|e| format!("Failed to parse config file: {}", e))?;
 
         // Normalize all domain names by removing trailing dots
         for domain in &mut config.domains {
             domain.name = normalize_domain(&domain.name);
+            if config.normalize_idn {
+                domain.name = idna::domain_to_ascii(&domain.name).map_err(|e| {
+                    format!(
+                        "Domain '{}' is not a valid internationalized domain name: {}",
+                        domain.name, e
+                    )
+                })?;
+            }
         }
 
-        config.validate()?;
+        let mut groups = std::mem::take(&mut config.domain_groups);
+        for group in &mut groups {
+            group.name = normalize_domain(&group.name);
+            group.domains = group
+                .domains
+                .iter()
+                .map(|d| config.normalize_request_domain(d))
+                .collect();
+        }
+        config.domain_groups = groups;
+
+        // Normalize the access lists the same way as request domains
+        config.denied_domains = config
+            .denied_domains
+            .iter()
+            .map(|d| config.normalize_request_domain(d))
+            .collect();
+        config.allowed_domains = config.allowed_domains.as_ref().map(|allowed| {
+            allowed
+                .iter()
+                .map(|d| config.normalize_request_domain(d))
+                .collect()
+        });
+
         Ok(config)
     }
 
+    /// Fills in domain keys from a TOML file of `"domain" = "key"` pairs.
+    ///
+    /// A domain may get its key from either file but not both, and every entry in the
+    /// keys file must belong to a configured domain, unless `domain_discovery_txt` is set,
+    /// in which case the keys of other domains are kept for the discovered ones.
+    fn merge_keys_file(&mut self, keys_file: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(keys_file)
+            .map_err(|e| format!("Failed to read keys file {:?}: {}", keys_file, e))?;
+        let keys: HashMap<String, String> = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse keys file {:?}: {}", keys_file, e))?;
+
+        for (name, key) in keys {
+            let name = self.normalize_request_domain(&name);
+            let Some(domain) = self.domains.iter_mut().find(|d| d.name == name) else {
+                // The domain may still be discovered later
+                if self.domain_discovery_txt.is_some() {
+                    self.discovery_keys.insert(name, key);
+                    continue;
+                }
+                return Err(format!("keys_file contains unknown domain '{}'", name));
+            };
+            if !domain.key.is_empty() {
+                return Err(format!(
+                    "Domain '{}' has a key in both the config and keys_file",
+                    name
+                ));
+            }
+            domain.key = key;
+        }
+        Ok(())
+    }
+
+    /// Normalizes a domain name received from a client so it can be compared with
+    /// the configured domains: removes the trailing dot and, if IDN normalization is
+    /// enabled, converts Unicode labels to punycode.
+    ///
+    /// Names that can't be converted are returned as-is and simply won't match any
+    /// configured domain.
+    fn normalize_request_domain(&self, domain: &str) -> String {
+        let domain = normalize_domain(domain);
+        if self.normalize_idn {
+            idna::domain_to_ascii(&domain).unwrap_or(domain)
+        } else {
+            domain
+        }
+    }
+
+    /// Returns true if updates for `domain` are refused by `denied_domains` or
+    /// `allowed_domains`.
+    fn domain_blocked(&self, domain: &str) -> bool {
+        self.denied_domains.iter().any(|d| d == domain)
+            || self
+                .allowed_domains
+                .as_ref()
+                .is_some_and(|allowed| !allowed.iter().any(|d| d == domain))
+    }
+
+    /// Returns the IP to write for a client-supplied or auto-detected address, in canonical
+    /// form and with IPv4-mapped IPv6 addresses unwrapped when `unwrap_mapped_v4` is set.
+    fn record_ip(&self, ip: String) -> String {
+        let ip = canonical_ip(&ip);
+        if !self.unwrap_mapped_v4 {
+            return ip;
+        }
+        match ip
+            .parse::<std::net::Ipv6Addr>()
+            .map(|v6| v6.to_ipv4_mapped())
+        {
+            Ok(Some(v4)) => v4.to_string(),
+            _ => ip,
+        }
+    }
+
+    /// Checks the config, failing on the first problem found.
     fn validate(&self) -> Result<(), String> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem.message),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the config and returns every problem found, in the order they are checked.
+    fn problems(&self) -> Vec<ValidationProblem> {
+        let mut problems = Vec::new();
+        let mut problem = |field: &str, message: String| {
+            problems.push(ValidationProblem {
+                field: Some(field.to_string()),
+                message,
+            })
+        };
+
         // Check if there are any domains configured
         if self.domains.is_empty() {
-            return Err("Configuration must contain at least one domain".to_string());
+            problem(
+                "domains",
+                "Configuration must contain at least one domain".to_string(),
+            );
         }
 
         // Check each domain for validity
         for (idx, domain) in self.domains.iter().enumerate() {
             if domain.name.trim().is_empty() {
-                return Err(format!("Domain at index {} has an empty name", idx));
+                problem(
+                    &format!("domains[{}].name", idx),
+                    format!("Domain at index {} has an empty name", idx),
+                );
+            }
+            if domain.key.trim().is_empty() && self.key_lookup_command.is_none() {
+                problem(
+                    &format!("domains[{}].key", idx),
+                    format!(
+                        "Domain '{}' has an empty key (set `key` or add it to keys_file)",
+                        domain.name
+                    ),
+                );
+            }
+            if domain
+                .reload_command
+                .as_ref()
+                .is_some_and(|command| command.is_empty())
+            {
+                problem(
+                    &format!("domains[{}].reload_command", idx),
+                    format!(
+                        "Domain '{}' has a reload_command that names no program",
+                        domain.name
+                    ),
+                );
+            }
+            if domain
+                .section
+                .as_ref()
+                .is_some_and(|section| section.trim().is_empty())
+            {
+                problem(
+                    &format!("domains[{}].section", idx),
+                    format!("Domain '{}' has an empty section", domain.name),
+                );
+            }
+        }
+
+        if self
+            .reload_command
+            .as_ref()
+            .is_some_and(|command| command.is_empty())
+        {
+            problem(
+                "reload_command",
+                "reload_command must name a program".to_string(),
+            );
+        }
+
+        if self.domain_discovery_txt.is_some() && self.domain_discovery_interval_secs == 0 {
+            problem(
+                "domain_discovery_interval_secs",
+                "domain_discovery_interval_secs must be greater than 0".to_string(),
+            );
+        }
+
+        if let Some(token) = &self.proxy_token
+            && token.trim().is_empty()
+        {
+            problem(
+                "proxy_token",
+                "proxy_token cannot be empty when set".to_string(),
+            );
+        }
+
+        if let Some(token) = &self.diagnostics_token
+            && token.trim().is_empty()
+        {
+            problem(
+                "diagnostics_token",
+                "diagnostics_token cannot be empty when set".to_string(),
+            );
+        }
+
+        let listen_addrs = self.listen_addr.addrs();
+        if listen_addrs.is_empty() {
+            problem(
+                "listen_addr",
+                "listen_addr must contain at least one address".to_string(),
+            );
+        }
+        for addr in listen_addrs {
+            if let Err(e) = addr.parse::<SocketAddr>() {
+                problem(
+                    "listen_addr",
+                    format!("Invalid listen_addr '{}': {}", addr, e),
+                );
+            }
+        }
+
+        if let Some(addr) = &self.admin_listen_addr
+            && let Err(e) = addr.parse::<SocketAddr>()
+        {
+            problem(
+                "admin_listen_addr",
+                format!("Invalid admin_listen_addr '{}': {}", addr, e),
+            );
+        }
+
+        // Without a usable entry format every domain would look missing from the file
+        let mut entry_format_valid = true;
+        if self.entry_template.is_some() && self.entry_match_regex.is_none() {
+            problem(
+                "entry_match_regex",
+                "entry_template requires entry_match_regex so written entries can be found again"
+                    .to_string(),
+            );
+            entry_format_valid = false;
+        }
+        // Without `{type}`, writing an AAAA record would replace the A records and vice versa
+        if self
+            .entry_match_regex
+            .as_ref()
+            .is_some_and(|pattern| !pattern.contains("{type}"))
+        {
+            problem(
+                "entry_match_regex",
+                "entry_match_regex must use `{type}` so A and AAAA entries are told apart"
+                    .to_string(),
+            );
+            entry_format_valid = false;
+        }
+        if self.entry_match_regex.is_some()
+            && let Err(e) = self.entry_format().entry_regex("example.com", None)
+        {
+            problem(
+                "entry_match_regex",
+                format!("Invalid entry_match_regex: {}", e),
+            );
+            entry_format_valid = false;
+        }
+
+        if self
+            .key_lookup_command
+            .as_ref()
+            .is_some_and(|command| command.is_empty())
+        {
+            problem(
+                "key_lookup_command",
+                "key_lookup_command must name a program".to_string(),
+            );
+        }
+
+        const RESERVED_PATHS: [&str; 11] = [
+            "/",
+            "/update",
+            "/update/stream",
+            "/current",
+            "/export",
+            "/server-ip",
+            "/metrics",
+            "/diagnostics",
+            "/validate",
+            "/config-info",
+            "/events",
+        ];
+        if !self.health_path.starts_with('/') || RESERVED_PATHS.contains(&self.health_path.as_str())
+        {
+            problem(
+                "health_path",
+                format!(
+                    "health_path '{}' must start with '/' and not clash with another endpoint",
+                    self.health_path
+                ),
+            );
+        }
+
+        if let Err(e) = axum::http::HeaderValue::from_str(&self.cache_control) {
+            problem(
+                "cache_control",
+                format!("Invalid cache_control '{}': {}", self.cache_control, e),
+            );
+        }
+
+        if !matches!(self.success_status, 200 | 204) {
+            problem(
+                "success_status",
+                format!(
+                    "success_status must be 200 or 204, got {}",
+                    self.success_status
+                ),
+            );
+        }
+
+        for (idx, window) in self.freeze_schedule.iter().enumerate() {
+            if window.start_hour >= window.end_hour || window.end_hour > 24 {
+                problem(
+                    &format!("freeze_schedule[{}]", idx),
+                    format!(
+                        "freeze_schedule entry {} must have start_hour < end_hour <= 24",
+                        idx
+                    ),
+                );
+            }
+        }
+
+        if self.max_records_per_domain == 0 {
+            problem(
+                "max_records_per_domain",
+                "max_records_per_domain must be at least 1".to_string(),
+            );
+        }
+
+        if self.max_connections == Some(0) {
+            problem(
+                "max_connections",
+                "max_connections must be at least 1".to_string(),
+            );
+        }
+        if self.header_read_timeout_secs == 0 {
+            problem(
+                "header_read_timeout_secs",
+                "header_read_timeout_secs must be at least 1".to_string(),
+            );
+        }
+        if self.max_header_bytes == Some(0) {
+            problem(
+                "max_header_bytes",
+                "max_header_bytes must be at least 1".to_string(),
+            );
+        }
+        if self.events_enabled && self.diagnostics_token.is_none() {
+            problem(
+                "events_enabled",
+                "events_enabled requires diagnostics_token to authenticate subscribers".to_string(),
+            );
+        }
+
+        for (idx, group) in self.domain_groups.iter().enumerate() {
+            let field = format!("domain_groups[{}]", idx);
+            if group.key.trim().is_empty() {
+                problem(
+                    &format!("{}.key", field),
+                    format!("Domain group '{}' has an empty key", group.name),
+                );
+            }
+            if self.find_domain(&group.name).is_some()
+                || self.domain_groups[..idx]
+                    .iter()
+                    .any(|other| other.name == group.name)
+            {
+                problem(
+                    &format!("{}.name", field),
+                    format!(
+                        "Domain group '{}' clashes with another domain or group",
+                        group.name
+                    ),
+                );
             }
-            if domain.key.trim().is_empty() {
-                return Err(format!("Domain '{}' has an empty key", domain.name));
+            if group.domains.is_empty() {
+                problem(
+                    &format!("{}.domains", field),
+                    format!("Domain group '{}' has no domains", group.name),
+                );
+            }
+            for member in &group.domains {
+                if self.find_domain(member).is_none() {
+                    problem(
+                        &format!("{}.domains", field),
+                        format!(
+                            "Domain group '{}' contains unknown domain '{}'",
+                            group.name, member
+                        ),
+                    );
+                }
             }
         }
 
         // Check for duplicate domain names
         for i in 0..self.domains.len() {
-            for j in (i + 1)..self.domains.len() {
-                if self.domains[i].name == self.domains[j].name {
-                    return Err(format!(
+            if self.domains[..i]
+                .iter()
+                .any(|other| other.name == self.domains[i].name)
+            {
+                problem(
+                    &format!("domains[{}].name", i),
+                    format!(
                         "Duplicate domain '{}' found in configuration",
                         self.domains[i].name
-                    ));
-                }
+                    ),
+                );
             }
         }
 
-        // Check if Unbound config file exists and contains all configured domains
-        let unbound_content = fs::read_to_string(&self.unbound_config_path).map_err(|e| {
-            format!(
-                "Failed to read Unbound config file at {:?}: {}",
-                self.unbound_config_path, e
-            )
-        })?;
-
-        for domain in &self.domains {
-            if !domain_exists_in_config(&unbound_content, &domain.name) {
-                return Err(format!(
-                    "Domain '{}' not found in Unbound config file. Please add 'local-data: \"{} IN A <ip>\"' to {:?} first.",
-                    domain.name, domain.name, self.unbound_config_path
-                ));
+        // Check that each domain's Unbound config file exists and contains the domain
+        let mut contents: HashMap<&PathBuf, Option<String>> = HashMap::new();
+        for (idx, domain) in self.domains.iter().enumerate() {
+            if !entry_format_valid {
+                break;
+            }
+            let unbound_path = self.unbound_path_for(domain);
+            let unbound_content = contents.entry(unbound_path).or_insert_with(|| {
+                fs::read_to_string(unbound_path)
+                    .map_err(|e| {
+                        problem(
+                            if domain.config_path.is_some() {
+                                "config_path"
+                            } else {
+                                "unbound_config_path"
+                            },
+                            format!(
+                                "Failed to read Unbound config file at {:?}: {}",
+                                unbound_path, e
+                            ),
+                        )
+                    })
+                    .ok()
+            });
+            let Some(unbound_content) = unbound_content else {
+                continue;
+            };
+            if !self.entry_format().exists_in(unbound_content, &domain.name) {
+                problem(
+                    &format!("domains[{}].name", idx),
+                    format!(
+                        "Domain '{}' not found in Unbound config file. Please add 'local-data: \"{} IN A <ip>\"' to {:?} first.",
+                        domain.name, domain.name, unbound_path
+                    ),
+                );
+            }
+            if self.require_local_zone && !has_covering_local_zone(unbound_content, &domain.name) {
+                problem(
+                    &format!("domains[{}].name", idx),
+                    format!(
+                        "Domain '{}' is not covered by any local-zone in {:?}",
+                        domain.name, unbound_path
+                    ),
+                );
             }
         }
 
-        Ok(())
+        problems
+    }
+
+    fn entry_format(&self) -> EntryFormat<'_> {
+        EntryFormat {
+            template: self.entry_template.as_deref(),
+            match_regex: self.entry_match_regex.as_deref(),
+            trailing_dot: self.trailing_dot,
+            group_by_type: self.group_by_type,
+        }
     }
 
     fn find_domain(&self, name: &str) -> Option<&DomainConfig> {
         self.domains.iter().find(|d| d.name == name)
     }
+
+    /// Names of the domains added from `domain_discovery_txt`.
+    fn discovered_domains(&self) -> Vec<String> {
+        self.domains
+            .iter()
+            .filter(|d| d.discovered)
+            .map(|d| d.name.clone())
+            .collect()
+    }
+
+    /// Returns this config with `names` added as managed domains in place of the ones
+    /// discovered before. Configured domains keep their own settings. A discovered domain
+    /// takes its key from `keys_file`, or from `key_lookup_command`; one with neither is
+    /// skipped.
+    fn with_discovered(&self, names: &[String]) -> Config {
+        let mut config = self.clone();
+        config.domains.retain(|d| !d.discovered);
+        for name in names {
+            let name = config.normalize_request_domain(name);
+            if name.is_empty() || config.find_domain(&name).is_some() {
+                continue;
+            }
+            let key = match config.discovery_keys.get(&name) {
+                Some(key) => key.clone(),
+                None if config.key_lookup_command.is_some() => String::new(),
+                None => {
+                    warn!(domain = %name, "Skipping discovered domain without a key");
+                    continue;
+                }
+            };
+            config.domains.push(DomainConfig {
+                name,
+                key,
+                discovered: true,
+                ..DomainConfig::default()
+            });
+        }
+        config
+    }
+
+    fn find_group(&self, name: &str) -> Option<&DomainGroup> {
+        self.domain_groups.iter().find(|g| g.name == name)
+    }
+
+    /// The update rate limit for `domain`: its own `rate_limit`, else the global one.
+    fn rate_limit_for(&self, domain: &DomainConfig) -> Option<u32> {
+        domain.rate_limit.or(self.max_updates_per_minute)
+    }
+
+    /// The Unbound file holding `domain`'s entries: its own `config_path`, else the global one.
+    fn unbound_path_for<'a>(&'a self, domain: &'a DomainConfig) -> &'a PathBuf {
+        domain
+            .config_path
+            .as_ref()
+            .unwrap_or(&self.unbound_config_path)
+    }
+
+    /// The command that reloads Unbound after `domain` is written: its own
+    /// `reload_command`, else the global one. `None` runs `unbound-control reload`.
+    fn reload_command_for<'a>(&'a self, domain: &'a DomainConfig) -> Option<&'a [String]> {
+        domain
+            .reload_command
+            .as_deref()
+            .or(self.reload_command.as_deref())
+    }
+
+    /// The settings only read at startup that differ in `new`. A SIGHUP reload can't
+    /// apply these, so it keeps the running config when any of them changed.
+    ///
+    /// For `diagnostics_token` only setting or unsetting it counts, since the token itself
+    /// is checked on every request.
+    fn restart_only_changes(&self, new: &Config) -> Vec<&'static str> {
+        [
+            ("listen_addr", self.listen_addr != new.listen_addr),
+            (
+                "admin_listen_addr",
+                self.admin_listen_addr != new.admin_listen_addr,
+            ),
+            (
+                "max_connections",
+                self.max_connections != new.max_connections,
+            ),
+            ("proxy_protocol", self.proxy_protocol != new.proxy_protocol),
+            (
+                "header_read_timeout_secs",
+                self.header_read_timeout_secs != new.header_read_timeout_secs,
+            ),
+            (
+                "max_header_bytes",
+                self.max_header_bytes != new.max_header_bytes,
+            ),
+            (
+                "startup_delay_secs",
+                self.startup_delay_secs != new.startup_delay_secs,
+            ),
+            ("cache_control", self.cache_control != new.cache_control),
+            ("health_path", self.health_path != new.health_path),
+            (
+                "metrics_enabled",
+                self.metrics_enabled != new.metrics_enabled,
+            ),
+            (
+                "diagnostics_token",
+                self.diagnostics_token.is_some() != new.diagnostics_token.is_some(),
+            ),
+            ("events_enabled", self.events_enabled != new.events_enabled),
+            (
+                "status_page_enabled",
+                self.status_page_enabled != new.status_page_enabled,
+            ),
+            (
+                "server_ip_enabled",
+                self.server_ip_enabled != new.server_ip_enabled,
+            ),
+            (
+                "server_ip_echo_url",
+                self.server_ip_echo_url != new.server_ip_echo_url,
+            ),
+            (
+                "server_ip_cache_secs",
+                self.server_ip_cache_secs != new.server_ip_cache_secs,
+            ),
+            (
+                "verify_resolution_server",
+                self.verify_resolution_server != new.verify_resolution_server,
+            ),
+            ("state_path", self.state_path != new.state_path),
+            (
+                "key_lookup_command",
+                self.key_lookup_command != new.key_lookup_command,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    /// Every distinct Unbound file in use, the global one first.
+    fn unbound_paths(&self) -> Vec<&PathBuf> {
+        let mut paths = vec![&self.unbound_config_path];
+        for domain in &self.domains {
+            let path = self.unbound_path_for(domain);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct UpdateRequest {
-    domain: String,
-    ip: Option<String>,
+/// The records last written for a domain. Addresses are kept parsed, so `::1` and
+/// `0:0:0:0:0:0:0:1` are recognized as the same record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct KnownRecord {
+    ips: Vec<IpAddr>,
+    ttl: Option<u32>,
+    /// Where the records were written; `None` for records saved by older versions.
+    #[serde(default)]
+    written_to: Option<WrittenTo>,
 }
 
-#[derive(Debug, Serialize)]
-struct UpdateResponse {
-    success: bool,
-    message: String,
+/// The file, section and entry format a [`KnownRecord`] was written with, and the
+/// file's size and modification time right after the write. A saved record is only
+/// trusted while all of these still match, so hand edits and config changes made
+/// through a reload fall back to reading the Unbound config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct WrittenTo {
+    path: PathBuf,
+    section: Option<String>,
+    template: Option<String>,
+    match_regex: Option<String>,
+    trailing_dot: bool,
+    len: u64,
+    modified: SystemTime,
 }
 
-impl IntoResponse for UpdateResponse {
-    fn into_response(self) -> axum::response::Response {
-        let status = if self.success {
-            StatusCode::OK
-        } else {
-            StatusCode::BAD_REQUEST
+impl WrittenTo {
+    /// Describes the Unbound config `domain` is written to as it is now, or `None` if
+    /// the file can't be inspected.
+    fn current(config: &Config, domain: &DomainConfig) -> Option<Self> {
+        let path = config.unbound_path_for(domain);
+        let metadata = fs::metadata(path).ok()?;
+        let format = config.entry_format();
+        Some(WrittenTo {
+            path: path.clone(),
+            section: domain.section.clone(),
+            template: format.template.map(str::to_string),
+            match_regex: format.match_regex.map(str::to_string),
+            trailing_dot: format.trailing_dot,
+            len: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// Last-known records per domain, persisted as JSON to `state_path` so that no-op
+/// updates are recognized across restarts without re-reading the Unbound config.
+///
+/// Without a `state_path` nothing is tracked.
+#[derive(Debug, Default)]
+struct LastKnownStore {
+    path: Option<PathBuf>,
+    records: Mutex<HashMap<String, KnownRecord>>,
+}
+
+impl LastKnownStore {
+    /// Loads the store from `path`. A missing file starts out empty.
+    fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let records = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(content) => serde_json::from_str(&content)
+                    .map_err(|e| format!("Failed to parse state file {:?}: {}", path, e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(format!("Failed to read state file {:?}: {}", path, e)),
+            },
+            None => HashMap::new(),
         };
+        Ok(LastKnownStore {
+            path,
+            records: Mutex::new(records),
+        })
+    }
 
-        (status, axum::Json(self)).into_response()
+    fn get(&self, domain: &str) -> Option<KnownRecord> {
+        self.records.lock().unwrap().get(domain).cloned()
+    }
+
+    /// Remembers `record` for `domain` and saves the store if it changed.
+    fn record(&self, domain: &str, record: KnownRecord) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut records = self.records.lock().unwrap();
+        if records.get(domain) == Some(&record) {
+            return Ok(());
+        }
+        records.insert(domain.to_string(), record);
+        let json = serde_json::to_string_pretty(&*records)
+            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        fs::write(path, json).map_err(|e| format!("Failed to write state file {:?}: {}", path, e))
     }
 }
 
-fn extract_auth_key(headers: &HeaderMap) -> Result<String, String> {
-    let auth_header = headers
-        .get("authorization")
-        .ok_or_else(|| "Missing Authorization header".to_string())?;
+/// Shared state for request handlers.
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    update_rates: Arc<UpdateRateTracker>,
+    server_ip: Arc<ServerIpCache>,
+    last_known: Arc<LastKnownStore>,
+    reloader: Reloader,
+    clock: Clock,
+    /// When the `startup_delay_secs` warmup ends, measured against `clock`.
+    ready_at: SystemTime,
+    sleep: Sleep,
+    /// Resolves domain keys when `key_lookup_command` is set.
+    key_lookup: Option<KeyLookup>,
+    key_cache: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+    domain_locks: Arc<DomainLocks>,
+    reload_throttle: Arc<Mutex<ReloadThrottle>>,
+    idempotent_responses: Arc<IdempotencyCache>,
+    in_flight_updates: Arc<InFlightUpdates>,
+    dns_resolver: DnsResolver,
+    version_probe: VersionProbe,
+    /// Update outcomes for `/events` subscribers.
+    events: tokio::sync::broadcast::Sender<UpdateEvent>,
+}
 
-    let auth_str = auth_header
-        .to_str()
-        .map_err(|_| "Invalid Authorization header encoding".to_string())?;
+/// Source of the current wall-clock time, replaceable in tests.
+type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// Waits for the given duration, replaceable in tests.
+type Sleep = Arc<dyn Fn(Duration) -> futures_util::future::BoxFuture<'static, ()> + Send + Sync>;
+
+/// Tells Unbound to pick up the rewritten config, using the configured `reload_command`
+/// when given one.
+type Reloader = Arc<dyn Fn(Option<&[String]>) -> Result<(), String> + Send + Sync>;
+
+/// Looks up the A or AAAA records (by DNS query type) of a domain, for `verify_resolution`.
+type DnsResolver = Arc<
+    dyn Fn(&str, u16) -> futures_util::future::BoxFuture<'static, Result<Vec<IpAddr>, String>>
+        + Send
+        + Sync,
+>;
+
+/// Looks up the TXT strings of a name, for `domain_discovery_txt`.
+type TxtResolver = Arc<
+    dyn Fn(&str) -> futures_util::future::BoxFuture<'static, Result<Vec<String>, String>>
+        + Send
+        + Sync,
+>;
+
+/// Reports the installed Unbound version.
+type VersionProbe = Arc<dyn Fn() -> Result<String, String> + Send + Sync>;
+
+/// Returns the valid key for a domain from an external source.
+type KeyLookup = Arc<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
+
+impl AppState {
+    fn new(config: Arc<Config>) -> Self {
+        let echo_url = config.server_ip_echo_url.clone();
+        let resolver: ServerIpResolver = Arc::new(move || {
+            let echo_url = echo_url.clone();
+            Box::pin(async move { fetch_public_ip(&echo_url).await })
+        });
+        let server_ip =
+            ServerIpCache::new(resolver, Duration::from_secs(config.server_ip_cache_secs));
+        let dns_server = config.verify_resolution_server.clone();
+        let dns_resolver: DnsResolver = Arc::new(move |domain: &str, qtype| {
+            let dns_server = dns_server.clone();
+            let domain = domain.to_string();
+            Box::pin(async move { query_records(&dns_server, &domain, qtype).await })
+        });
+        let last_known = LastKnownStore::load(config.state_path.clone()).unwrap_or_else(|e| {
+            warn!(error = %e, "Ignoring saved state");
+            LastKnownStore {
+                path: config.state_path.clone(),
+                ..Default::default()
+            }
+        });
+
+        AppState {
+            update_rates: Arc::new(UpdateRateTracker::default()),
+            server_ip: Arc::new(server_ip),
+            last_known: Arc::new(last_known),
+            reloader: Arc::new(reload_unbound),
+            clock: Arc::new(SystemTime::now),
+            ready_at: SystemTime::now() + Duration::from_secs(config.startup_delay_secs),
+            sleep: Arc::new(|duration| Box::pin(tokio::time::sleep(duration))),
+            key_lookup: config.key_lookup_command.clone().map(|command| {
+                Arc::new(move |domain: &str| run_key_lookup_command(&command, domain)) as KeyLookup
+            }),
+            key_cache: Arc::default(),
+            domain_locks: Arc::default(),
+            reload_throttle: Arc::default(),
+            idempotent_responses: Arc::default(),
+            in_flight_updates: Arc::default(),
+            events: tokio::sync::broadcast::channel(64).0,
+            dns_resolver,
+            version_probe: Arc::new(unbound_version),
+            config,
+        }
+    }
+}
 
-    // Support both "Bearer <key>" and just "<key>" formats
-    let key = if let Some(bearer_key) = auth_str.strip_prefix("Bearer ") {
-        bearer_key.to_string()
-    } else {
-        auth_str.to_string()
-    };
+/// Looks up the server's own public IP address.
+type ServerIpResolver =
+    Arc<dyn Fn() -> futures_util::future::BoxFuture<'static, Result<IpAddr, String>> + Send + Sync>;
 
-    if key.trim().is_empty() {
-        return Err("Authorization header cannot be empty".to_string());
+/// Caches the server's public IP so that `/server-ip` doesn't make an outbound
+/// request every time it is called.
+struct ServerIpCache {
+    resolver: ServerIpResolver,
+    ttl: Duration,
+    cached: tokio::sync::Mutex<Option<(IpAddr, Instant)>>,
+}
+
+impl ServerIpCache {
+    fn new(resolver: ServerIpResolver, ttl: Duration) -> Self {
+        ServerIpCache {
+            resolver,
+            ttl,
+            cached: tokio::sync::Mutex::new(None),
+        }
     }
 
-    Ok(key)
+    /// Returns the cached IP, resolving it again once the cached value is older than the TTL.
+    ///
+    /// The lock is held while resolving so that concurrent callers share one lookup.
+    async fn get(&self) -> Result<IpAddr, String> {
+        let mut cached = self.cached.lock().await;
+        if let Some((ip, resolved_at)) = *cached
+            && resolved_at.elapsed() < self.ttl
+        {
+            return Ok(ip);
+        }
+
+        let ip = (self.resolver)().await?;
+        *cached = Some((ip, Instant::now()));
+        Ok(ip)
+    }
 }
 
-/// Extracts the real client IP address from the request headers when running behind a proxy.
-///
-/// This function checks for common proxy headers in the following order:
-/// 1. X-Forwarded-For: Takes the leftmost (original client) IP from the comma-separated list
-/// 2. X-Real-IP: The direct client IP set by the proxy
-/// 3. Falls back to the direct connection IP if no proxy headers are present
-///
-/// # Arguments
-/// * `headers` - The HTTP request headers
-/// * `addr` - The socket address of the direct connection
+/// Window over which per-domain update rates are measured and limited.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// One lock per domain, so that concurrent updates of the same domain don't race.
+#[derive(Debug, Default)]
+struct DomainLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl DomainLocks {
+    fn get(&self, domain: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        Arc::clone(locks.entry(domain.to_string()).or_default())
+    }
+}
+
+/// Takes the lock of every domain in `domains` when `domain_lock_timeout_ms` is set.
+/// Locks are taken in name order, so overlapping groups can't wait on each other forever.
 ///
 /// # Returns
-/// The client IP address as a string
-fn extract_client_ip(headers: &HeaderMap, addr: &SocketAddr) -> String {
-    // Check X-Forwarded-For header first (most common)
-    // Format: "client, proxy1, proxy2" - we want the leftmost (client) IP
-    if let Some(forwarded_for) = headers.get("x-forwarded-for")
-        && let Ok(forwarded_str) = forwarded_for.to_str()
-    {
-        // Take the first IP in the comma-separated list
-        if let Some(client_ip) = forwarded_str.split(',').next() {
-            let ip = client_ip.trim();
-            if !ip.is_empty() {
-                return ip.to_string();
-            }
+/// The guards to hold through the write and reload, or `None` if the locks weren't all
+/// free within the timeout
+async fn lock_domains(
+    state: &AppState,
+    domains: &[&str],
+) -> Option<Vec<tokio::sync::OwnedMutexGuard<()>>> {
+    let Some(timeout_ms) = state.config.domain_lock_timeout_ms else {
+        return Some(Vec::new());
+    };
+    let mut names = domains.to_vec();
+    names.sort_unstable();
+    names.dedup();
+    let lock_all = async {
+        let mut guards = Vec::with_capacity(names.len());
+        for name in names {
+            guards.push(state.domain_locks.get(name).lock_owned().await);
         }
-    }
+        guards
+    };
+    tokio::time::timeout(Duration::from_millis(timeout_ms), lock_all)
+        .await
+        .ok()
+}
 
-    // Check X-Real-IP header (used by nginx and others)
-    if let Some(real_ip) = headers.get("x-real-ip")
-        && let Ok(ip_str) = real_ip.to_str()
-    {
-        let ip = ip_str.trim();
-        if !ip.is_empty() {
-            return ip.to_string();
-        }
-    }
+/// Successful `/update` responses by key fingerprint and `Idempotency-Key`, kept for
+/// `idempotency_ttl_secs` so retries are answered without being applied again.
+#[derive(Debug, Default)]
+struct IdempotencyCache {
+    responses: Mutex<HashMap<String, IdempotentResponse>>,
+}
 
-    // Fall back to the direct connection IP
-    addr.ip().to_string()
+/// Most responses the `IdempotencyCache` holds; the oldest is dropped to make room.
+const MAX_IDEMPOTENT_RESPONSES: usize = 10_000;
+
+#[derive(Debug, Clone)]
+struct IdempotentResponse {
+    /// `update_fingerprint` of the request that produced the response, so a key reused
+    /// for a different request is caught instead of answered with the wrong response
+    request: String,
+    response: CachedResponse,
 }
 
-async fn update_handler(
-    State(config): State<Arc<Config>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    stored_at: Instant,
+    status: StatusCode,
     headers: HeaderMap,
     body: Bytes,
-) -> UpdateResponse {
-    let client_ip = extract_client_ip(&headers, &addr);
+}
 
-    // Extract and validate Authorization header
-    let auth_key = match extract_auth_key(&headers) {
-        Ok(key) => key,
-        Err(e) => {
-            warn!(client_ip = %client_ip, reason = %e, "Request failed");
-            return UpdateResponse {
-                success: false,
-                message: e,
-            };
-        }
-    };
+impl CachedResponse {
+    /// Buffers the body of `response` so it can be sent again.
+    async fn buffer(response: Response) -> Result<Self, axum::Error> {
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await?;
+        Ok(CachedResponse {
+            stored_at: Instant::now(),
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    }
 
-    // Parse the request based on Content-Type
-    let mut payload = match parse_update_request(&headers, &body) {
-        Ok(p) => p,
-        Err(e) => {
-            warn!(client_ip = %client_ip, reason = "parse error", error = %e, "Request failed");
-            return UpdateResponse {
-                success: false,
-                message: format!("Failed to parse request: {}", e),
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.body).into_response();
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+impl IdempotencyCache {
+    fn get(&self, key: &str, ttl: Duration, now: Instant) -> Option<IdempotentResponse> {
+        let responses = self.responses.lock().unwrap();
+        responses
+            .get(key)
+            .filter(|cached| now.duration_since(cached.response.stored_at) < ttl)
+            .cloned()
+    }
+
+    /// Stores a response, dropping the ones that have expired and, past
+    /// `MAX_IDEMPOTENT_RESPONSES`, the oldest.
+    fn insert(&self, key: String, response: IdempotentResponse, ttl: Duration) {
+        let now = response.response.stored_at;
+        let mut responses = self.responses.lock().unwrap();
+        responses.retain(|_, cached| now.duration_since(cached.response.stored_at) < ttl);
+        while responses.len() >= MAX_IDEMPOTENT_RESPONSES && !responses.contains_key(&key) {
+            let Some(oldest) = responses
+                .iter()
+                .min_by_key(|(_, cached)| cached.response.stored_at)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
             };
+            responses.remove(&oldest);
         }
-    };
+        responses.insert(key, response);
+    }
+}
 
-    // Normalize the domain name by removing trailing dot
-    payload.domain = normalize_domain(&payload.domain);
+/// `/update` requests being applied, by request fingerprint, so identical requests
+/// arriving meanwhile share the result when `coalesce_updates` is set.
+#[derive(Default)]
+struct InFlightUpdates {
+    updates: Mutex<HashMap<String, SharedUpdate>>,
+}
 
-    info!(client_ip = %client_ip, domain = %payload.domain, "Received update request");
+type SharedUpdate =
+    futures_util::future::Shared<futures_util::future::BoxFuture<'static, CachedResponse>>;
+
+/// When Unbound was last reloaded, the reloads waiting for `min_reload_interval_secs`
+/// to pass, and the reloads whose last run failed. `None` stands for the default
+/// `unbound-control reload`.
+#[derive(Debug, Default)]
+struct ReloadThrottle {
+    last: Option<Instant>,
+    pending: Vec<Option<Vec<String>>>,
+    failed: Vec<Option<Vec<String>>>,
+}
 
-    // Authenticate the request - use same error message for both invalid domain and invalid key
-    // to prevent leaking information about which domains are valid
-    const UNAUTHORIZED_ERROR: &str = "Unauthorized";
+/// Tracks recent update attempts per domain over a sliding one-minute window.
+///
+/// The same counters drive both rate limit enforcement and the
+/// `ddns_domain_update_rate` metric, so the two can never disagree.
+#[derive(Debug, Default)]
+struct UpdateRateTracker {
+    updates: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
 
-    let domain_config = match config.find_domain(&payload.domain) {
-        Some(d) => d,
-        None => {
-            warn!(client_ip = %client_ip, domain = %payload.domain, reason = "unknown domain", "Request failed");
-            return UpdateResponse {
-                success: false,
-                message: UNAUTHORIZED_ERROR.to_string(),
-            };
+impl UpdateRateTracker {
+    /// Records an update for `domain` at `now`, unless it already reached `limit`
+    /// updates within the window.
+    ///
+    /// # Returns
+    /// An error message if the update is rate limited
+    fn check_and_record(
+        &self,
+        domain: &str,
+        limit: Option<u32>,
+        now: Instant,
+    ) -> Result<(), String> {
+        let mut updates = self.updates.lock().unwrap();
+        let recent = updates.entry(domain.to_string()).or_default();
+        prune_window(recent, now);
+
+        if let Some(limit) = limit
+            && recent.len() >= limit as usize
+        {
+            return Err(format!(
+                "Rate limit exceeded: at most {} updates per minute",
+                limit
+            ));
         }
-    };
 
-    // Use constant-time comparison to prevent timing attacks
-    // that could be used to guess the key byte-by-byte
-    if !bool::from(domain_config.key.as_bytes().ct_eq(auth_key.as_bytes())) {
-        warn!(client_ip = %client_ip, domain = %payload.domain, reason = "invalid key", "Request failed");
-        return UpdateResponse {
-            success: false,
-            message: UNAUTHORIZED_ERROR.to_string(),
-        };
+        recent.push_back(now);
+        Ok(())
     }
 
-    // Determine the IP address
-    let ip = match payload.ip {
-        Some(ip) => ip,
-        None => client_ip.clone(),
-    };
-
-    // Validate the IP address (IPv4 only, as we only support A records, not AAAA)
-    if ip.parse::<std::net::Ipv4Addr>().is_err() {
-        warn!(client_ip = %client_ip, domain = %payload.domain, ip = %ip, reason = "invalid IPv4 address", "Request failed");
-        return UpdateResponse {
-            success: false,
-            message: format!("Invalid IPv4 address: {}", ip),
-        };
+    /// Returns how long until `domain` drops below `limit` updates again, i.e. until the
+    /// oldest update counting towards the limit leaves the window. `None` when not limited.
+    fn retry_after(&self, domain: &str, limit: Option<u32>, now: Instant) -> Option<Duration> {
+        let limit = limit? as usize;
+        let mut updates = self.updates.lock().unwrap();
+        let recent = updates.get_mut(domain)?;
+        prune_window(recent, now);
+        let blocking = *recent.get(recent.len().checked_sub(limit)?)?;
+        Some(RATE_WINDOW.saturating_sub(now.saturating_duration_since(blocking)))
     }
 
-    // Update the Unbound configuration
-    match update_unbound_config(&config.unbound_config_path, &payload.domain, &ip) {
-        Ok(_) => {
-            // Reload Unbound
-            match reload_unbound() {
-                Ok(_) => {
-                    info!(domain = %payload.domain, ip = %ip, "DNS record updated successfully");
-                    UpdateResponse {
-                        success: true,
-                        message: format!("Updated {} to {}", payload.domain, ip),
-                    }
-                }
-                Err(e) => {
-                    error!(domain = %payload.domain, ip = %ip, error = %e, "Failed to reload Unbound");
-                    UpdateResponse {
-                        success: false,
-                        message: format!("Failed to reload Unbound: {}", e),
-                    }
-                }
-            }
-        }
-        Err(e) => {
-            error!(domain = %payload.domain, ip = %ip, error = %e, "Failed to update configuration");
-            UpdateResponse {
-                success: false,
-                message: format!("Failed to update configuration: {}", e),
+    /// Returns the number of updates recorded for `domain` within the window ending at `now`.
+    fn rate(&self, domain: &str, now: Instant) -> usize {
+        let mut updates = self.updates.lock().unwrap();
+        match updates.get_mut(domain) {
+            Some(recent) => {
+                prune_window(recent, now);
+                recent.len()
             }
+            None => 0,
         }
     }
 }
 
-fn parse_update_request(headers: &HeaderMap, body: &Bytes) -> Result<UpdateRequest, String> {
-    let content_type = headers
-        .get("content-type")
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
+/// Drops timestamps that have fallen out of the rate window ending at `now`.
+fn prune_window(recent: &mut VecDeque<Instant>, now: Instant) {
+    while let Some(oldest) = recent.front() {
+        if now.saturating_duration_since(*oldest) < RATE_WINDOW {
+            break;
+        }
+        recent.pop_front();
+    }
+}
 
-    if content_type.contains("application/json") {
-        // Parse as JSON
-        serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))
-    } else {
-        // Parse as form data (default)
-        let body_str = std::str::from_utf8(body).map_err(|e| format!("Invalid UTF-8: {}", e))?;
+#[derive(Debug, Serialize, Deserialize)]
+struct UpdateRequest {
+    domain: String,
+    ip: Option<String>,
+    /// Second record written alongside `ip`, for clients to fail over to.
+    #[serde(default)]
+    backup_ip: Option<String>,
+    /// TTL for this write only, overriding the domain's configured TTL.
+    #[serde(default)]
+    ttl: Option<u32>,
+    /// Explicit IPv4 address. Takes precedence over `ip`.
+    #[serde(default)]
+    ipv4: Option<String>,
+    /// Explicit IPv6 address. Takes precedence over `ip`.
+    #[serde(default)]
+    ipv6: Option<String>,
+}
 
-        serde_urlencoded::from_str(body_str).map_err(|e| format!("Invalid form data: {}", e))
+impl UpdateRequest {
+    /// Folds the family-specific `ipv4`/`ipv6` fields into `ip`, so later steps only
+    /// need to look at `ip`.
+    ///
+    /// `ipv4` and `ipv6` override the legacy `ip`, but sending `ip` together with an
+    /// explicit address of a different value is rejected as ambiguous, as is sending both
+    /// `ipv4` and `ipv6`.
+    fn resolve_ip_fields(&mut self) -> Result<(), String> {
+        let (field, explicit) = match (self.ipv4.take(), self.ipv6.take()) {
+            (Some(_), Some(_)) => {
+                return Err("Send either ipv4 or ipv6, not both".to_string());
+            }
+            (Some(ipv4), None) => ("ipv4", ipv4),
+            (None, Some(ipv6)) => ("ipv6", ipv6),
+            (None, None) => return Ok(()),
+        };
+        if let Some(ip) = &self.ip
+            && ip != &explicit
+        {
+            return Err(format!(
+                "Conflicting ip '{}' and {} '{}'",
+                ip, field, explicit
+            ));
+        }
+        self.ip = Some(explicit);
+        Ok(())
     }
 }
 
-fn domain_exists_in_config(content: &str, domain: &str) -> bool {
-    // Match domain with or without trailing dot (\.? makes the dot optional)
-    let pattern = format!(r#"local-data:\s*"{}\.?\s+IN\s+A\s+"#, regex::escape(domain));
-    if let Ok(re) = Regex::new(&pattern) {
-        re.is_match(content)
-    } else {
-        false
-    }
+#[derive(Debug, Serialize)]
+struct UpdateResponse {
+    success: bool,
+    message: String,
+    /// How the request was interpreted, included when `verbose_response` is enabled.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    details: Option<ResponseDetails>,
+    /// The type of record written (`A` or `AAAA`), included on successful updates.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record_type: Option<&'static str>,
 }
 
-fn update_unbound_config(config_path: &PathBuf, domain: &str, ip: &str) -> Result<(), String> {
-    // Read the current configuration
-    let content = fs::read_to_string(config_path)
-        .map_err(|e| format!("Failed to read Unbound config: {}", e))?;
+/// Where the IP written for an update came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum IpSource {
+    /// Sent by the client in the request
+    Explicit,
+    /// The address of the connection
+    AutoDetected,
+    /// Taken from a trusted `X-Forwarded-For` or `X-Real-IP` header
+    Forwarded,
+    /// Kept from the current record by a `PATCH` that only changes the TTL
+    Kept,
+}
 
-    // Check if domain exists in the configuration (domain is already normalized without trailing dot)
-    if !domain_exists_in_config(&content, domain) {
-        return Err(format!(
-            "Domain '{}' not found in Unbound config. Cannot update non-existent domain.",
-            domain
-        ));
-    }
+#[derive(Debug, Serialize)]
+struct ResponseDetails {
+    domain: String,
+    ip_source: IpSource,
+}
 
-    // Create the new local-data entry with proper FQDN format (trailing dot)
-    let new_entry = format!("local-data: \"{}. IN A {}\"", domain, ip);
+/// Parses an address before it is written into the Unbound config, so a malformed value
+/// (or one carrying quotes) can never end up inside a `local-data` entry.
+fn parse_ip(ip: &str) -> Result<IpAddr, String> {
+    ip.parse()
+        .map_err(|_| format!("Invalid IP address: {}", ip))
+}
 
-    // Pattern to match existing local-data entry for this domain (with or without trailing dot)
-    let pattern = format!(
-        r#"local-data:\s*"{}\.?\s+IN\s+A\s+[^"]+""#,
-        regex::escape(domain)
-    );
-    let re = Regex::new(&pattern).map_err(|e| format!("Failed to compile regex: {}", e))?;
+/// The type of DNS record that holds `ip`.
+fn record_type_for(ip: &str) -> &'static str {
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => "AAAA",
+        _ => "A",
+    }
+}
 
-    // Replace existing entry (we already checked it exists)
-    let updated_content = re.replace(&content, new_entry.as_str()).to_string();
+impl IntoResponse for UpdateResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = if self.success {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_REQUEST
+        };
 
-    // Write the updated configuration
-    fs::write(config_path, updated_content)
-        .map_err(|e| format!("Failed to write Unbound config: {}", e))?;
+        (status, axum::Json(self)).into_response()
+    }
+}
 
-    Ok(())
+#[derive(Debug, Deserialize)]
+struct CurrentQuery {
+    domain: String,
 }
 
-fn reload_unbound() -> Result<(), String> {
-    let output = Command::new("unbound-control")
-        .arg("reload")
-        .output()
-        .map_err(|e| format!("Failed to execute unbound-control: {}", e))?;
+/// Body of `PATCH /domains/{name}`. Omitted fields keep their current value.
+#[derive(Debug, Deserialize)]
+struct PatchRequest {
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    ttl: Option<u32>,
+}
 
-    if output.status.success() {
-        Ok(())
-    } else {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        Err(format!("unbound-control failed: {}", stderr))
-    }
+/// A single `local-data` record parsed from the Unbound config.
+#[derive(Debug, Serialize, PartialEq)]
+struct Record {
+    record_type: String,
+    value: String,
+    ttl: Option<u32>,
 }
 
-fn create_app(config: Arc<Config>) -> Router {
-    Router::new()
-        .route("/update", post(update_handler))
-        .with_state(config)
+#[derive(Debug, Serialize)]
+struct CurrentResponse {
+    success: bool,
+    domain: String,
+    records: Vec<Record>,
 }
 
-fn print_config_info(config: &Config) {
-    let domain_names: Vec<&str> = config.domains.iter().map(|d| d.name.as_str()).collect();
-    info!(
-        unbound_config_path = ?config.unbound_config_path,
-        domain_count = config.domains.len(),
-        domains = ?domain_names,
-        "Loaded configuration"
-    );
+/// Identifies a key in logs without revealing it: the first 8 hex digits of its SHA-256.
+fn key_fingerprint(key: &str) -> String {
+    Sha256::digest(key.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
 
-#[tokio::main]
-async fn main() {
-    // Initialize tracing subscriber for logging
-    // Log level can be controlled via RUST_LOG env var (e.g., RUST_LOG=info)
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .init();
+/// Extracts the key from the `Authorization` header, stripping surrounding whitespace
+/// when `trim` is set.
+fn extract_auth_key(headers: &HeaderMap, trim: bool) -> Result<String, String> {
+    let auth_header = headers
+        .get("authorization")
+        .ok_or_else(|| "Missing Authorization header".to_string())?;
 
-    // Load configuration
-    let config = match Config::load("config.toml") {
-        Ok(config) => Arc::new(config),
-        Err(e) => {
-            error!(error = %e, "Failed to load configuration");
-            std::process::exit(1);
+    let auth_str = auth_header
+        .to_str()
+        .map_err(|_| "Invalid Authorization header encoding".to_string())?;
+
+    // Support both "Bearer <key>" and just "<key>" formats. The scheme is
+    // case-insensitive, so "bearer <key>" and "BEARER <key>" work too.
+    const BEARER_PREFIX: &str = "Bearer ";
+    let key = match auth_str.get(..BEARER_PREFIX.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(BEARER_PREFIX) => {
+            auth_str[BEARER_PREFIX.len()..].to_string()
         }
+        _ => auth_str.to_string(),
     };
 
-    print_config_info(&config);
-
-    // Build the router
-    let app = create_app(config);
-
-    // Start the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    if key.trim().is_empty() {
+        return Err("Authorization header cannot be empty".to_string());
+    }
 
-    info!(address = "0.0.0.0:3000", "Server started");
+    Ok(if trim { key.trim().to_string() } else { key })
+}
 
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+/// Returns true if the request carries an `X-Proxy-Token` header matching the configured token.
+fn proxy_token_matches(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get("x-proxy-token")
+        .map(|v| bool::from(v.as_bytes().ct_eq(expected.as_bytes())))
+        .unwrap_or(false)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+/// Extracts the real client IP address from the request headers when running behind a proxy.
+///
+/// This function checks for common proxy headers in the following order:
+/// 1. X-Forwarded-For: Takes the leftmost (original client) IP from the comma-separated list
+/// 2. X-Real-IP: The direct client IP set by the proxy
+/// 3. Falls back to the direct connection IP if no proxy headers are present
+///
+/// When a `proxy_token` is configured, the proxy headers are only trusted if the request
+/// also carries a matching `X-Proxy-Token` header; otherwise the connection IP is used.
+///
+/// # Arguments
+/// * `headers` - The HTTP request headers
+/// * `addr` - The socket address of the direct connection
+/// * `proxy_token` - The shared secret expected from the trusted proxy, if any
+///
+/// # Returns
+/// The client IP address as a string
+fn extract_client_ip(headers: &HeaderMap, addr: &SocketAddr, proxy_token: Option<&str>) -> String {
+    forwarded_client_ip(headers, proxy_token).unwrap_or_else(|| addr.ip().to_string())
+}
 
-    // ============================================================================
-    // TEST HELPERS
-    // ============================================================================
+/// Returns the client IP reported by trusted proxy headers, if any. See [`extract_client_ip`].
+fn forwarded_client_ip(headers: &HeaderMap, proxy_token: Option<&str>) -> Option<String> {
+    if let Some(expected) = proxy_token
+        && !proxy_token_matches(headers, expected)
+    {
+        return None;
+    }
 
-    /// Creates a temporary unbound config file with optional domain entries.
-    ///
-    /// # Arguments
-    /// * `domains` - Optional slice of tuples (domain_name, ip_address) to add as local-data entries
-    ///
-    /// # Returns
-    /// A NamedTempFile that will be automatically deleted when dropped
-    fn create_unbound_config(domains: Option<&[(&str, &str)]>) -> NamedTempFile {
-        let mut file = NamedTempFile::new().unwrap();
-        writeln!(file, "server:").unwrap();
-        writeln!(file, "  verbosity: 1").unwrap();
+    // Check X-Forwarded-For header first (most common)
+    // Format: "client, proxy1, proxy2" - we want the leftmost (client) IP
+    if let Some(forwarded_for) = headers.get("x-forwarded-for")
+        && let Ok(forwarded_str) = forwarded_for.to_str()
+    {
+        // Take the first IP in the comma-separated list
+        if let Some(client_ip) = forwarded_str.split(',').next() {
+            let ip = client_ip.trim();
+            if !ip.is_empty() {
+                return Some(ip.to_string());
+            }
+        }
+    }
 
-        if let Some(domain_entries) = domains {
-            for (domain, ip) in domain_entries {
-                writeln!(file, "local-data: \"{} IN A {}\"", domain, ip).unwrap();
+    // Check X-Real-IP header (used by nginx and others)
+    if let Some(real_ip) = headers.get("x-real-ip")
+        && let Ok(ip_str) = real_ip.to_str()
+    {
+        let ip = ip_str.trim();
+        if !ip.is_empty() {
+            return Some(ip.to_string());
+        }
+    }
+
+    None
+}
+
+/// Error message returned for both unknown domains and invalid keys, so that
+/// responses don't leak which domains are valid.
+const UNAUTHORIZED_ERROR: &str = "Unauthorized";
+
+/// Answers a request whose authentication failed with `401 Unauthorized` and a `Bearer`
+/// challenge, so clients can tell it apart from a malformed request.
+fn unauthorized(message: String) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(axum::http::header::WWW_AUTHENTICATE, "Bearer")],
+        UpdateResponse {
+            success: false,
+            message,
+            details: None,
+            record_type: None,
+        },
+    )
+        .into_response()
+}
+
+/// Error message returned for domains refused by `denied_domains` or `allowed_domains`.
+const DOMAIN_BLOCKED_ERROR: &str = "Updates for this domain are disabled";
+
+/// Error message returned when `domain_lock_timeout_ms` passes before a domain's lock is free.
+const DOMAIN_LOCKED_ERROR: &str = "Another update for this domain is in progress";
+
+/// Message returned to clients for internal errors when `verbose_errors` is off.
+const INTERNAL_ERROR: &str = "Internal error";
+
+/// Returns the client-facing message for an internal error, hiding `detail` unless
+/// `verbose_errors` is enabled. Callers are responsible for logging the detail.
+fn client_error_message(config: &Config, detail: String) -> String {
+    if config.verbose_errors {
+        detail
+    } else {
+        INTERNAL_ERROR.to_string()
+    }
+}
+
+/// Looks up `domain` and checks `auth_key` against its configured key.
+///
+/// # Returns
+/// The matching domain config, or the reason authorization failed (for logging only;
+/// clients should always be sent `UNAUTHORIZED_ERROR`)
+fn authorize_domain<'a>(
+    state: &'a AppState,
+    domain: &str,
+    auth_key: &str,
+) -> Result<&'a DomainConfig, &'static str> {
+    let domain_config = state.config.find_domain(domain).ok_or("unknown domain")?;
+    let key = domain_key(state, domain_config).map_err(|e| {
+        error!(domain = %domain, error = %e, "Key lookup failed");
+        "key lookup failed"
+    })?;
+
+    // Use constant-time comparison to prevent timing attacks
+    // that could be used to guess the key byte-by-byte
+    if !bool::from(key.as_bytes().ct_eq(auth_key.as_bytes())) {
+        return Err("invalid key");
+    }
+
+    Ok(domain_config)
+}
+
+/// Returns the key for `domain_config`, asking `key_lookup_command` (cached for
+/// `key_lookup_cache_secs`) when one is configured.
+fn domain_key(state: &AppState, domain_config: &DomainConfig) -> Result<String, String> {
+    let Some(lookup) = &state.key_lookup else {
+        return Ok(domain_config.key.clone());
+    };
+
+    let now = Instant::now();
+    let max_age = Duration::from_secs(state.config.key_lookup_cache_secs);
+    if let Some((key, fetched)) = state.key_cache.lock().unwrap().get(&domain_config.name)
+        && now.saturating_duration_since(*fetched) < max_age
+    {
+        return Ok(key.clone());
+    }
+
+    let key = lookup(&domain_config.name)?;
+    state
+        .key_cache
+        .lock()
+        .unwrap()
+        .insert(domain_config.name.clone(), (key.clone(), now));
+    Ok(key)
+}
+
+/// Runs `command` with `domain` appended and returns the key it prints.
+fn run_key_lookup_command(command: &[String], domain: &str) -> Result<String, String> {
+    let (program, args) = command.split_first().ok_or("key_lookup_command is empty")?;
+    let output = Command::new(program)
+        .args(args)
+        .arg(domain)
+        .output()
+        .map_err(|e| format!("Failed to execute {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if key.is_empty() {
+        return Err(format!("{} printed no key for {}", program, domain));
+    }
+    Ok(key)
+}
+
+/// The outcome of an `/update` request, as streamed by `/events`.
+#[derive(Debug, Clone, Serialize)]
+struct UpdateEvent {
+    domain: String,
+    ip: String,
+    /// Seconds since the Unix epoch
+    time: u64,
+    success: bool,
+    message: String,
+}
+
+/// Sends an update outcome to the `/events` subscribers, if there are any.
+fn publish_event(state: &AppState, domain: &str, ip: &str, success: bool, message: &str) {
+    let time = (state.clock)()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    // Sending only fails when nobody is subscribed
+    let _ = state.events.send(UpdateEvent {
+        domain: domain.to_string(),
+        ip: ip.to_string(),
+        time,
+        success,
+        message: message.to_string(),
+    });
+}
+
+/// Numbers requests for the `update` log span.
+static NEXT_REQUEST_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+/// Handles `POST /update`. A request repeating the `Idempotency-Key` of an earlier
+/// successful one (with the same key) gets that response back without being applied again,
+/// while a different request reusing the `Idempotency-Key` is refused.
+/// With `coalesce_updates`, identical requests in flight at once are applied only once.
+///
+/// Everything logged while handling the request is attributed to an `update` span
+/// carrying a request id and, once parsed, the domain.
+async fn update_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    traced_update(state, addr, headers, UpdateParams::Body(body)).await
+}
+
+/// Handles `GET /update` for router DDNS clients that can only send query parameters,
+/// e.g. `/update?domain=home.example.com&ip=203.0.113.5`. Authentication and the update
+/// itself work exactly as for `POST /update`.
+async fn update_query_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    query: Result<Query<UpdateRequest>, axum::extract::rejection::QueryRejection>,
+) -> Response {
+    match query {
+        Ok(Query(payload)) => {
+            traced_update(state, addr, headers, UpdateParams::Query(payload)).await
+        }
+        Err(e) => {
+            warn!(client_ip = %addr.ip(), reason = "parse error", error = %e, "Request failed");
+            UpdateResponse {
+                success: false,
+                message: format!("Failed to parse request: {}", e.body_text()),
+                details: None,
+                record_type: None,
             }
+            .into_response()
         }
+    }
+}
 
-        file
+/// Where an `/update` request carries its parameters.
+enum UpdateParams {
+    /// A JSON or form body, or with `header_params` an empty body and `X-Ddns-*` headers
+    Body(Bytes),
+    /// The query string of `GET /update`
+    Query(UpdateRequest),
+}
+
+async fn traced_update(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    params: UpdateParams,
+) -> Response {
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let span = tracing::info_span!("update", request_id, domain = tracing::field::Empty);
+    idempotent_update(state, addr, headers, params)
+        .instrument(span)
+        .await
+}
+
+async fn idempotent_update(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    params: UpdateParams,
+) -> Response {
+    let ttl = Duration::from_secs(state.config.idempotency_ttl_secs);
+    let cache_key = match (
+        headers.get("idempotency-key"),
+        extract_auth_key(&headers, state.config.trim_submitted_key),
+    ) {
+        (Some(idempotency_key), Ok(auth_key)) if !ttl.is_zero() => format!(
+            "{}:{}",
+            key_fingerprint(&auth_key),
+            String::from_utf8_lossy(idempotency_key.as_bytes())
+        ),
+        _ => return coalesced_update(state, addr, headers, params).await,
+    };
+
+    let request = update_fingerprint(&state.config, addr, &headers, &params);
+    if let Some(cached) = state
+        .idempotent_responses
+        .get(&cache_key, ttl, Instant::now())
+    {
+        if cached.request != request {
+            warn!(idempotency_key = %cache_key, reason = "idempotency key reused", "Request failed");
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                UpdateResponse {
+                    success: false,
+                    message: "Idempotency-Key was already used for a different request".to_string(),
+                    details: None,
+                    record_type: None,
+                },
+            )
+                .into_response();
+        }
+        debug!(idempotency_key = %cache_key, "Replaying response for repeated request");
+        return cached.response.into_response();
     }
 
-    /// Creates a test Config struct with optional parameters.
-    ///
-    /// # Arguments
-    /// * `unbound_config_path` - Optional path to unbound config file
-    /// * `domains` - Optional slice of tuples (domain_name, api_key) for domain configs
-    ///
-    /// # Returns
-    /// A Config struct ready for testing
-    fn create_test_config(
-        unbound_config_path: Option<PathBuf>,
-        domains: Option<&[(&str, &str)]>,
-    ) -> Config {
-        Config {
-            unbound_config_path: unbound_config_path
-                .unwrap_or_else(|| PathBuf::from("/tmp/test.conf")),
-            domains: domains
-                .map(|d| {
-                    d.iter()
-                        .map(|(name, key)| DomainConfig {
-                            name: name.to_string(),
-                            key: key.to_string(),
-                        })
-                        .collect()
-                })
-                .unwrap_or_else(Vec::new),
+    let response = coalesced_update(state.clone(), addr, headers, params).await;
+    if !(response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED) {
+        return response;
+    }
+    let cached = match CachedResponse::buffer(response).await {
+        Ok(cached) => cached,
+        Err(e) => {
+            error!(error = %e, "Failed to buffer response");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    state.idempotent_responses.insert(
+        cache_key,
+        IdempotentResponse {
+            request,
+            response: cached.clone(),
+        },
+        ttl,
+    );
+    cached.into_response()
+}
+
+/// Applies an update, or with `coalesce_updates` joins an identical one already in
+/// progress and answers with its response.
+async fn coalesced_update(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    params: UpdateParams,
+) -> Response {
+    if !state.config.coalesce_updates {
+        return process_update(state, addr, headers, params).await;
+    }
+
+    let key = update_fingerprint(&state.config, addr, &headers, &params);
+    let update = {
+        let mut updates = state.in_flight_updates.updates.lock().unwrap();
+        match updates.get(&key) {
+            Some(update) => {
+                debug!("Joining identical update in progress");
+                update.clone()
+            }
+            None => {
+                let state = state.clone();
+                let finished = key.clone();
+                let update = async move {
+                    let response = process_update(state.clone(), addr, headers, params).await;
+                    let cached = CachedResponse::buffer(response).await.unwrap_or_else(|e| {
+                        error!(error = %e, "Failed to buffer response");
+                        CachedResponse {
+                            stored_at: Instant::now(),
+                            status: StatusCode::INTERNAL_SERVER_ERROR,
+                            headers: HeaderMap::new(),
+                            body: Bytes::new(),
+                        }
+                    });
+                    state
+                        .in_flight_updates
+                        .updates
+                        .lock()
+                        .unwrap()
+                        .remove(&finished);
+                    cached
+                }
+                .boxed()
+                .shared();
+                updates.insert(key, update.clone());
+                update
+            }
+        }
+    };
+    update.await.into_response()
+}
+
+/// Identifies an `/update` request by everything that decides its outcome: the key,
+/// the client address, the headers read as parameters and the body or query.
+fn update_fingerprint(
+    config: &Config,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    params: &UpdateParams,
+) -> String {
+    let mut hasher = Sha256::new();
+    let forwarded_ip = forwarded_client_ip(headers, config.proxy_token.as_deref());
+    hasher.update(forwarded_ip.unwrap_or_else(|| addr.ip().to_string()));
+    for name in [
+        "authorization",
+        "content-type",
+        "x-ddns-domain",
+        "x-ddns-ip",
+    ] {
+        hasher.update([0]);
+        if let Some(value) = headers.get(name) {
+            hasher.update(value.as_bytes());
+        }
+    }
+    match params {
+        UpdateParams::Body(body) => {
+            hasher.update([0]);
+            hasher.update(body);
+        }
+        UpdateParams::Query(payload) => {
+            hasher.update([1]);
+            hasher.update(serde_json::to_vec(payload).unwrap_or_default());
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn process_update(
+    state: AppState,
+    addr: SocketAddr,
+    headers: HeaderMap,
+    params: UpdateParams,
+) -> Response {
+    let config = &state.config;
+    let forwarded_ip = forwarded_client_ip(&headers, config.proxy_token.as_deref());
+    let client_ip_source = match forwarded_ip {
+        Some(_) => IpSource::Forwarded,
+        None => IpSource::AutoDetected,
+    };
+    let client_ip = forwarded_ip.unwrap_or_else(|| addr.ip().to_string());
+
+    // Extract and validate Authorization header
+    let auth_key = match extract_auth_key(&headers, config.trim_submitted_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(client_ip = %client_ip, reason = %e, "Request failed");
+            return unauthorized(e);
+        }
+    };
+
+    // Parse the request based on Content-Type, or from headers for body-less clients
+    let parsed = match params {
+        UpdateParams::Query(payload) => Ok(payload),
+        UpdateParams::Body(body) => {
+            let header_payload = if config.header_params && body.is_empty() {
+                update_request_from_headers(&headers)
+            } else {
+                None
+            };
+            if header_payload.is_none() && body.iter().all(u8::is_ascii_whitespace) {
+                warn!(client_ip = %client_ip, reason = "empty body", "Request failed");
+                return UpdateResponse {
+                    success: false,
+                    message: "Empty request body".to_string(),
+                    details: None,
+                    record_type: None,
+                }
+                .into_response();
+            }
+            match header_payload {
+                Some(payload) => Ok(payload),
+                None if config.require_content_type && !headers.contains_key("content-type") => {
+                    Err("Missing Content-Type header".to_string())
+                }
+                None => parse_update_request(&headers, &body),
+            }
+        }
+    };
+    let mut payload = match parsed {
+        Ok(p) => p,
+        Err(e) => {
+            warn!(client_ip = %client_ip, reason = "parse error", error = %e, "Request failed");
+            return UpdateResponse {
+                success: false,
+                message: format!("Failed to parse request: {}", e),
+                details: None,
+                record_type: None,
+            }
+            .into_response();
+        }
+    };
+    if let Err(e) = payload.resolve_ip_fields() {
+        warn!(client_ip = %client_ip, reason = %e, "Request failed");
+        return UpdateResponse {
+            success: false,
+            message: e,
+            details: None,
+            record_type: None,
+        }
+        .into_response();
+    }
+
+    // Normalize the domain name by removing trailing dot
+    payload.domain = config.normalize_request_domain(&payload.domain);
+    tracing::Span::current().record("domain", tracing::field::display(&payload.domain));
+
+    if config.quiet_noop {
+        // The outcome is still logged at info level when the record actually changes
+        debug!(client_ip = %client_ip, domain = %payload.domain, "Received update request");
+    } else {
+        info!(client_ip = %client_ip, domain = %payload.domain, "Received update request");
+    }
+
+    if let Some(group) = config.find_group(&payload.domain) {
+        if payload.backup_ip.is_some() {
+            return UpdateResponse {
+                success: false,
+                message: "backup_ip is not supported for domain groups".to_string(),
+                details: None,
+                record_type: None,
+            }
+            .into_response();
+        }
+        let (ip, ip_source) = match payload.ip {
+            Some(ip) => (ip, IpSource::Explicit),
+            None => (client_ip, client_ip_source),
+        };
+        let ip = config.record_ip(ip);
+        return update_group(&state, group, &auth_key, &ip, ip_source, payload.ttl).await;
+    }
+
+    // Access lists are checked before the key, so blocked domains are refused cheaply
+    if config.domain_blocked(&payload.domain) {
+        warn!(client_ip = %client_ip, domain = %payload.domain, reason = "domain blocked", "Request failed");
+        return (
+            StatusCode::FORBIDDEN,
+            UpdateResponse {
+                success: false,
+                message: DOMAIN_BLOCKED_ERROR.to_string(),
+                details: None,
+                record_type: None,
+            },
+        )
+            .into_response();
+    }
+
+    let domain_config = match authorize_domain(&state, &payload.domain, &auth_key) {
+        Ok(d) => d,
+        Err(reason) => {
+            warn!(client_ip = %client_ip, domain = %payload.domain, reason = reason, "Request failed");
+            return unauthorized(UNAUTHORIZED_ERROR.to_string());
+        }
+    };
+
+    // Determine the IP address
+    let (ip, ip_source) = match payload.ip {
+        Some(ip) => (ip, IpSource::Explicit),
+        None => (client_ip.clone(), client_ip_source),
+    };
+    let ip = config.record_ip(ip);
+
+    // Hold the domain's lock through the write and reload; the first update wins
+    let Some(_domain_guards) = lock_domains(&state, &[&domain_config.name]).await else {
+        warn!(domain = %payload.domain, ip = %ip, reason = "domain locked", "Request failed");
+        return (
+            StatusCode::CONFLICT,
+            UpdateResponse {
+                success: false,
+                message: DOMAIN_LOCKED_ERROR.to_string(),
+                details: None,
+                record_type: None,
+            },
+        )
+            .into_response();
+    };
+
+    let backup_ip = payload.backup_ip.as_deref().map(canonical_ip);
+    let backup_ip = backup_ip.as_deref();
+    let changed = match apply_update(&state, domain_config, &ip, backup_ip, payload.ttl, None) {
+        Ok(changed) => changed,
+        Err((status, message)) => {
+            return rejected_update(&state, &[domain_config], status, message);
+        }
+    };
+
+    finish_update(
+        &state,
+        &[domain_config],
+        ResponseDetails {
+            domain: payload.domain,
+            ip_source,
+        },
+        &ip,
+        backup_ip,
+        changed,
+        &auth_key,
+    )
+    .await
+}
+
+/// Reloads Unbound after `domains` were written with `ip`, waits for and verifies the
+/// new records, and builds the response shared by single updates, domain groups and
+/// `PATCH`. The domain in `details` (or the list of group members) is named in the
+/// message, and `details` is included with `verbose_response`.
+///
+/// Each distinct reload command runs once. Writes that changed nothing skip the reload,
+/// unless the last one failed, and are answered as a no-op.
+async fn finish_update(
+    state: &AppState,
+    domains: &[&DomainConfig],
+    details: ResponseDetails,
+    ip: &str,
+    backup_ip: Option<&str>,
+    changed: bool,
+    auth_key: &str,
+) -> Response {
+    let config = &state.config;
+    let name = details.domain.as_str();
+
+    // Reload Unbound, unless the records were already in place and loaded
+    let mut reload_commands: Vec<Option<&[String]>> = Vec::new();
+    for domain in domains {
+        let command = config.reload_command_for(domain);
+        if (changed || reload_outstanding(state, command)) && !reload_commands.contains(&command) {
+            reload_commands.push(command);
+        }
+    }
+    let reloading = !reload_commands.is_empty();
+    let mut reloaded = Ok(Reload::Done);
+    for command in reload_commands {
+        match reload_if_enabled(state, command) {
+            Ok(Reload::Done) => {}
+            Ok(Reload::Deferred) => reloaded = Ok(Reload::Deferred),
+            Err(e) => {
+                reloaded = Err(e);
+                break;
+            }
+        }
+    }
+    // A deferred reload leaves the old records in Unbound, so there is nothing to wait for
+    let reloaded_now = reloading && reloaded == Ok(Reload::Done);
+    if reloaded_now {
+        post_reload_delay(state).await;
+    }
+    if reloaded_now && config.reload_enabled && config.verify_resolution {
+        for domain in domains {
+            if let Err(e) = verify_resolution(state, &domain.name, ip).await {
+                error!(domain = %domain.name, ip = %ip, error = %e, "Failed to verify resolution");
+                let message =
+                    client_error_message(config, format!("Failed to verify resolution: {}", e));
+                publish_event(state, &domain.name, ip, false, &message);
+                return UpdateResponse {
+                    success: false,
+                    message,
+                    details: None,
+                    record_type: None,
+                }
+                .into_response();
+            }
+        }
+    }
+    let mut response = match reloaded {
+        Ok(_) if !changed && config.quiet_noop => {
+            debug!(domain = %name, ip = %ip, key_fingerprint = %key_fingerprint(auth_key), "DNS record unchanged");
+            UpdateResponse {
+                success: true,
+                message: "nochg".to_string(),
+                details: None,
+                record_type: None,
+            }
+        }
+        Ok(_) if !changed => {
+            info!(domain = %name, ip = %ip, key_fingerprint = %key_fingerprint(auth_key), "DNS record unchanged");
+            UpdateResponse {
+                success: true,
+                message: format!("No change, already {}", ip),
+                details: None,
+                record_type: None,
+            }
+        }
+        Ok(reload) => {
+            info!(domain = %name, ip = %ip, reload = ?reload, key_fingerprint = %key_fingerprint(auth_key), "DNS record updated successfully");
+            let mut message = updated_message(name, ip, backup_ip);
+            if reload == Reload::Deferred {
+                message.push_str(RELOAD_PENDING);
+            }
+            UpdateResponse {
+                success: true,
+                message,
+                details: None,
+                record_type: None,
+            }
+        }
+        Err(e) => {
+            error!(domain = %name, ip = %ip, error = %e, "Failed to reload Unbound");
+            UpdateResponse {
+                success: false,
+                message: client_error_message(config, format!("Failed to reload Unbound: {}", e)),
+                details: None,
+                record_type: None,
+            }
+        }
+    };
+
+    // The write itself was published when it was applied
+    if !response.success {
+        for domain in domains {
+            publish_event(state, &domain.name, ip, false, &response.message);
         }
     }
+    if response.success {
+        response.record_type = Some(record_type_for(ip));
+    }
+    if config.verbose_response {
+        response.details = Some(details);
+    }
+
+    if response.success && !changed && config.noop_status_304 {
+        return StatusCode::NOT_MODIFIED.into_response();
+    }
+    if response.success && config.success_status == 204 {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    response.into_response()
+}
+
+fn updated_message(domain: &str, ip: &str, backup_ip: Option<&str>) -> String {
+    match backup_ip {
+        Some(backup_ip) => format!("Updated {} to {} (backup {})", domain, ip, backup_ip),
+        None => format!("Updated {} to {}", domain, ip),
+    }
+}
+
+/// Answers an update of `domains` rejected with `status`. A `429` carries a `Retry-After`
+/// header saying when the last of the rate-limited domains accepts updates again, and a
+/// `503` for a locked Unbound config one of `locked_retry_after_secs`.
+fn rejected_update(
+    state: &AppState,
+    domains: &[&DomainConfig],
+    status: StatusCode,
+    message: String,
+) -> Response {
+    let locked = message == CONFIG_LOCKED;
+    let mut response = (
+        status,
+        UpdateResponse {
+            success: false,
+            message,
+            details: None,
+            record_type: None,
+        },
+    )
+        .into_response();
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let now = Instant::now();
+        let wait = domains
+            .iter()
+            .filter_map(|domain| {
+                state.update_rates.retry_after(
+                    &domain.name,
+                    state.config.rate_limit_for(domain),
+                    now,
+                )
+            })
+            .max()
+            .unwrap_or(RATE_WINDOW);
+        // Round up so clients never retry while still limited
+        let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        response
+            .headers_mut()
+            .insert(axum::http::header::RETRY_AFTER, secs.max(1).into());
+    }
+    if status == StatusCode::SERVICE_UNAVAILABLE && locked {
+        response.headers_mut().insert(
+            axum::http::header::RETRY_AFTER,
+            state.config.locked_retry_after_secs.into(),
+        );
+    }
+    response
+}
+
+/// Validates `ip` (and `backup_ip`, if given) and writes them to the Unbound config for an
+/// already-authorized domain, replacing all of its existing A records. A `requested_ttl`
+/// (checked against `max_request_ttl`) takes precedence over a `kept_ttl` carried over
+/// from the current entry, which in turn takes precedence over the configured TTL.
+///
+/// Unbound is not reloaded, so callers writing several records can reload once at the end.
+/// The outcome is published to `/events`; callers only publish failures that come later.
+///
+/// # Returns
+/// Whether the record changed, or the HTTP status and client-facing message if the
+/// update was rejected or failed
+fn apply_update(
+    state: &AppState,
+    domain_config: &DomainConfig,
+    ip: &str,
+    backup_ip: Option<&str>,
+    requested_ttl: Option<u32>,
+    kept_ttl: Option<u32>,
+) -> Result<bool, (StatusCode, String)> {
+    let result = write_update(state, domain_config, ip, backup_ip, requested_ttl, kept_ttl);
+    let message = match &result {
+        Ok(true) => updated_message(&domain_config.name, ip, backup_ip),
+        Ok(false) => format!("No change, already {}", ip),
+        Err((_, message)) => message.clone(),
+    };
+    publish_event(state, &domain_config.name, ip, result.is_ok(), &message);
+    result
+}
+
+/// The checks and write of [`apply_update`], without publishing the outcome.
+fn write_update(
+    state: &AppState,
+    domain_config: &DomainConfig,
+    ip: &str,
+    backup_ip: Option<&str>,
+    requested_ttl: Option<u32>,
+    kept_ttl: Option<u32>,
+) -> Result<bool, (StatusCode, String)> {
+    let config = &state.config;
+    let domain = &domain_config.name;
+
+    if is_frozen(&config.freeze_schedule, (state.clock)()) {
+        warn!(domain = %domain, ip = %ip, reason = "freeze schedule", "Request failed");
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Updates are frozen by the configured freeze schedule".to_string(),
+        ));
+    }
+
+    if let Some(rejected) = std::iter::once(ip)
+        .chain(backup_ip)
+        .find(|ip| !domain_config.ip_version.allows(ip))
+    {
+        warn!(domain = %domain, ip = %rejected, reason = "wrong address family", "Request failed");
+        let family = match domain_config.ip_version {
+            IpVersion::V6 => "IPv6",
+            _ => "IPv4",
+        };
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} only accepts {} addresses, got {}",
+                domain, family, rejected
+            ),
+        ));
+    }
+
+    // Validate the IP address; its family decides whether an A or AAAA record is written
+    let parsed_ip = parse_ip(ip).map_err(|e| {
+        warn!(domain = %domain, ip = %ip, reason = "invalid IP address", "Request failed");
+        (StatusCode::BAD_REQUEST, e)
+    })?;
+
+    if let Some(backup_ip) = backup_ip {
+        if config.max_records_per_domain < 2 {
+            warn!(domain = %domain, ip = %ip, reason = "backup IP not allowed", "Request failed");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "backup_ip is not allowed: max_records_per_domain is {}",
+                    config.max_records_per_domain
+                ),
+            ));
+        }
+        match parse_ip(backup_ip) {
+            Ok(backup) if backup.is_ipv4() == parsed_ip.is_ipv4() => {}
+            Ok(_) => {
+                warn!(domain = %domain, ip = %backup_ip, reason = "backup IP family mismatch", "Request failed");
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "backup_ip {} must be the same address family as {}",
+                        backup_ip, ip
+                    ),
+                ));
+            }
+            Err(e) => {
+                warn!(domain = %domain, ip = %backup_ip, reason = "invalid IP address", "Request failed");
+                return Err((StatusCode::BAD_REQUEST, e));
+            }
+        }
+    }
+
+    if let (Some(ttl), Some(max)) = (requested_ttl, config.max_request_ttl)
+        && ttl > max
+    {
+        warn!(domain = %domain, ip = %ip, ttl = ttl, reason = "TTL above maximum", "Request failed");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Requested TTL {} exceeds the maximum of {}", ttl, max),
+        ));
+    }
+
+    // Enforce the per-domain update rate; only requests that passed validation count
+    if let Err(e) = state.update_rates.check_and_record(
+        domain,
+        config.rate_limit_for(domain_config),
+        Instant::now(),
+    ) {
+        warn!(domain = %domain, ip = %ip, reason = %e, "Request failed");
+        return Err((StatusCode::TOO_MANY_REQUESTS, e));
+    }
+
+    // Refuse to write when the disk is nearly full, since even the write can fail midway
+    if let Some(min_free_bytes) = config.min_free_bytes
+        && let Err(e) = available_space(config.unbound_path_for(domain_config))
+            .and_then(|available| check_free_space(available, min_free_bytes))
+    {
+        error!(domain = %domain, ip = %ip, error = %e, "Refusing to update configuration");
+        return Err((StatusCode::INSUFFICIENT_STORAGE, e));
+    }
+
+    let ips: Vec<&str> = std::iter::once(ip).chain(backup_ip).collect();
+    let ttl = requested_ttl
+        .or(kept_ttl)
+        .or(domain_config.ttl_for(&parsed_ip));
+    let known = KnownRecord {
+        ips: ips.iter().filter_map(|ip| parse_ip(ip).ok()).collect(),
+        ttl,
+        written_to: WrittenTo::current(config, domain_config),
+    };
+
+    // The persisted state tells us this exact record is already written, to a file
+    // nothing has touched since
+    if known.written_to.is_some() && state.last_known.get(domain).as_ref() == Some(&known) {
+        return Ok(false);
+    }
+
+    // Update the Unbound configuration
+    let path = config.unbound_path_for(domain_config);
+    let section_store;
+    let store: &dyn ConfigStore = match &domain_config.section {
+        Some(view) => {
+            section_store = SectionStore::new(path, view);
+            &section_store
+        }
+        None => path,
+    };
+    let sorted_store;
+    let store = if config.sort_managed_entries {
+        sorted_store = SortedStore::new(store, config);
+        &sorted_store
+    } else {
+        store
+    };
+    let changed = update_unbound_config(
+        store,
+        domain,
+        &ips,
+        ttl,
+        config.entry_format(),
+        config.verify_after_write,
+        domain_config.exclusive_family,
+    )
+    .map_err(|e| {
+        if e == CONFIG_LOCKED {
+            warn!(domain = %domain, ip = %ip, reason = "config locked", "Request failed");
+        } else {
+            error!(domain = %domain, ip = %ip, error = %e, "Failed to update configuration");
+        }
+        write_failure(config, e)
+    })?;
+
+    // The Unbound config is already written, so a failure here only loses the shortcut
+    let known = KnownRecord {
+        written_to: WrittenTo::current(config, domain_config),
+        ..known
+    };
+    if let Err(e) = state.last_known.record(domain, known) {
+        error!(domain = %domain, error = %e, "Failed to save state");
+    }
+
+    Ok(changed)
+}
+
+/// Updates every member of `group` to `ip` and reloads Unbound once, running each
+/// distinct member `reload_command` once.
+async fn update_group(
+    state: &AppState,
+    group: &DomainGroup,
+    auth_key: &str,
+    ip: &str,
+    ip_source: IpSource,
+    ttl: Option<u32>,
+) -> Response {
+    let config = &state.config;
+    let members: Vec<&DomainConfig> = group
+        .domains
+        .iter()
+        .filter_map(|d| config.find_domain(d))
+        .collect();
+
+    // Access lists are checked before the key, as for single updates
+    if let Some(member) = members.iter().find(|m| config.domain_blocked(&m.name)) {
+        warn!(group = %group.name, domain = %member.name, reason = "domain blocked", "Request failed");
+        return rejected_update(
+            state,
+            &members,
+            StatusCode::FORBIDDEN,
+            DOMAIN_BLOCKED_ERROR.to_string(),
+        );
+    }
+    if !bool::from(group.key.as_bytes().ct_eq(auth_key.as_bytes())) {
+        warn!(group = %group.name, reason = "invalid key", "Request failed");
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    // Hold every member's lock through the write and reload, like a single update does
+    let names: Vec<&str> = members.iter().map(|m| m.name.as_str()).collect();
+    let Some(_domain_guards) = lock_domains(state, &names).await else {
+        warn!(group = %group.name, ip = %ip, reason = "domain locked", "Request failed");
+        return rejected_update(
+            state,
+            &members,
+            StatusCode::CONFLICT,
+            DOMAIN_LOCKED_ERROR.to_string(),
+        );
+    };
+
+    let changed = match apply_group_update(state, group, ip, ttl) {
+        Ok(changed) => changed,
+        Err((status, message)) => {
+            return rejected_update(state, &members, status, message);
+        }
+    };
+
+    info!(group = %group.name, domains = ?group.domains, ip = %ip, changed, "Domain group written");
+    finish_update(
+        state,
+        &members,
+        ResponseDetails {
+            domain: group.domains.join(", "),
+            ip_source,
+        },
+        ip,
+        None,
+        changed,
+        auth_key,
+    )
+    .await
+}
+
+/// An Unbound config held in memory, so several updates can be applied before any of
+/// them is written out.
+struct StagedConfig(std::cell::RefCell<String>);
+
+impl ConfigStore for StagedConfig {
+    fn read(&self) -> Result<String, String> {
+        Ok(self.0.borrow().clone())
+    }
+
+    fn write(&self, content: &str) -> Result<(), String> {
+        *self.0.borrow_mut() = content.to_string();
+        Ok(())
+    }
+}
+
+/// Writes `ip` for every member of `group`. All members are checked and staged in
+/// memory first, so a failure for any member leaves every file untouched. The outcome is
+/// published to `/events` for every member.
+///
+/// # Returns
+/// Whether any Unbound config changed
+fn apply_group_update(
+    state: &AppState,
+    group: &DomainGroup,
+    ip: &str,
+    requested_ttl: Option<u32>,
+) -> Result<bool, (StatusCode, String)> {
+    let result = write_group_update(state, group, ip, requested_ttl);
+    for member in &group.domains {
+        match &result {
+            Ok(true) => publish_event(state, member, ip, true, &updated_message(member, ip, None)),
+            Ok(false) => publish_event(
+                state,
+                member,
+                ip,
+                true,
+                &format!("No change, already {}", ip),
+            ),
+            Err((_, message)) => publish_event(state, member, ip, false, message),
+        }
+    }
+    result
+}
+
+/// The checks and writes of [`apply_group_update`], without publishing the outcome.
+fn write_group_update(
+    state: &AppState,
+    group: &DomainGroup,
+    ip: &str,
+    requested_ttl: Option<u32>,
+) -> Result<bool, (StatusCode, String)> {
+    let config = &state.config;
+    let members: Vec<&DomainConfig> = group
+        .domains
+        .iter()
+        .filter_map(|d| config.find_domain(d))
+        .collect();
+
+    if is_frozen(&config.freeze_schedule, (state.clock)()) {
+        warn!(group = %group.name, ip = %ip, reason = "freeze schedule", "Request failed");
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Updates are frozen by the configured freeze schedule".to_string(),
+        ));
+    }
+    let parsed_ip = parse_ip(ip).map_err(|e| {
+        warn!(group = %group.name, ip = %ip, reason = "invalid IP address", "Request failed");
+        (StatusCode::BAD_REQUEST, e)
+    })?;
+    if let Some(member) = members.iter().find(|m| !m.ip_version.allows(ip)) {
+        let family = if parsed_ip.is_ipv4() { "IPv6" } else { "IPv4" };
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} only accepts {} addresses, got {}",
+                member.name, family, ip
+            ),
+        ));
+    }
+    if let (Some(ttl), Some(max)) = (requested_ttl, config.max_request_ttl)
+        && ttl > max
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Requested TTL {} exceeds the maximum of {}", ttl, max),
+        ));
+    }
+    for member in &members {
+        if let Err(e) = state.update_rates.check_and_record(
+            &member.name,
+            config.rate_limit_for(member),
+            Instant::now(),
+        ) {
+            warn!(group = %group.name, domain = %member.name, reason = %e, "Request failed");
+            return Err((StatusCode::TOO_MANY_REQUESTS, e));
+        }
+    }
+
+    let internal_error = |e: String| {
+        if e == CONFIG_LOCKED {
+            warn!(group = %group.name, ip = %ip, reason = "config locked", "Request failed");
+        } else {
+            error!(group = %group.name, ip = %ip, error = %e, "Failed to update configuration");
+        }
+        write_failure(config, e)
+    };
+
+    // Stage every member's entry, one in-memory copy per Unbound file
+    let mut staged: Vec<(&PathBuf, StagedConfig)> = Vec::new();
+    let mut changed = false;
+    for member in &members {
+        let path = config.unbound_path_for(member);
+        let index = match staged.iter().position(|(p, _)| *p == path) {
+            Some(index) => index,
+            None => {
+                let content = path.read().map_err(internal_error)?;
+                staged.push((path, StagedConfig(std::cell::RefCell::new(content))));
+                staged.len() - 1
+            }
+        };
+        let staged_config: &dyn ConfigStore = &staged[index].1;
+        let section_store;
+        let store = match &member.section {
+            Some(view) => {
+                section_store = SectionStore::new(staged_config, view);
+                &section_store
+            }
+            None => staged_config,
+        };
+        let sorted_store;
+        let store = if config.sort_managed_entries {
+            sorted_store = SortedStore::new(store, config);
+            &sorted_store
+        } else {
+            store
+        };
+        changed |= update_unbound_config(
+            store,
+            &member.name,
+            &[ip],
+            requested_ttl.or(member.ttl_for(&parsed_ip)),
+            config.entry_format(),
+            false,
+            member.exclusive_family,
+        )
+        .map_err(internal_error)?;
+    }
+
+    if let Some(min_free_bytes) = config.min_free_bytes {
+        for (path, _) in &staged {
+            if let Err(e) = available_space(path)
+                .and_then(|available| check_free_space(available, min_free_bytes))
+            {
+                error!(group = %group.name, ip = %ip, error = %e, "Refusing to update configuration");
+                return Err((StatusCode::INSUFFICIENT_STORAGE, e));
+            }
+        }
+    }
+
+    for (path, content) in &staged {
+        path.write(&content.0.borrow()).map_err(internal_error)?;
+    }
+
+    // Same read-back check as single updates, once all files are written
+    if config.verify_after_write {
+        for member in &members {
+            let ttl = requested_ttl.or(member.ttl_for(&parsed_ip));
+            let entry = config.entry_format().render(&member.name, ip, ttl);
+            let written = config
+                .unbound_path_for(member)
+                .read()
+                .map_err(internal_error)?;
+            if !written.contains(&entry) {
+                return Err(internal_error(READ_BACK_MISMATCH.to_string()));
+            }
+        }
+    }
+
+    // Keep the no-op shortcut of single updates in line with what was just written
+    for member in &members {
+        let known = KnownRecord {
+            ips: vec![parsed_ip],
+            ttl: requested_ttl.or(member.ttl_for(&parsed_ip)),
+            written_to: WrittenTo::current(config, member),
+        };
+        if let Err(e) = state.last_known.record(&member.name, known) {
+            error!(domain = %member.name, error = %e, "Failed to save state");
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Result of a single line of a `/update/stream` request.
+#[derive(Debug, Serialize)]
+struct StreamUpdateResult {
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<String>,
+    success: bool,
+    /// Whether the write changed the Unbound config, as opposed to a no-op.
+    changed: bool,
+    message: String,
+    /// The address written, checked by `verify_resolution` after the reload.
+    #[serde(skip)]
+    ip: Option<String>,
+}
+
+/// Counts of line outcomes, sent as the last line of a `/update/stream` response.
+#[derive(Debug, Default, Serialize)]
+struct StreamSummary {
+    changed: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamSummaryResult {
+    summary: StreamSummary,
+}
+
+/// Line of a `/update/stream` response reporting the single reload.
+#[derive(Debug, Serialize)]
+struct StreamReloadResult {
+    reload: bool,
+    success: bool,
+    message: String,
+}
+
+/// Line of a `/update/stream` response reporting the `verify_resolution` check of a
+/// changed domain after the reload.
+#[derive(Debug, Serialize)]
+struct StreamVerifyResult {
+    verify: bool,
+    domain: String,
+    success: bool,
+    message: String,
+}
+
+/// Applies newline-delimited JSON updates from the request body as they arrive.
+///
+/// Each line is an update object (`{"domain": ..., "ip": ...}`) authorized with the key
+/// from the Authorization header. A result line is streamed back for every update, and
+/// Unbound is reloaded once after the whole body has been processed.
+async fn update_stream_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let config = &state.config;
+    let client_ip = extract_client_ip(&headers, &addr, config.proxy_token.as_deref());
+
+    let auth_key = match extract_auth_key(&headers, config.trim_submitted_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(client_ip = %client_ip, reason = %e, "Request failed");
+            return unauthorized(e);
+        }
+    };
+
+    info!(client_ip = %client_ip, "Received streaming update request");
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    tokio::spawn(process_update_stream(
+        state.clone(),
+        client_ip,
+        auth_key,
+        body,
+        tx,
+    ));
+
+    let results = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv()
+            .await
+            .map(|line| (Ok::<_, std::convert::Infallible>(line), rx))
+    });
+
+    (
+        [(axum::http::header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(results),
+    )
+        .into_response()
+}
+
+async fn process_update_stream(
+    state: AppState,
+    client_ip: String,
+    auth_key: String,
+    body: Body,
+    tx: tokio::sync::mpsc::UnboundedSender<String>,
+) {
+    let mut chunks = body.into_data_stream();
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut line_number = 0;
+    let mut written = 0;
+    // Distinct reload commands of the written domains, `None` being `unbound-control`
+    let mut reload_commands: Vec<Option<&[String]>> = Vec::new();
+    // Locks of the domains written so far, held until the batch is reloaded
+    let mut domain_guards: HashMap<String, Vec<tokio::sync::OwnedMutexGuard<()>>> = HashMap::new();
+    let mut changed_records: Vec<(String, String)> = Vec::new();
+    let mut summary = StreamSummary::default();
+    let mut finished = false;
+
+    while !finished {
+        match chunks.next().await {
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                warn!(client_ip = %client_ip, error = %e, "Failed to read streaming request body");
+                finished = true;
+            }
+            None => {
+                // Treat any trailing data without a newline as the final line
+                if !buffer.is_empty() {
+                    buffer.push(b'\n');
+                }
+                finished = true;
+            }
+        }
+
+        while let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            line_number += 1;
+
+            let result = apply_stream_line(
+                &state,
+                &client_ip,
+                &auth_key,
+                &line,
+                line_number,
+                &mut domain_guards,
+            )
+            .await;
+            if let (true, Some(domain), Some(ip)) = (result.changed, &result.domain, &result.ip) {
+                changed_records.push((domain.clone(), ip.clone()));
+            }
+            // Lines that left the config as it was give Unbound nothing new to load,
+            // unless its last reload failed
+            let command = result
+                .domain
+                .as_deref()
+                .and_then(|domain| state.config.find_domain(domain))
+                .map_or(state.config.reload_command.as_deref(), |domain_config| {
+                    state.config.reload_command_for(domain_config)
+                });
+            if result.success
+                && (result.changed || reload_outstanding(&state, command))
+                && !reload_commands.contains(&command)
+            {
+                reload_commands.push(command);
+            }
+            if result.success && result.changed {
+                written += 1;
+            }
+            match (result.success, result.changed) {
+                (true, true) => summary.changed += 1,
+                (true, false) => summary.unchanged += 1,
+                (false, _) => summary.failed += 1,
+            }
+            send_json_line(&tx, &result);
+        }
+    }
+
+    // Every write above left Unbound alone, so run each reload command once for the batch
+    let config = &state.config;
+    if !config.reload_enabled {
+        reload_commands.clear();
+    }
+    let mut reloaded_now = !reload_commands.is_empty();
+    for command in reload_commands {
+        let reloaded = reload(&state, command);
+        reloaded_now &= reloaded == Ok(Reload::Done);
+        let result = match reloaded {
+            Ok(Reload::Done) => {
+                info!(client_ip = %client_ip, updates = written, key_fingerprint = %key_fingerprint(&auth_key), "DNS records updated successfully");
+                StreamReloadResult {
+                    reload: true,
+                    success: true,
+                    message: format!("Reloaded Unbound after {} updates", written),
+                }
+            }
+            Ok(Reload::Deferred) => {
+                info!(client_ip = %client_ip, updates = written, key_fingerprint = %key_fingerprint(&auth_key), "DNS records updated, Unbound reload deferred");
+                StreamReloadResult {
+                    reload: true,
+                    success: true,
+                    message: format!("Unbound reload pending after {} updates", written),
+                }
+            }
+            Err(e) => {
+                error!(client_ip = %client_ip, error = %e, "Failed to reload Unbound");
+                let message =
+                    client_error_message(config, format!("Failed to reload Unbound: {}", e));
+                for (domain, ip) in &changed_records {
+                    let reloaded_by = config
+                        .find_domain(domain)
+                        .map_or(config.reload_command.as_deref(), |domain_config| {
+                            config.reload_command_for(domain_config)
+                        });
+                    if reloaded_by == command {
+                        publish_event(&state, domain, ip, false, &message);
+                    }
+                }
+                StreamReloadResult {
+                    reload: true,
+                    success: false,
+                    message,
+                }
+            }
+        };
+        send_json_line(&tx, &result);
+    }
+
+    if reloaded_now && !changed_records.is_empty() {
+        post_reload_delay(&state).await;
+        if config.verify_resolution {
+            for (domain, ip) in changed_records {
+                let result = match verify_resolution(&state, &domain, &ip).await {
+                    Ok(()) => StreamVerifyResult {
+                        verify: true,
+                        message: format!("{} resolves to {}", domain, ip),
+                        domain,
+                        success: true,
+                    },
+                    Err(e) => {
+                        error!(domain = %domain, ip = %ip, error = %e, "Failed to verify resolution");
+                        let message = client_error_message(
+                            config,
+                            format!("Failed to verify resolution: {}", e),
+                        );
+                        publish_event(&state, &domain, &ip, false, &message);
+                        StreamVerifyResult {
+                            verify: true,
+                            domain,
+                            success: false,
+                            message,
+                        }
+                    }
+                };
+                send_json_line(&tx, &result);
+            }
+        }
+    }
+    drop(domain_guards);
+
+    send_json_line(&tx, &StreamSummaryResult { summary });
+}
+
+/// Serializes `value` as a single NDJSON line. Send errors mean the client went away,
+/// in which case the remaining results are simply dropped.
+fn send_json_line<T: Serialize>(tx: &tokio::sync::mpsc::UnboundedSender<String>, value: &T) {
+    if let Ok(json) = serde_json::to_string(value) {
+        let _ = tx.send(json + "\n");
+    }
+}
+
+/// Parses, authorizes and writes a single line of a `/update/stream` request. The domain's
+/// lock is taken on its first line and kept in `domain_guards` for the rest of the batch.
+async fn apply_stream_line(
+    state: &AppState,
+    client_ip: &str,
+    auth_key: &str,
+    line: &[u8],
+    line_number: usize,
+    domain_guards: &mut HashMap<String, Vec<tokio::sync::OwnedMutexGuard<()>>>,
+) -> StreamUpdateResult {
+    let mut payload: UpdateRequest = match serde_json::from_slice(line) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!(client_ip = %client_ip, line = line_number, reason = "parse error", error = %e, "Request failed");
+            return StreamUpdateResult {
+                line: line_number,
+                domain: None,
+                success: false,
+                changed: false,
+                message: format!("Failed to parse request: Invalid JSON: {}", e),
+                ip: None,
+            };
+        }
+    };
+    if let Err(e) = payload.resolve_ip_fields() {
+        warn!(client_ip = %client_ip, line = line_number, reason = %e, "Request failed");
+        return StreamUpdateResult {
+            line: line_number,
+            domain: None,
+            success: false,
+            changed: false,
+            message: e,
+            ip: None,
+        };
+    }
+
+    // Normalize the domain name by removing trailing dot
+    payload.domain = state.config.normalize_request_domain(&payload.domain);
+
+    if state.config.domain_blocked(&payload.domain) {
+        warn!(client_ip = %client_ip, domain = %payload.domain, line = line_number, reason = "domain blocked", "Request failed");
+        return StreamUpdateResult {
+            line: line_number,
+            domain: Some(payload.domain),
+            success: false,
+            changed: false,
+            message: DOMAIN_BLOCKED_ERROR.to_string(),
+            ip: None,
+        };
+    }
+
+    let domain_config = match authorize_domain(state, &payload.domain, auth_key) {
+        Ok(d) => d,
+        Err(reason) => {
+            warn!(client_ip = %client_ip, domain = %payload.domain, reason = reason, "Request failed");
+            return StreamUpdateResult {
+                line: line_number,
+                domain: Some(payload.domain),
+                success: false,
+                changed: false,
+                message: UNAUTHORIZED_ERROR.to_string(),
+                ip: None,
+            };
+        }
+    };
+
+    if !domain_guards.contains_key(&domain_config.name) {
+        let Some(guards) = lock_domains(state, &[&domain_config.name]).await else {
+            warn!(client_ip = %client_ip, domain = %payload.domain, line = line_number, reason = "domain locked", "Request failed");
+            return StreamUpdateResult {
+                line: line_number,
+                domain: Some(payload.domain),
+                success: false,
+                changed: false,
+                message: DOMAIN_LOCKED_ERROR.to_string(),
+                ip: None,
+            };
+        };
+        domain_guards.insert(domain_config.name.clone(), guards);
+    }
+
+    let ip = state
+        .config
+        .record_ip(payload.ip.unwrap_or_else(|| client_ip.to_string()));
+    let backup_ip = payload.backup_ip.as_deref().map(canonical_ip);
+    let backup_ip = backup_ip.as_deref();
+    match apply_update(state, domain_config, &ip, backup_ip, payload.ttl, None) {
+        Ok(changed) => StreamUpdateResult {
+            line: line_number,
+            message: if changed {
+                updated_message(&payload.domain, &ip, backup_ip)
+            } else {
+                format!("No change, already {}", ip)
+            },
+            domain: Some(payload.domain),
+            success: true,
+            changed,
+            ip: Some(ip),
+        },
+        Err((_, message)) => StreamUpdateResult {
+            line: line_number,
+            domain: Some(payload.domain),
+            success: false,
+            changed: false,
+            message,
+            ip: None,
+        },
+    }
+}
+fn parse_update_request(headers: &HeaderMap, body: &Bytes) -> Result<UpdateRequest, String> {
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    if content_type.contains("application/json") {
+        // Parse as JSON
+        serde_json::from_slice(body).map_err(|e| format!("Invalid JSON: {}", e))
+    } else {
+        // Parse as form data (default)
+        let body_str = std::str::from_utf8(body).map_err(|e| format!("Invalid UTF-8: {}", e))?;
+
+        serde_urlencoded::from_str(body_str).map_err(|e| format!("Invalid form data: {}", e))
+    }
+}
+
+/// Builds an update request from the `X-Ddns-Domain` and `X-Ddns-IP` headers.
+///
+/// # Returns
+/// `None` if the domain header is missing or not valid text
+fn update_request_from_headers(headers: &HeaderMap) -> Option<UpdateRequest> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+    };
+    Some(UpdateRequest {
+        domain: header("x-ddns-domain")?,
+        ip: header("x-ddns-ip"),
+        backup_ip: None,
+        ttl: None,
+        ipv4: None,
+        ipv6: None,
+    })
+}
+
+/// Returns the current records for a domain as stored in the Unbound config.
+async fn current_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<CurrentQuery>,
+) -> Response {
+    let config = &state.config;
+    let domain = config.normalize_request_domain(&query.domain);
+
+    let auth_key = match extract_auth_key(&headers, config.trim_submitted_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(domain = %domain, reason = %e, "Current records request failed");
+            return unauthorized(e);
+        }
+    };
+
+    let domain_config = match authorize_domain(&state, &domain, &auth_key) {
+        Ok(domain_config) => domain_config,
+        Err(reason) => {
+            warn!(domain = %domain, reason = reason, "Current records request failed");
+            return unauthorized(UNAUTHORIZED_ERROR.to_string());
+        }
+    };
+
+    let content = match fs::read_to_string(config.unbound_path_for(domain_config)) {
+        Ok(content) => content,
+        Err(e) => {
+            error!(domain = %domain, error = %e, "Failed to read Unbound config");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                UpdateResponse {
+                    success: false,
+                    message: client_error_message(
+                        config,
+                        format!("Failed to read Unbound config: {}", e),
+                    ),
+                    details: None,
+                    record_type: None,
+                },
+            )
+                .into_response();
+        }
+    };
+
+    let records = parse_records(&content, &domain);
+    axum::Json(CurrentResponse {
+        success: true,
+        domain,
+        records,
+    })
+    .into_response()
+}
+
+/// Returns the `local-data` entries of every configured domain the supplied key is valid
+/// for, as plain text that can be pasted into an Unbound config.
+async fn export_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = &state.config;
+
+    let auth_key = match extract_auth_key(&headers, config.trim_submitted_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(reason = %e, "Export request failed");
+            return unauthorized(e);
+        }
+    };
+
+    let domains: Vec<&DomainConfig> = config
+        .domains
+        .iter()
+        .filter(|d| authorize_domain(&state, &d.name, &auth_key).is_ok())
+        .collect();
+    if domains.is_empty() {
+        warn!(reason = "invalid key", "Export request failed");
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    let format = config.entry_format();
+    let mut contents: HashMap<&PathBuf, String> = HashMap::new();
+    let mut body = String::new();
+    for domain in domains {
+        let unbound_path = config.unbound_path_for(domain);
+        if !contents.contains_key(unbound_path) {
+            match fs::read_to_string(unbound_path) {
+                Ok(content) => {
+                    contents.insert(unbound_path, content);
+                }
+                Err(e) => {
+                    error!(path = ?unbound_path, error = %e, "Failed to read Unbound config");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        UpdateResponse {
+                            success: false,
+                            message: client_error_message(
+                                config,
+                                format!("Failed to read Unbound config: {}", e),
+                            ),
+                            details: None,
+                            record_type: None,
+                        },
+                    )
+                        .into_response();
+                }
+            }
+        }
+        let Ok(re) = format.entry_regex(&domain.name, None) else {
+            continue;
+        };
+        for entry in re.find_iter(&contents[unbound_path]) {
+            body.push_str(entry.as_str().trim());
+            body.push('\n');
+        }
+    }
+
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; charset=utf-8",
+        )],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct ServerIpResponse {
+    success: bool,
+    ip: String,
+}
+
+/// Returns the server's own public IP address, as seen by an outbound echo service.
+async fn server_ip_handler(State(state): State<AppState>) -> Response {
+    match state.server_ip.get().await {
+        Ok(ip) => axum::Json(ServerIpResponse {
+            success: true,
+            ip: ip.to_string(),
+        })
+        .into_response(),
+        Err(e) => {
+            error!(error = %e, "Failed to determine server public IP");
+            (
+                StatusCode::BAD_GATEWAY,
+                UpdateResponse {
+                    success: false,
+                    message: client_error_message(&state.config, e),
+                    details: None,
+                    record_type: None,
+                },
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Polls the DNS resolver until `domain` resolves to `ip`, giving up after
+/// `verify_resolution_timeout_ms`.
+async fn verify_resolution(state: &AppState, domain: &str, ip: &str) -> Result<(), String> {
+    const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+    let expected: IpAddr = ip
+        .parse()
+        .map_err(|e| format!("Invalid IP '{}': {}", ip, e))?;
+    let qtype = if expected.is_ipv6() {
+        QTYPE_AAAA
+    } else {
+        QTYPE_A
+    };
+    let timeout = Duration::from_millis(state.config.verify_resolution_timeout_ms);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let query = (state.dns_resolver)(domain, qtype);
+        let last = match tokio::time::timeout_at(deadline.into(), query).await {
+            Ok(Ok(ips)) if ips.contains(&expected) => return Ok(()),
+            Ok(Ok(ips)) => format!("got {:?}", ips),
+            Ok(Err(e)) => e,
+            Err(_) => "query timed out".to_string(),
+        };
+        if Instant::now() + RETRY_INTERVAL >= deadline {
+            return Err(format!(
+                "{} did not resolve to {} within {}ms ({})",
+                domain,
+                ip,
+                timeout.as_millis(),
+                last
+            ));
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// DNS query type for A records.
+const QTYPE_A: u16 = 1;
+/// DNS query type for AAAA records.
+const QTYPE_AAAA: u16 = 28;
+/// DNS query type for TXT records.
+const QTYPE_TXT: u16 = 16;
+
+/// Sends a single query of type `qtype` for `domain` over UDP to `server` and returns
+/// the addresses in the answer.
+async fn query_records(server: &str, domain: &str, qtype: u16) -> Result<Vec<IpAddr>, String> {
+    let (response, id) = exchange(server, domain, qtype).await?;
+    parse_response(&response, id)
+}
+
+/// Queries `server` for the TXT records of `name` and returns their text.
+async fn query_txt(server: &str, name: &str) -> Result<Vec<String>, String> {
+    let (response, id) = exchange(server, name, QTYPE_TXT).await?;
+    parse_txt_response(&response, id)
+}
+
+/// Sends a single query over UDP to `server` and returns the response with its id.
+async fn exchange(server: &str, domain: &str, qtype: u16) -> Result<(Vec<u8>, u16), String> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| format!("Failed to open DNS socket: {}", e))?;
+    socket
+        .connect(server)
+        .await
+        .map_err(|e| format!("Failed to reach DNS server {}: {}", server, e))?;
+
+    // Any id will do since the socket only receives from `server`
+    let id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u16);
+    socket
+        .send(&build_query(id, domain, qtype)?)
+        .await
+        .map_err(|e| format!("Failed to send DNS query: {}", e))?;
+    let mut buf = [0u8; 512];
+    let len = socket
+        .recv(&mut buf)
+        .await
+        .map_err(|e| format!("Failed to read DNS response: {}", e))?;
+    Ok((buf[..len].to_vec(), id))
+}
+
+/// Builds a recursive DNS query for the records of type `qtype` of `domain`.
+fn build_query(id: u16, domain: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(18 + domain.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in domain.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("Cannot query invalid name '{}'", domain));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, then QTYPE and QCLASS IN
+    query.push(0x00);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01]);
+    Ok(query)
+}
+
+/// Extracts the A and AAAA record addresses from the answer section of a DNS response.
+fn parse_response(response: &[u8], id: u16) -> Result<Vec<IpAddr>, String> {
+    let mut ips = Vec::new();
+    for (record_type, rdata) in answers(response, id)? {
+        if record_type == QTYPE_A && rdata.len() == 4 {
+            ips.push(IpAddr::V4(Ipv4Addr::new(
+                rdata[0], rdata[1], rdata[2], rdata[3],
+            )));
+        } else if record_type == QTYPE_AAAA && rdata.len() == 16 {
+            let octets: [u8; 16] = rdata.try_into().map_err(|_| MALFORMED_DNS_RESPONSE)?;
+            ips.push(IpAddr::from(octets));
+        }
+    }
+    Ok(ips)
+}
+
+/// Extracts the text of each TXT record in the answer section of a DNS response, joining
+/// the strings a record is split into.
+fn parse_txt_response(response: &[u8], id: u16) -> Result<Vec<String>, String> {
+    let mut texts = Vec::new();
+    for (record_type, mut rdata) in answers(response, id)? {
+        if record_type != QTYPE_TXT {
+            continue;
+        }
+        let mut text = Vec::new();
+        while let Some((&len, rest)) = rdata.split_first() {
+            let chunk = rest.get(..len as usize).ok_or(MALFORMED_DNS_RESPONSE)?;
+            text.extend_from_slice(chunk);
+            rdata = &rest[len as usize..];
+        }
+        texts.push(String::from_utf8_lossy(&text).into_owned());
+    }
+    Ok(texts)
+}
+
+const MALFORMED_DNS_RESPONSE: &str = "Malformed DNS response";
+
+/// Checks the header of a DNS response and returns the type and data of every record
+/// in its answer section.
+fn answers(response: &[u8], id: u16) -> Result<Vec<(u16, &[u8])>, String> {
+    if response.len() < 12 || response[..2] != id.to_be_bytes() {
+        return Err(MALFORMED_DNS_RESPONSE.to_string());
+    }
+    let rcode = response[3] & 0x0f;
+    if rcode != 0 {
+        return Err(format!("DNS query failed with rcode {}", rcode));
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    // Skips a possibly compressed name, returning the offset just past it
+    let skip_name = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *response.get(pos)?;
+            match len {
+                0 => return Some(pos + 1),
+                len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+                len => pos += 1 + len as usize,
+            }
+        }
+    };
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pos).ok_or(MALFORMED_DNS_RESPONSE)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(pos).ok_or(MALFORMED_DNS_RESPONSE)?;
+        let header = response.get(pos..pos + 10).ok_or(MALFORMED_DNS_RESPONSE)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = response
+            .get(pos + 10..pos + 10 + rdlength)
+            .ok_or(MALFORMED_DNS_RESPONSE)?;
+        records.push((record_type, rdata));
+        pos += 10 + rdlength;
+    }
+    Ok(records)
+}
+
+/// Fetches the public IP of this server from a plain-HTTP echo service that responds
+/// with the caller's address as the body (e.g. `http://checkip.amazonaws.com/`).
+async fn fetch_public_ip(url: &str) -> Result<IpAddr, String> {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    let uri: axum::http::Uri = url
+        .parse()
+        .map_err(|e| format!("Invalid echo URL '{}': {}", url, e))?;
+    if uri.scheme_str() != Some("http") {
+        return Err(format!("Echo URL '{}' must use http://", url));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| format!("Echo URL '{}' has no host", url))?
+        .to_string();
+    let port = uri.port_u16().unwrap_or(80);
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let request = axum::http::Request::get(path)
+        .header(axum::http::header::HOST, &host)
+        .body(Body::empty())
+        .map_err(|e| format!("Failed to build echo request: {}", e))?;
+
+    let lookup = async {
+        let stream = tokio::net::TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| format!("Failed to connect to {}: {}", url, e))?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+                .await
+                .map_err(|e| format!("Failed to connect to {}: {}", url, e))?;
+        tokio::spawn(connection);
+
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| format!("Echo request to {} failed: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "Echo service {} returned {}",
+                url,
+                response.status()
+            ));
+        }
+        axum::body::to_bytes(Body::new(response.into_body()), 1024)
+            .await
+            .map_err(|e| format!("Failed to read echo response: {}", e))
+    };
+
+    let body = tokio::time::timeout(TIMEOUT, lookup)
+        .await
+        .map_err(|_| format!("Echo request to {} timed out", url))??;
+
+    let text = String::from_utf8_lossy(&body);
+    text.trim().parse().map_err(|_| {
+        format!(
+            "Echo service {} returned an invalid IP: {}",
+            url,
+            text.trim()
+        )
+    })
+}
+
+/// Reports that the server is up, for load balancer health checks.
+async fn health_handler() -> UpdateResponse {
+    UpdateResponse {
+        success: true,
+        message: "OK".to_string(),
+        details: None,
+        record_type: None,
+    }
+}
+
+/// Renders Prometheus metrics for every configured domain.
+async fn metrics_handler(State(state): State<AppState>) -> Response {
+    let now = Instant::now();
+    let mut body = String::from(
+        "# HELP ddns_domain_update_rate Updates received for the domain in the last minute.\n\
+         # TYPE ddns_domain_update_rate gauge\n",
+    );
+    for domain in &state.config.domains {
+        body.push_str(&format!(
+            "ddns_domain_update_rate{{domain=\"{}\"}} {}\n",
+            domain.name,
+            state.update_rates.rate(&domain.name, now)
+        ));
+    }
+
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        body,
+    )
+        .into_response()
+}
+
+/// A problem found in a config, as listed by `/validate`.
+#[derive(Debug, Serialize)]
+struct ValidationProblem {
+    /// The offending setting, e.g. `domains[1].key`. Absent when the config can't be parsed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ValidateResponse {
+    valid: bool,
+    problems: Vec<ValidationProblem>,
+}
+
+/// Checks a candidate config sent as TOML and lists every problem in it, without
+/// touching the running config. Like `/diagnostics`, it requires `diagnostics_token`.
+///
+/// Only files the running config already uses are read: a candidate naming any other
+/// `keys_file`, `unbound_config_path` or `config_path` gets those paths reported instead.
+async fn validate_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    if !diagnostics_authorized(&state.config, &headers) {
+        warn!(
+            reason = "invalid diagnostics token",
+            "Validate request failed"
+        );
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    let problems = match Config::parse_inline(&body) {
+        Ok(candidate) => validate_candidate(&state.config, candidate),
+        Err(message) => vec![ValidationProblem {
+            field: None,
+            message,
+        }],
+    };
+    axum::Json(ValidateResponse {
+        valid: problems.is_empty(),
+        problems,
+    })
+    .into_response()
+}
+
+/// Lists the problems in a candidate config for `/validate`, refusing to read any file
+/// the running config doesn't use.
+fn validate_candidate(running: &Config, mut candidate: Config) -> Vec<ValidationProblem> {
+    let mut problems = Vec::new();
+    let mut unknown_path = |field: String, path: &Path| {
+        problems.push(ValidationProblem {
+            field: Some(field),
+            message: format!(
+                "{:?} is not used by the running config, so /validate won't read it",
+                path
+            ),
+        })
+    };
+
+    if let Some(keys_file) = &candidate.keys_file
+        && running.keys_file.as_ref() != Some(keys_file)
+    {
+        unknown_path("keys_file".to_string(), keys_file);
+    }
+    let running_paths = running.unbound_paths();
+    for (idx, domain) in candidate.domains.iter().enumerate() {
+        let path = candidate.unbound_path_for(domain);
+        if !running_paths.contains(&path) {
+            let field = if domain.config_path.is_some() {
+                format!("domains[{}].config_path", idx)
+            } else {
+                "unbound_config_path".to_string()
+            };
+            unknown_path(field, path);
+        }
+    }
+    problems.dedup_by(|a, b| a.field == b.field && a.message == b.message);
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    if let Some(keys_file) = candidate.keys_file.clone()
+        && let Err(message) = candidate.merge_keys_file(&keys_file)
+    {
+        return vec![ValidationProblem {
+            field: Some("keys_file".to_string()),
+            message,
+        }];
+    }
+    candidate.problems()
+}
+
+#[derive(Debug, Serialize)]
+struct ConfigInfoResponse {
+    unbound_config: FileInfo,
+    domain_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct FileInfo {
+    path: PathBuf,
+    /// Last modification time in seconds since the Unix epoch.
+    modified: u64,
+    size: u64,
+}
+
+/// Reports when the Unbound config was last modified and how large it is, so external
+/// edits can be spotted. Requires `diagnostics_token`.
+async fn config_info_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = &state.config;
+    if !diagnostics_authorized(config, &headers) {
+        warn!(
+            reason = "invalid diagnostics token",
+            "Config info request failed"
+        );
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    let path = &config.unbound_config_path;
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!(path = ?path, error = %e, "Failed to read Unbound config metadata");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                UpdateResponse {
+                    success: false,
+                    message: client_error_message(
+                        config,
+                        format!("Failed to read Unbound config metadata: {}", e),
+                    ),
+                    details: None,
+                    record_type: None,
+                },
+            )
+                .into_response();
+        }
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+
+    axum::Json(ConfigInfoResponse {
+        unbound_config: FileInfo {
+            path: path.clone(),
+            modified,
+            size: metadata.len(),
+        },
+        domain_count: config.domains.len(),
+    })
+    .into_response()
+}
+
+/// Whether the request carries the configured `diagnostics_token`.
+fn diagnostics_authorized(config: &Config, headers: &HeaderMap) -> bool {
+    match (
+        &config.diagnostics_token,
+        extract_auth_key(headers, config.trim_submitted_key),
+    ) {
+        (Some(expected), Ok(key)) => bool::from(key.as_bytes().ct_eq(expected.as_bytes())),
+        _ => false,
+    }
+}
+
+/// Streams update outcomes as Server-Sent Events, one `update` event with a JSON
+/// [`UpdateEvent`] per `/update` request that reached the write. Events a slow client
+/// falls too far behind on are skipped.
+async fn events_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use tokio::sync::broadcast::error::RecvError;
+
+    if !diagnostics_authorized(&state.config, &headers) {
+        warn!(
+            reason = "invalid diagnostics token",
+            "Events request failed"
+        );
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    let events =
+        futures_util::stream::unfold(state.events.subscribe(), |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        return Some((Event::default().event("update").json_data(event), receiver));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Events subscriber fell behind");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+struct DiagnosticsResponse {
+    unbound_config: FileDiagnostics,
+    unbound_control: ControlDiagnostics,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unbound_version: Option<VersionDiagnostics>,
+    domain_count: usize,
+    domains: Vec<DomainDiagnostics>,
+}
+
+#[derive(Debug, Serialize)]
+struct FileDiagnostics {
+    path: PathBuf,
+    readable: bool,
+    writable: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ControlDiagnostics {
+    reachable: bool,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct VersionDiagnostics {
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DomainDiagnostics {
+    name: String,
+    has_entry: bool,
+}
+
+/// Reports the checks most useful for troubleshooting a deployment in a single response.
+async fn diagnostics_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let config = &state.config;
+    if !diagnostics_authorized(config, &headers) {
+        warn!(
+            reason = "invalid diagnostics token",
+            "Diagnostics request failed"
+        );
+        return unauthorized(UNAUTHORIZED_ERROR.to_string());
+    }
+
+    let path = &config.unbound_config_path;
+    let content = fs::read_to_string(path).ok();
+    let writable = fs::OpenOptions::new().append(true).open(path).is_ok();
+    let unbound_control = match unbound_control_status() {
+        Ok(()) => ControlDiagnostics {
+            reachable: true,
+            message: "unbound-control status succeeded".to_string(),
+        },
+        Err(e) => ControlDiagnostics {
+            reachable: false,
+            message: e,
+        },
+    };
+    let format = config.entry_format();
+    let domains = config
+        .domains
+        .iter()
+        .map(|d| {
+            let domain_content = match &d.config_path {
+                Some(domain_path) => fs::read_to_string(domain_path).ok(),
+                None => content.clone(),
+            };
+            DomainDiagnostics {
+                name: d.name.clone(),
+                has_entry: domain_content
+                    .as_deref()
+                    .is_some_and(|c| format.exists_in(c, &d.name)),
+            }
+        })
+        .collect();
+
+    axum::Json(DiagnosticsResponse {
+        unbound_config: FileDiagnostics {
+            path: path.clone(),
+            readable: content.is_some(),
+            writable,
+        },
+        unbound_control,
+        unbound_version: config.diagnostics_unbound_version.then(|| {
+            match (state.version_probe)() {
+                Ok(version) => VersionDiagnostics {
+                    version: Some(version),
+                    error: None,
+                },
+                Err(e) => VersionDiagnostics {
+                    version: None,
+                    error: Some(e),
+                },
+            }
+        }),
+        domain_count: config.domains.len(),
+        domains,
+    })
+    .into_response()
+}
+
+/// Escapes text for inclusion in HTML.
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders a self-contained HTML page with the service status and the current records
+/// of every configured domain.
+async fn status_page_handler(State(state): State<AppState>) -> Response {
+    let config = &state.config;
+    let mut contents = HashMap::new();
+    let mut status = "OK".to_string();
+    for unbound_path in config.unbound_paths() {
+        match fs::read_to_string(unbound_path) {
+            Ok(content) => {
+                contents.insert(unbound_path, content);
+            }
+            Err(e) if status == "OK" => status = format!("Cannot read Unbound config: {}", e),
+            Err(_) => {}
+        }
+    }
+
+    let mut rows = String::new();
+    for domain in &config.domains {
+        let records = contents
+            .get(config.unbound_path_for(domain))
+            .map(|c| parse_records(c, &domain.name))
+            .unwrap_or_default();
+        let values: Vec<&str> = records.iter().map(|r| r.value.as_str()).collect();
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&domain.name),
+            html_escape(&values.join(", "))
+        ));
+    }
+
+    axum::response::Html(format!(
+        "<!DOCTYPE html>\n\
+         <html><head><meta charset=\"utf-8\"><title>unbound_ddns status</title></head>\n\
+         <body>\n<h1>unbound_ddns</h1>\n<p>Status: {}</p>\n\
+         <table>\n<tr><th>Domain</th><th>IP</th></tr>\n{}</table>\n</body></html>\n",
+        html_escape(&status),
+        rows
+    ))
+    .into_response()
+}
+
+/// Changes only the fields given in the body (`ip`, `ttl`) of a domain's A record,
+/// keeping the rest of the current entry as it is in the Unbound config.
+async fn patch_domain_handler(
+    State(state): State<AppState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let config = &state.config;
+    let domain = config.normalize_request_domain(&name);
+    let fail = |status: StatusCode, message: String| {
+        (
+            status,
+            UpdateResponse {
+                success: false,
+                message,
+                details: None,
+                record_type: None,
+            },
+        )
+            .into_response()
+    };
+
+    let auth_key = match extract_auth_key(&headers, config.trim_submitted_key) {
+        Ok(key) => key,
+        Err(e) => {
+            warn!(domain = %domain, reason = %e, "Patch request failed");
+            return unauthorized(e);
+        }
+    };
+    let patch: PatchRequest = match serde_json::from_slice(&body) {
+        Ok(patch) => patch,
+        Err(e) => {
+            warn!(domain = %domain, reason = "parse error", error = %e, "Patch request failed");
+            return fail(
+                StatusCode::BAD_REQUEST,
+                format!("Failed to parse request: Invalid JSON: {}", e),
+            );
+        }
+    };
+    if patch.ip.is_none() && patch.ttl.is_none() {
+        return fail(
+            StatusCode::BAD_REQUEST,
+            "Nothing to update: set ip and/or ttl".to_string(),
+        );
+    }
+    if config.domain_blocked(&domain) {
+        warn!(domain = %domain, reason = "domain blocked", "Patch request failed");
+        return fail(StatusCode::FORBIDDEN, DOMAIN_BLOCKED_ERROR.to_string());
+    }
+    let domain_config = match authorize_domain(&state, &domain, &auth_key) {
+        Ok(domain_config) => domain_config,
+        Err(reason) => {
+            warn!(domain = %domain, reason = reason, "Patch request failed");
+            return unauthorized(UNAUTHORIZED_ERROR.to_string());
+        }
+    };
+
+    // Reading, merging and writing back must not interleave with another update
+    let Some(_domain_guards) = lock_domains(&state, &[&domain_config.name]).await else {
+        warn!(domain = %domain, reason = "domain locked", "Patch request failed");
+        return fail(StatusCode::CONFLICT, DOMAIN_LOCKED_ERROR.to_string());
+    };
+
+    let content = match fs::read_to_string(config.unbound_path_for(domain_config)) {
+        Ok(content) => content,
+        Err(e) => {
+            error!(domain = %domain, error = %e, "Failed to read Unbound config");
+            return fail(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                client_error_message(config, format!("Failed to read Unbound config: {}", e)),
+            );
+        }
+    };
+    // A new address patches the records of its own family; a TTL alone patches the A records
+    let record_type = patch.ip.as_deref().map_or("A", record_type_for);
+    let current: Vec<Record> = parse_records(&content, &domain)
+        .into_iter()
+        .filter(|record| record.record_type == record_type)
+        .collect();
+    let Some(first) = current.first() else {
+        return fail(
+            StatusCode::NOT_FOUND,
+            format!("No {} record for {} to patch", record_type, domain),
+        );
+    };
+
+    // Merge: the new IP replaces the primary address, any backup address stays
+    let (ip, ip_source) = match patch.ip {
+        Some(ip) => (config.record_ip(ip), IpSource::Explicit),
+        None => (first.value.clone(), IpSource::Kept),
+    };
+    let backup_ip = current
+        .get(1)
+        .map(|record| record.value.as_str())
+        .filter(|backup| *backup != ip);
+
+    // Only a TTL sent by the client is held to `max_request_ttl`; the current one is kept
+    let changed = match apply_update(&state, domain_config, &ip, backup_ip, patch.ttl, first.ttl) {
+        Ok(changed) => changed,
+        Err((status, message)) => {
+            return rejected_update(&state, &[domain_config], status, message);
+        }
+    };
+    finish_update(
+        &state,
+        &[domain_config],
+        ResponseDetails { domain, ip_source },
+        &ip,
+        backup_ip,
+        changed,
+        &auth_key,
+    )
+    .await
+}
+
+/// Parses every `local-data` record for `domain` (of any record type) from the config content.
+///
+/// # Arguments
+/// * `content` - The Unbound config file content
+/// * `domain` - The normalized domain name (without trailing dot)
+///
+/// # Returns
+/// The records in the order they appear in the file
+fn parse_records(content: &str, domain: &str) -> Vec<Record> {
+    let pattern = format!(
+        r#"(?m)^[ \t]*local-data:\s*"{}\.?\s+(?:(\d+)\s+)?IN\s+([A-Za-z0-9]+)\s+([^"]+)""#,
+        regex::escape(domain)
+    );
+    let Ok(re) = Regex::new(&pattern) else {
+        return Vec::new();
+    };
+
+    re.captures_iter(content)
+        .map(|caps| Record {
+            record_type: caps[2].to_uppercase(),
+            value: caps[3].trim().to_string(),
+            ttl: caps.get(1).and_then(|t| t.as_str().parse().ok()),
+        })
+        .collect()
+}
+
+fn domain_exists_in_config(content: &str, domain: &str) -> bool {
+    // Match domain with or without trailing dot (\.? makes the dot optional)
+    // An optional numeric TTL may sit between the name and the class
+    // Either an A or an AAAA entry counts
+    // Only entries at the start of a line count, so commented-out entries are ignored
+    let pattern = format!(
+        r#"(?m)^[ \t]*local-data:\s*"{}\.?\s+(?:\d+\s+)?IN\s+(?:A|AAAA)\s+"#,
+        regex::escape(domain)
+    );
+    if let Ok(re) = Regex::new(&pattern) {
+        re.is_match(content)
+    } else {
+        false
+    }
+}
+
+/// Returns true if a `local-zone` entry in `content` is `domain` itself or one of its
+/// parent zones. Commented-out entries are ignored.
+fn has_covering_local_zone(content: &str, domain: &str) -> bool {
+    let re = Regex::new(r#"(?m)^[ \t]*local-zone:[ \t]*"?([^"\s]+)"?"#).unwrap();
+    let domain = domain.to_ascii_lowercase();
+    re.captures_iter(content).any(|caps| {
+        let zone = caps[1].trim_end_matches('.').to_ascii_lowercase();
+        zone.is_empty() || domain == zone || domain.ends_with(&format!(".{}", zone))
+    })
+}
+
+/// Returns the line ending used by most lines of `content`, so that lines we add
+/// match a file that was edited on Windows. Defaults to `\n`.
+fn line_ending(content: &str) -> &'static str {
+    let crlf = content.matches("\r\n").count();
+    let lf = content.matches('\n').count() - crlf;
+    if crlf > lf { "\r\n" } else { "\n" }
+}
+
+/// Renders a `local-data` entry for an A record, or an AAAA record for an IPv6 address.
+///
+/// The domain is written as a FQDN (with trailing dot). A TTL of `Some(0)` is written
+/// explicitly, while `None` leaves the TTL out so Unbound applies its default.
+fn format_local_data(domain: &str, ip: &str, ttl: Option<u32>) -> String {
+    let record_type = record_type_for(ip);
+    match ttl {
+        Some(ttl) => format!(
+            "local-data: \"{}. {} IN {} {}\"",
+            domain, ttl, record_type, ip
+        ),
+        None => format!("local-data: \"{}. IN {} {}\"", domain, record_type, ip),
+    }
+}
+
+/// Renders a `local-data` entry like `format_local_data`, without the trailing dot on
+/// the name.
+fn format_local_data_bare(domain: &str, ip: &str, ttl: Option<u32>) -> String {
+    let record_type = record_type_for(ip);
+    match ttl {
+        Some(ttl) => format!(
+            "local-data: \"{} {} IN {} {}\"",
+            domain, ttl, record_type, ip
+        ),
+        None => format!("local-data: \"{} IN {} {}\"", domain, record_type, ip),
+    }
+}
+
+/// How entries are rendered into and recognized in the Unbound config.
+///
+/// By default entries use the built-in `local-data: "name [ttl] IN A ip"` format (`AAAA`
+/// for IPv6 addresses). A custom `template` may use the `{domain}`, `{ttl}`, `{class}`,
+/// `{type}` and `{value}` placeholders, where `{domain}` has no trailing dot and `{ttl}` is
+/// empty when no TTL is configured. A custom `match_regex` must match a whole entry, with
+/// `{domain}` standing in for the escaped domain name and `{type}` for the record type.
+#[derive(Debug, Clone, Copy, Default)]
+struct EntryFormat<'a> {
+    template: Option<&'a str>,
+    match_regex: Option<&'a str>,
+    /// Write `name.` rather than `name` in the built-in format.
+    trailing_dot: bool,
+    /// Add new entries after the last entry of their record type, of any domain.
+    group_by_type: bool,
+}
+
+impl EntryFormat<'_> {
+    fn render(&self, domain: &str, ip: &str, ttl: Option<u32>) -> String {
+        match self.template {
+            Some(template) => template
+                .replace("{domain}", domain)
+                .replace("{ttl}", &ttl.map(|t| t.to_string()).unwrap_or_default())
+                .replace("{class}", "IN")
+                .replace("{type}", record_type_for(ip))
+                .replace("{value}", ip),
+            None if self.trailing_dot => format_local_data(domain, ip, ttl),
+            None => format_local_data_bare(domain, ip, ttl),
+        }
+    }
+
+    /// Builds a regex matching the entry for `domain` at the start of a line, skipping
+    /// commented-out entries. Only entries of `record_type` match, or both A and AAAA
+    /// entries when it is `None`. The leading indentation is captured so it can be
+    /// preserved, and the line ending following the entry is captured as `eol`.
+    fn entry_regex(&self, domain: &str, record_type: Option<&str>) -> Result<Regex, String> {
+        self.regex_for(&regex::escape(domain), record_type)
+    }
+
+    /// Matches the entries of `record_type` of every domain, for `group_by_type`.
+    fn type_regex(&self, record_type: &str) -> Result<Regex, String> {
+        self.regex_for(r#"[^\s"']+?"#, Some(record_type))
+    }
+
+    fn regex_for(&self, domain_pattern: &str, record_type: Option<&str>) -> Result<Regex, String> {
+        let record_type = record_type.unwrap_or("(?:A|AAAA)");
+        let entry = match self.match_regex {
+            Some(pattern) => pattern
+                .replace("{domain}", domain_pattern)
+                .replace("{type}", record_type),
+            // With or without trailing dot and with or without a TTL
+            None => format!(
+                r#"local-data:\s*"{}\.?\s+(?:\d+\s+)?IN\s+{}\s+[^"]+""#,
+                domain_pattern, record_type
+            ),
+        };
+        Regex::new(&format!(r"(?m)^([ \t]*)(?:{})(?P<eol>[ \t]*\r?\n)?", entry))
+            .map_err(|e| format!("Failed to compile regex: {}", e))
+    }
+
+    fn exists_in(&self, content: &str, domain: &str) -> bool {
+        match self.match_regex {
+            Some(_) => self
+                .entry_regex(domain, None)
+                .is_ok_and(|re| re.is_match(content)),
+            None => domain_exists_in_config(content, domain),
+        }
+    }
+}
+
+/// Where the text of an Unbound config lives. A `PathBuf` is the file at that path.
+trait ConfigStore {
+    fn read(&self) -> Result<String, String>;
+    fn write(&self, content: &str) -> Result<(), String>;
+}
+
+impl ConfigStore for PathBuf {
+    fn read(&self) -> Result<String, String> {
+        fs::read_to_string(self).map_err(|e| format!("Failed to read Unbound config: {}", e))
+    }
+
+    /// Replaces the file atomically: the content is written to a temporary file next to
+    /// it, which is then renamed over the original, so a crash or full disk never leaves
+    /// a half-written config behind. The temporary file is created with the original's
+    /// permissions, so it is never readable by more users than the config, and gets its
+    /// ownership where this process may set it. The directory must be writable.
+    ///
+    /// Fails with [`CONFIG_LOCKED`] instead of writing while another process holds a lock
+    /// on the file.
+    fn write(&self, content: &str) -> Result<(), String> {
+        use fs2::FileExt;
+        use std::io::Write;
+
+        let write_error = |e: std::io::Error| {
+            if e.kind() == std::io::ErrorKind::StorageFull {
+                DISK_FULL.to_string()
+            } else {
+                format!("Failed to write Unbound config: {}", e)
+            }
+        };
+
+        // Replace the file a symlink points to, not the link itself
+        let target = fs::canonicalize(self).unwrap_or_else(|_| self.clone());
+        let original = match fs::File::open(&target) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(write_error(e)),
+        };
+        if let Some(original) = &original {
+            original.try_lock_exclusive().map_err(|e| {
+                if is_lock_contention(&e) {
+                    CONFIG_LOCKED.to_string()
+                } else {
+                    write_error(e)
+                }
+            })?;
+        }
+
+        let temp = temp_path_for(&target);
+        let dir = target
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let metadata = original.as_ref().map(fs::File::metadata).transpose();
+        let metadata = metadata.map_err(write_error)?;
+        let mut created = false;
+        let mut replace = || -> std::io::Result<()> {
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            if let Some(metadata) = &metadata {
+                use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+                options.mode(metadata.permissions().mode() & 0o7777);
+            }
+            let mut file = options.open(&temp).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::PermissionDenied {
+                    std::io::Error::new(
+                        e.kind(),
+                        format!(
+                            "cannot create a temporary file in {:?}, the directory holding the Unbound config must be writable: {}",
+                            dir, e
+                        ),
+                    )
+                } else {
+                    e
+                }
+            })?;
+            created = true;
+            file.write_all(content.as_bytes())?;
+            if let Some(metadata) = &metadata {
+                // The umask may have cleared bits of the mode asked for above
+                file.set_permissions(metadata.permissions())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    // Only a privileged process may hand the file to another owner
+                    if let Err(e) =
+                        std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid()))
+                    {
+                        warn!(path = ?target, error = %e, "Failed to keep the Unbound config's owner");
+                    }
+                }
+            }
+            file.sync_all()?;
+            fs::rename(&temp, &target)?;
+            // Make the rename itself durable. The new content is in place either way.
+            #[cfg(unix)]
+            if let Err(e) = fs::File::open(dir).and_then(|dir| dir.sync_all()) {
+                warn!(path = ?dir, error = %e, "Failed to sync the Unbound config's directory");
+            }
+            Ok(())
+        };
+        replace().map_err(|e| {
+            if created {
+                let _ = fs::remove_file(&temp);
+            }
+            write_error(e)
+        })
+    }
+}
+
+/// A temporary file name in the same directory as `path`, so renaming it over `path`
+/// stays on one filesystem. Unique within this process.
+fn temp_path_for(path: &Path) -> PathBuf {
+    static NEXT_TEMP_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let name = path
+        .file_name()
+        .map_or_else(|| "unbound.conf".into(), |name| name.to_string_lossy());
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        name,
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    ))
+}
+
+/// Whether `e` means a file is locked by someone else, which passes once they're done,
+/// as opposed to failures like missing permissions that need fixing.
+fn is_lock_contention(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::WouldBlock
+        || e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// One named `view:` clause of an Unbound config, read and written in place within the
+/// rest of the file.
+struct SectionStore<'a> {
+    inner: &'a dyn ConfigStore,
+    view: &'a str,
+    full: std::cell::RefCell<String>,
+}
+
+impl<'a> SectionStore<'a> {
+    fn new(inner: &'a dyn ConfigStore, view: &'a str) -> Self {
+        SectionStore {
+            inner,
+            view,
+            full: std::cell::RefCell::default(),
+        }
+    }
+
+    fn range(&self, content: &str) -> Result<std::ops::Range<usize>, String> {
+        view_range(content, self.view)
+            .ok_or_else(|| format!("View '{}' not found in Unbound config", self.view))
+    }
+}
+
+impl ConfigStore for SectionStore<'_> {
+    fn read(&self) -> Result<String, String> {
+        let full = self.inner.read()?;
+        let section = full[self.range(&full)?].to_string();
+        *self.full.borrow_mut() = full;
+        Ok(section)
+    }
+
+    fn write(&self, content: &str) -> Result<(), String> {
+        let mut full = self.full.borrow().clone();
+        let range = self.range(&full)?;
+        full.replace_range(range, content);
+        self.inner.write(&full)
+    }
+}
+
+/// Finds the `view:` clause whose `name` is `view`, from its header line up to the next
+/// clause header (a line holding only `word:`) or the end of the file.
+fn view_range(content: &str, view: &str) -> Option<std::ops::Range<usize>> {
+    let header = Regex::new(r"(?m)^[ \t]*([A-Za-z][A-Za-z0-9-]*):[ \t]*(?:#.*)?\r?$").unwrap();
+    let name = Regex::new(&format!(
+        r#"(?m)^[ \t]*name:[ \t]*"?{}"?[ \t]*(?:#.*)?\r?$"#,
+        regex::escape(view)
+    ))
+    .unwrap();
+
+    let headers: Vec<(usize, bool)> = header
+        .captures_iter(content)
+        .map(|caps| (caps.get(0).unwrap().start(), &caps[1] == "view"))
+        .collect();
+    headers
+        .iter()
+        .enumerate()
+        .find_map(|(i, &(start, is_view))| {
+            let end = headers.get(i + 1).map_or(content.len(), |&(next, _)| next);
+            (is_view && name.is_match(&content[start..end])).then_some(start..end)
+        })
+}
+
+/// Writes through to `inner` with the entries of the configured domains put in a
+/// canonical order, for `sort_managed_entries`.
+struct SortedStore<'a> {
+    inner: &'a dyn ConfigStore,
+    domains: Vec<&'a str>,
+}
+
+impl<'a> SortedStore<'a> {
+    fn new(inner: &'a dyn ConfigStore, config: &'a Config) -> Self {
+        SortedStore {
+            inner,
+            domains: config.domains.iter().map(|d| d.name.as_str()).collect(),
+        }
+    }
+}
+
+impl ConfigStore for SortedStore<'_> {
+    fn read(&self) -> Result<String, String> {
+        self.inner.read()
+    }
+
+    fn write(&self, content: &str) -> Result<(), String> {
+        self.inner
+            .write(&sort_managed_entries(content, &self.domains))
+    }
+}
+
+/// Reorders the `local-data` lines of `domains` by domain, record type and value. The
+/// sorted entries take the places the managed entries held, so every other line, and
+/// the line endings, stay where they are.
+fn sort_managed_entries(content: &str, domains: &[&str]) -> String {
+    let re = Regex::new(
+        r#"^[ \t]*local-data:\s*"([^\s"]+?)\.?\s+(?:\d+\s+)?IN\s+([A-Za-z0-9]+)\s+([^"]+)""#,
+    )
+    .expect("valid entry pattern");
+
+    let mut lines: Vec<String> = content.split_inclusive('\n').map(String::from).collect();
+    let mut slots = Vec::new();
+    let mut entries = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let text = line.trim_end_matches(['\r', '\n']);
+        if let Some(caps) = re.captures(text)
+            && domains.iter().any(|d| d.eq_ignore_ascii_case(&caps[1]))
+        {
+            let key = (
+                caps[1].to_ascii_lowercase(),
+                caps[2].to_ascii_uppercase(),
+                caps[3].trim().to_string(),
+            );
+            slots.push(index);
+            entries.push((key, text.to_string()));
+        }
+    }
+    entries.sort();
+
+    for (index, (_, text)) in slots.into_iter().zip(entries) {
+        let eol = lines[index][lines[index].trim_end_matches(['\r', '\n']).len()..].to_string();
+        lines[index] = text + &eol;
+    }
+    lines.concat()
+}
+
+/// Error returned when the Unbound config can't be written because another process
+/// holds a lock on it.
+const CONFIG_LOCKED: &str = "Unbound config is temporarily locked, retry later";
+
+/// Error returned when `verify` is set and the written entries are missing on read-back.
+const READ_BACK_MISMATCH: &str = "Written entries not found when reading the Unbound config back";
+
+/// Error returned when the filesystem holding the Unbound config ran out of space.
+const DISK_FULL: &str = "Not enough disk space to write the Unbound config";
+
+/// Status for a failure to read or write the Unbound config. These are problems on the
+/// server, never with the request, so clients are told to retry rather than give up.
+fn write_failure_status(e: &str) -> StatusCode {
+    if e == DISK_FULL {
+        StatusCode::INSUFFICIENT_STORAGE
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+/// Status and client-facing message for a failed read or write of the Unbound config.
+/// A locked config is a `503` that [`rejected_update`] pairs with `Retry-After`.
+fn write_failure(config: &Config, e: String) -> (StatusCode, String) {
+    if e == CONFIG_LOCKED {
+        return (StatusCode::SERVICE_UNAVAILABLE, e);
+    }
+    (
+        write_failure_status(&e),
+        client_error_message(config, format!("Failed to update configuration: {}", e)),
+    )
+}
+
+/// Replaces the existing `local-data` entries for `domain` with one entry per IP, written
+/// where the first existing entry was. Any further existing entries are removed, while
+/// the lines around them, such as comments, are left untouched. With `verify`, the
+/// config is read back afterwards to confirm the new entries are there.
+///
+/// Only entries of the IPs' record type (A for IPv4, AAAA for IPv6) are replaced, so a
+/// domain can hold both. When the domain only has entries of the other type, the new
+/// entries are added after them, or with `group_by_type` after the last entry of their
+/// own type in the file, if there is one. With `exclusive`, entries of the other type
+/// are removed in the same write instead.
+///
+/// When the entries are already in place, nothing is written.
+///
+/// # Returns
+/// Whether the config content changed
+fn update_unbound_config(
+    store: &(impl ConfigStore + ?Sized),
+    domain: &str,
+    ips: &[&str],
+    ttl: Option<u32>,
+    format: EntryFormat,
+    verify: bool,
+    exclusive: bool,
+) -> Result<bool, String> {
+    // Read the current configuration
+    let content = store.read()?;
+
+    // Check if domain exists in the configuration (domain is already normalized without trailing dot)
+    if !format.exists_in(&content, domain) {
+        return Err(format!(
+            "Domain '{}' not found in Unbound config. Cannot update non-existent domain.",
+            domain
+        ));
+    }
+
+    let record_type = ips.first().map_or("A", |ip| record_type_for(ip));
+
+    // Compare addresses rather than text, so an entry written with different spacing
+    // or without the trailing dot still counts as up to date
+    let current: Vec<(IpAddr, Option<u32>)> = parse_records(&content, domain)
+        .into_iter()
+        .filter(|record| match exclusive {
+            true => matches!(record.record_type.as_str(), "A" | "AAAA"),
+            false => record.record_type == record_type,
+        })
+        .filter_map(|record| Some((record.value.trim().parse().ok()?, record.ttl)))
+        .collect();
+    let wanted: Vec<(IpAddr, Option<u32>)> = ips
+        .iter()
+        .map(|ip| Ok((parse_ip(ip)?, ttl)))
+        .collect::<Result<_, String>>()?;
+    if current == wanted {
+        return Ok(false);
+    }
+
+    let re = format.entry_regex(domain, Some(record_type))?;
+    let newline = line_ending(&content);
+    let render = |indent: &str| -> String {
+        let entries: Vec<String> = ips
+            .iter()
+            .map(|ip| format!("{}{}", indent, format.render(domain, ip, ttl)))
+            .collect();
+        entries.join(newline)
+    };
+
+    let updated_content = if re.is_match(&content) {
+        // Replace the first existing entry and drop the rest, including their line
+        // endings. The first entry's own line ending (LF or CRLF) is kept as is.
+        let mut first = true;
+        re.replace_all(&content, |caps: &regex::Captures| {
+            if !std::mem::take(&mut first) {
+                return String::new();
+            }
+            let eol = caps.name("eol").map_or("", |m| m.as_str());
+            format!("{}{}", render(&caps[1]), eol)
+        })
+        .to_string()
+    } else {
+        // Only the other record type exists: add ours after its last entry, or with
+        // `group_by_type` after the last entry of our type of any domain
+        let grouped = match format.group_by_type {
+            true => format
+                .type_regex(record_type)?
+                .captures_iter(&content)
+                .last(),
+            false => None,
+        };
+        let last = match grouped {
+            Some(last) => last,
+            None => format
+                .entry_regex(domain, None)?
+                .captures_iter(&content)
+                .last()
+                .ok_or_else(|| format!("Domain '{}' not found in Unbound config", domain))?,
+        };
+        let end = last.get(0).unwrap().end();
+        let mut updated = content[..end].to_string();
+        match last.name("eol") {
+            Some(_) => {
+                updated.push_str(&render(&last[1]));
+                updated.push_str(newline);
+            }
+            None => {
+                updated.push_str(newline);
+                updated.push_str(&render(&last[1]));
+            }
+        }
+        updated.push_str(&content[end..]);
+        updated
+    };
+    let updated_content = if exclusive {
+        let other = if record_type == "A" { "AAAA" } else { "A" };
+        format
+            .entry_regex(domain, Some(other))?
+            .replace_all(&updated_content, "")
+            .to_string()
+    } else {
+        updated_content
+    };
+    if updated_content == content {
+        // Already up to date: leave the file, and its modification time, alone
+        return Ok(false);
+    }
+
+    // Write the updated configuration
+    store.write(&updated_content)?;
+
+    if verify {
+        let written = store.read()?;
+        if !ips
+            .iter()
+            .all(|ip| written.contains(&format.render(domain, ip, ttl)))
+        {
+            return Err(READ_BACK_MISMATCH.to_string());
+        }
+    }
+
+    Ok(true)
+}
+
+/// Returns the space available to this process on the filesystem holding `path`.
+fn available_space(path: &Path) -> Result<u64, String> {
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    fs2::available_space(dir).map_err(|e| format!("Failed to check free disk space: {}", e))
+}
+
+/// Checks that `available` bytes meets the configured `min_free_bytes` threshold.
+fn check_free_space(available: u64, min_free_bytes: u64) -> Result<(), String> {
+    if available < min_free_bytes {
+        return Err(format!(
+            "Insufficient disk space: {} bytes free, {} required",
+            available, min_free_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Placeholder address written for domains bootstrapped by `--init-unbound`.
+const INIT_PLACEHOLDER_IP: &str = "0.0.0.0";
+
+/// Appends a `local-data` stub for every configured domain missing from the Unbound config,
+/// creating the file if needed, so that startup validation passes on a fresh install.
+///
+/// The stubs are written under their own `server:` clause, which Unbound merges with any
+/// existing one.
+///
+/// # Returns
+/// The names of the domains that were added
+fn init_unbound_config(
+    unbound_path: &Path,
+    domains: &[DomainConfig],
+    format: EntryFormat,
+) -> Result<Vec<String>, String> {
+    let content = match fs::read_to_string(unbound_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read Unbound config: {}", e)),
+    };
+
+    let missing: Vec<&DomainConfig> = domains
+        .iter()
+        .filter(|d| !format.exists_in(&content, &d.name))
+        .collect();
+    if missing.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let newline = line_ending(&content);
+    let mut updated_content = content;
+    if !updated_content.is_empty() && !updated_content.ends_with('\n') {
+        updated_content.push_str(newline);
+    }
+    updated_content.push_str("server:");
+    updated_content.push_str(newline);
+    for domain in &missing {
+        updated_content.push_str("  ");
+        updated_content.push_str(&format.render(&domain.name, INIT_PLACEHOLDER_IP, domain.ttl));
+        updated_content.push_str(newline);
+    }
+
+    unbound_path.to_path_buf().write(&updated_content)?;
+
+    Ok(missing.iter().map(|d| d.name.clone()).collect())
+}
+
+/// Reloads Unbound unless reloading has been disabled in the config.
+fn reload_if_enabled(state: &AppState, command: Option<&[String]>) -> Result<Reload, String> {
+    if !state.config.reload_enabled {
+        return Ok(Reload::Done);
+    }
+    reload(state, command)
+}
+
+/// What became of a reload request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reload {
+    /// Unbound was reloaded, or reloading is left to something else
+    Done,
+    /// Postponed by `min_reload_interval_secs`, so Unbound still serves the old records
+    Deferred,
+}
+
+/// Suffix for the message of an update whose reload was deferred.
+const RELOAD_PENDING: &str = " (reload pending)";
+
+/// Waits `post_reload_delay_ms` after a reload, so the new record is being served
+/// by the time the client gets its response.
+async fn post_reload_delay(state: &AppState) {
+    if state.config.reload_enabled && state.config.post_reload_delay_ms > 0 {
+        (state.sleep)(Duration::from_millis(state.config.post_reload_delay_ms)).await;
+    }
+}
+
+/// Reloads Unbound, or schedules the reload for later when the previous one happened
+/// less than `min_reload_interval_secs` ago. Deferred reloads only log their errors.
+fn reload(state: &AppState, command: Option<&[String]>) -> Result<Reload, String> {
+    let Some(secs) = state.config.min_reload_interval_secs else {
+        return reload_now(state, command).map(|_| Reload::Done);
+    };
+    let interval = Duration::from_secs(secs);
+    let now = Instant::now();
+    let mut throttle = state.reload_throttle.lock().unwrap();
+    let Some(due) = throttle
+        .last
+        .map(|last| last + interval)
+        .filter(|due| now < *due)
+    else {
+        throttle.last = Some(now);
+        drop(throttle);
+        return reload_now(state, command).map(|_| Reload::Done);
+    };
+
+    let command = command.map(<[String]>::to_vec);
+    if throttle.pending.contains(&command) {
+        return Ok(Reload::Deferred);
+    }
+    throttle.pending.push(command);
+    if throttle.pending.len() == 1 {
+        debug!(
+            delay_ms = (due - now).as_millis() as u64,
+            "Deferring Unbound reload"
+        );
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(due.into()).await;
+            let pending = {
+                let mut throttle = state.reload_throttle.lock().unwrap();
+                throttle.last = Some(Instant::now());
+                std::mem::take(&mut throttle.pending)
+            };
+            for command in pending {
+                if let Err(e) = reload_now(&state, command.as_deref()) {
+                    error!(error = %e, "Failed to reload Unbound");
+                }
+            }
+        });
+    }
+    Ok(Reload::Deferred)
+}
+
+/// Runs the reloader, treating a missing `unbound-control` as a skipped reload when
+/// `ignore_missing_unbound_control` is set. Failures are remembered until the same
+/// command succeeds, see [`reload_outstanding`].
+fn reload_now(state: &AppState, command: Option<&[String]>) -> Result<(), String> {
+    let result = match (state.reloader)(command) {
+        Err(e) if e == UNBOUND_CONTROL_NOT_FOUND && state.config.ignore_missing_unbound_control => {
+            warn!(error = %e, "Skipping Unbound reload");
+            Ok(())
+        }
+        result => result,
+    };
+    let command = command.map(<[String]>::to_vec);
+    let failed = &mut state.reload_throttle.lock().unwrap().failed;
+    match &result {
+        Ok(()) => failed.retain(|c| *c != command),
+        Err(_) if !failed.contains(&command) => failed.push(command),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Whether the last reload with `command` failed, so Unbound may not serve what is on
+/// disk. Writes that change nothing still reload then, letting a client retry recover.
+fn reload_outstanding(state: &AppState, command: Option<&[String]>) -> bool {
+    let command = command.map(<[String]>::to_vec);
+    state
+        .reload_throttle
+        .lock()
+        .unwrap()
+        .failed
+        .contains(&command)
+}
+
+fn reload_unbound(command: Option<&[String]>) -> Result<(), String> {
+    let Some((program, args)) = command.and_then(|command| command.split_first()) else {
+        return run_unbound_control("reload");
+    };
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to execute {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        ))
+    }
+}
+
+/// Checks that Unbound is running and reachable through `unbound-control`.
+fn unbound_control_status() -> Result<(), String> {
+    run_unbound_control("status")
+}
+
+/// Error returned when the `unbound-control` binary cannot be found.
+const UNBOUND_CONTROL_NOT_FOUND: &str = "unbound-control not found on PATH. Install it or add its directory to PATH, or set `reload_enabled = false` if something else reloads Unbound";
+
+fn run_unbound_control(command: &str) -> Result<(), String> {
+    let output = Command::new("unbound-control")
+        .arg(command)
+        .output()
+        .map_err(unbound_control_exec_error)?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(format!("unbound-control failed: {}", stderr))
+    }
+}
+
+/// Runs `unbound -V` and returns the version it reports, e.g. `1.19.0`.
+fn unbound_version() -> Result<String, String> {
+    let output = Command::new("unbound").arg("-V").output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            "unbound not found on PATH".to_string()
+        } else {
+            format!("Failed to execute unbound: {}", e)
+        }
+    })?;
+    parse_unbound_version(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| "unbound -V did not report a version".to_string())
+}
+
+/// Extracts the version from `unbound -V` output, which starts with `Version 1.19.0`.
+fn parse_unbound_version(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("Version ")
+            .map(|version| version.trim().to_string())
+    })
+}
+
+fn unbound_control_exec_error(e: std::io::Error) -> String {
+    if e.kind() == std::io::ErrorKind::NotFound {
+        UNBOUND_CONTROL_NOT_FOUND.to_string()
+    } else {
+        format!("Failed to execute unbound-control: {}", e)
+    }
+}
+
+/// Formats `time` as an Apache log timestamp in UTC, e.g. `10/Oct/2000:13:55:36 +0000`.
+fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = secs / 86_400 + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    let time_of_day = secs % 86_400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    )
+}
+
+/// Header value for the access log, `-` when absent or not valid UTF-8.
+fn request_header(headers: &HeaderMap, name: &str) -> String {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Rejects requests whose headers add up to more than `max_header_bytes`, counting
+/// each header as it appears on the wire (`name: value\r\n`), with a JSON body.
+///
+/// Heads too large for the connection's read buffer never get here: `serve` sizes the
+/// buffer from the same setting, and hyper answers those with a bare 431 as soon as the
+/// buffer fills up.
+async fn limit_header_size(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if let Some(limit) = state.config.max_header_bytes {
+        let size: usize = request
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum();
+        if size > limit {
+            warn!(size, limit, reason = "headers too large", "Request failed");
+            return (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                UpdateResponse {
+                    success: false,
+                    message: "Request headers too large".to_string(),
+                    details: None,
+                    record_type: None,
+                },
+            )
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Answers 503 with a `Retry-After` header until the `startup_delay_secs` warmup has
+/// passed.
+async fn warmup(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    if let Ok(remaining) = state.ready_at.duration_since((state.clock)())
+        && !remaining.is_zero()
+    {
+        debug!(
+            remaining_secs = remaining.as_secs(),
+            "Rejecting request during startup delay"
+        );
+        let retry_after = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(axum::http::header::RETRY_AFTER, retry_after.to_string())],
+            UpdateResponse {
+                success: false,
+                message: "Server is starting up".to_string(),
+                details: None,
+                record_type: None,
+            },
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// The parts of a request that go into its access log line. [`access_log_request`]
+/// moves them onto the response, where the `TraceLayer`'s [`AccessLog`] reads them.
+#[derive(Debug, Clone)]
+struct AccessLogRequest {
+    client: String,
+    method: axum::http::Method,
+    path: String,
+    version: axum::http::Version,
+    referer: String,
+    user_agent: String,
+    time: SystemTime,
+}
+
+/// Records the request for the access log, resolving the client with the `proxy_token`
+/// of the live config.
+async fn access_log_request(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let client = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => {
+            extract_client_ip(request.headers(), addr, state.config.proxy_token.as_deref())
+        }
+        None => "-".to_string(),
+    };
+    let entry = AccessLogRequest {
+        client,
+        method: request.method().clone(),
+        path: request
+            .uri()
+            .path_and_query()
+            .map_or_else(|| request.uri().path().to_string(), |p| p.to_string()),
+        version: request.version(),
+        referer: request_header(request.headers(), "referer"),
+        user_agent: request_header(request.headers(), "user-agent"),
+        time: (state.clock)(),
+    };
+
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(entry);
+    response
+}
+
+/// `TraceLayer` response callback that logs one line per request in the
+/// `access_log_format` of the live config.
+#[derive(Clone)]
+struct AccessLog {
+    config: Arc<ArcSwap<Config>>,
+}
+
+impl<B> tower_http::trace::OnResponse<B> for AccessLog {
+    fn on_response(self, response: &Response<B>, latency: Duration, _span: &tracing::Span) {
+        let Some(request) = response.extensions().get::<AccessLogRequest>() else {
+            return;
+        };
+        let latency_ms = latency.as_millis();
+        let status = response.status().as_u16();
+        let bytes = response
+            .headers()
+            .get(axum::http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("-");
+        let AccessLogRequest {
+            client,
+            method,
+            path,
+            version,
+            referer,
+            user_agent,
+            time,
+        } = request;
+        let request_line = format!("{} {} {:?}", method, path, version);
+
+        match self.config.load().access_log_format {
+            AccessLogFormat::Tracing => {
+                info!(client = %client, method = %method, path = %path, status = status, latency_ms = latency_ms, "Request handled");
+            }
+            AccessLogFormat::Common => {
+                info!(
+                    "{} - - [{}] \"{}\" {} {} {}ms",
+                    client,
+                    format_clf_time(*time),
+                    request_line,
+                    status,
+                    bytes,
+                    latency_ms
+                );
+            }
+            AccessLogFormat::Combined => {
+                info!(
+                    "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {}ms",
+                    client,
+                    format_clf_time(*time),
+                    request_line,
+                    status,
+                    bytes,
+                    referer,
+                    user_agent,
+                    latency_ms
+                );
+            }
+            AccessLogFormat::Json => {
+                let line = serde_json::json!({
+                    "time": format_clf_time(*time),
+                    "client": client,
+                    "method": method.as_str(),
+                    "path": path,
+                    "status": status,
+                    "latency_ms": latency_ms,
+                });
+                info!("{}", line);
+            }
+        }
+    }
+}
+
+/// Builds the public router and, when `admin_listen_addr` is set, a separate admin
+/// router. Both share the same state so metrics reflect updates made on the public one.
+fn create_apps(live_config: Arc<ArcSwap<Config>>) -> (Router, Option<Router>) {
+    let config = live_config.load_full();
+    let separate_admin = config.admin_listen_addr.is_some();
+
+    // Keep proxies from serving stale records from the read endpoints
+    let cache_control = axum::http::HeaderValue::from_str(&config.cache_control)
+        .ok()
+        .filter(|value| !value.is_empty());
+    let no_cache = axum::middleware::map_response(move |mut response: Response| {
+        let cache_control = cache_control.clone();
+        async move {
+            if let Some(value) = cache_control {
+                response
+                    .headers_mut()
+                    .entry(axum::http::header::CACHE_CONTROL)
+                    .or_insert(value);
+            }
+            response
+        }
+    });
+
+    let mut router = Router::new()
+        .route("/update", post(update_handler).get(update_query_handler))
+        .route("/update/stream", post(update_stream_handler))
+        .route("/domains/{name}", patch(patch_domain_handler))
+        .route("/current", get(current_handler).layer(no_cache.clone()))
+        .route("/export", get(export_handler).layer(no_cache.clone()))
+        .route(
+            &config.health_path,
+            get(health_handler).layer(no_cache.clone()),
+        );
+    if config.server_ip_enabled {
+        router = router.route("/server-ip", get(server_ip_handler).layer(no_cache.clone()));
+    }
+
+    let mut admin_router = Router::new();
+    if config.metrics_enabled {
+        admin_router = admin_router.route("/metrics", get(metrics_handler));
+    }
+    if config.diagnostics_token.is_some() {
+        admin_router = admin_router.route(
+            "/diagnostics",
+            get(diagnostics_handler).layer(no_cache.clone()),
+        );
+        admin_router = admin_router.route("/validate", post(validate_handler));
+        admin_router = admin_router.route(
+            "/config-info",
+            get(config_info_handler).layer(no_cache.clone()),
+        );
+    }
+    if config.events_enabled {
+        admin_router = admin_router.route("/events", get(events_handler));
+    }
+    if config.status_page_enabled {
+        admin_router = admin_router.route("/", get(status_page_handler).layer(no_cache));
+    }
+
+    let state = AppState::new(config);
+    if state.config.startup_delay_secs > 0 {
+        router = router.layer(axum::middleware::from_fn_with_state(state.clone(), warmup));
+    }
+    let max_header_bytes = state.config.max_header_bytes;
+    let state = LiveState {
+        state,
+        config: live_config,
+    };
+
+    // The access log follows the live config, so a SIGHUP can change its format
+    let request_layer = axum::middleware::from_fn_with_state(state.clone(), access_log_request);
+    let trace_layer = tower_http::trace::TraceLayer::new_for_http()
+        .on_response(AccessLog {
+            config: state.config.clone(),
+        })
+        .on_failure(());
+    router = router
+        .layer(request_layer.clone())
+        .layer(trace_layer.clone());
+    admin_router = admin_router.layer(request_layer).layer(trace_layer);
+
+    if max_header_bytes.is_some() {
+        let layer = axum::middleware::from_fn_with_state(state.state.clone(), limit_header_size);
+        router = router.layer(layer.clone());
+        admin_router = admin_router.layer(layer);
+    }
+    if separate_admin {
+        (
+            router.with_state(state.clone()),
+            Some(admin_router.with_state(state)),
+        )
+    } else {
+        (router.merge(admin_router).with_state(state), None)
+    }
+}
+
+/// Router state: the shared [`AppState`] plus the config that a SIGHUP can replace.
+/// Handlers extract an `AppState` holding the config current when the request
+/// arrived, so a reload never changes the config under a request in progress.
+#[derive(Clone)]
+struct LiveState {
+    state: AppState,
+    config: Arc<ArcSwap<Config>>,
+}
+
+impl axum::extract::FromRef<LiveState> for AppState {
+    fn from_ref(live: &LiveState) -> Self {
+        AppState {
+            config: live.config.load_full(),
+            ..live.state.clone()
+        }
+    }
+}
+
+/// Resolves `domain_discovery_txt` at startup and then every
+/// `domain_discovery_interval_secs`, both read from the live config. A failed lookup
+/// keeps the domains discovered before.
+async fn discover_domains(config: Arc<ArcSwap<Config>>, resolver: TxtResolver) {
+    loop {
+        if let Err(e) = refresh_discovered_domains(&config, &resolver).await {
+            warn!(error = %e, "Failed to discover domains, keeping the current ones");
+        }
+        let interval = config.load().domain_discovery_interval_secs.max(1);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Looks up `domain_discovery_txt` once and swaps in a config managing the domains its
+/// TXT records list, separated by commas or whitespace. Without `domain_discovery_txt`,
+/// domains discovered earlier are dropped.
+async fn refresh_discovered_domains(
+    config: &ArcSwap<Config>,
+    resolver: &TxtResolver,
+) -> Result<(), String> {
+    let names = match config.load().domain_discovery_txt.clone() {
+        Some(txt_name) => {
+            let texts = resolver(&txt_name).await?;
+            texts
+                .iter()
+                .flat_map(|text| text.split(|c: char| c == ',' || c.is_whitespace()))
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        }
+        None if config.load().discovered_domains().is_empty() => return Ok(()),
+        None => Vec::new(),
+    };
+    let previous = config
+        .rcu(|current| Arc::new(current.with_discovered(&names)))
+        .discovered_domains();
+    let discovered = config.load().discovered_domains();
+    if discovered != previous {
+        info!(domains = ?discovered, "Discovered domains changed");
+    }
+    Ok(())
+}
+
+/// Reloads `path` on every SIGHUP and swaps it in for new requests. A config that fails
+/// to load or validate is logged and the running one is kept.
+///
+/// So is a config changing any setting only read at startup, as listed by
+/// [`Config::restart_only_changes`]; those need a restart.
+#[cfg(unix)]
+async fn reload_config_on_hangup(path: &str, config: Arc<ArcSwap<Config>>) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(error = %e, "Failed to install SIGHUP handler");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match Config::load(path) {
+            Ok(reloaded) => {
+                let restart_only = config.load().restart_only_changes(&reloaded);
+                if !restart_only.is_empty() {
+                    error!(path = %path, settings = ?restart_only, "Changed settings require a restart, keeping the current configuration");
+                    continue;
+                }
+                // Keep the discovered domains until discovery runs again
+                let reloaded = match reloaded.domain_discovery_txt {
+                    Some(_) => reloaded.with_discovered(&config.load().discovered_domains()),
+                    None => reloaded,
+                };
+                print_config_info(&reloaded);
+                config.store(Arc::new(reloaded));
+                info!(path = %path, "Reloaded configuration");
+            }
+            Err(e) => {
+                error!(path = %path, error = %e, "Failed to reload configuration, keeping the current one");
+            }
+        }
+    }
+}
+
+/// Binds a listener for every address, failing if any of them cannot be bound.
+async fn bind_listeners(addrs: &[&str]) -> Result<Vec<tokio::net::TcpListener>, String> {
+    let mut listeners = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Listener that only accepts a connection once it holds a permit from a shared
+/// semaphore. The permit travels with the connection and is released when it closes.
+struct LimitedListener {
+    inner: tokio::net::TcpListener,
+    permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl axum::serve::Listener for LimitedListener {
+    type Io = LimitedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("connection semaphore is never closed");
+        let (stream, addr) = axum::serve::Listener::accept(&mut self.inner).await;
+        (
+            LimitedStream {
+                stream,
+                _permit: permit,
+            },
+            addr,
+        )
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// A client connection holding one of the `max_connections` slots.
+struct LimitedStream {
+    stream: tokio::net::TcpStream,
+    _permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl tokio::io::AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// How long a connection may take to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Signature that starts every PROXY protocol v2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection, consuming
+/// exactly the header bytes so the HTTP request that follows is left untouched.
+///
+/// # Returns
+/// The original client address, or `None` when the header carries no address
+/// (`UNKNOWN` or a v2 `LOCAL` command) and the peer address should be kept
+async fn read_proxy_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, String>
+where
+    S: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    let mut prefix = [0u8; 5];
+    stream
+        .read_exact(&mut prefix)
+        .await
+        .map_err(|e| format!("Failed to read PROXY header: {}", e))?;
+
+    if &prefix == b"PROXY" {
+        // A v1 header is a single CRLF-terminated line of at most 107 bytes
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= 107 {
+                return Err("PROXY v1 header too long".to_string());
+            }
+            line.push(
+                stream
+                    .read_u8()
+                    .await
+                    .map_err(|e| format!("Failed to read PROXY header: {}", e))?,
+            );
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| "PROXY v1 header is not valid text".to_string())?;
+        return parse_proxy_v1(line);
+    }
+
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&prefix);
+    stream
+        .read_exact(&mut header[5..])
+        .await
+        .map_err(|e| format!("Failed to read PROXY header: {}", e))?;
+    if header[..12] != PROXY_V2_SIGNATURE {
+        return Err("Connection did not start with a PROXY header".to_string());
+    }
+    let mut payload = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| format!("Failed to read PROXY header: {}", e))?;
+    parse_proxy_v2(header[12], header[13], &payload)
+}
+
+/// Parses a PROXY protocol v1 line such as `PROXY TCP4 203.0.113.7 192.0.2.1 51234 443`.
+fn parse_proxy_v1(line: &str) -> Result<Option<SocketAddr>, String> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            protocol @ ("TCP4" | "TCP6"),
+            source,
+            _destination,
+            port,
+            _,
+        ] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| format!("Invalid PROXY source address: {}", source))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(format!("PROXY source address {} is not {}", ip, protocol));
+            }
+            let port: u16 = port
+                .parse()
+                .map_err(|_| format!("Invalid PROXY source port: {}", port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("Malformed PROXY v1 header: {}", line)),
+    }
+}
+
+/// Parses the body of a PROXY protocol v2 header following its signature.
+fn parse_proxy_v2(
+    version_command: u8,
+    family: u8,
+    payload: &[u8],
+) -> Result<Option<SocketAddr>, String> {
+    if version_command >> 4 != 2 {
+        return Err("Unsupported PROXY protocol version".to_string());
+    }
+    match version_command & 0x0F {
+        // LOCAL: health checks from the proxy itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err("Unsupported PROXY v2 command".to_string()),
+    }
+    let truncated = || "Truncated PROXY v2 address block".to_string();
+    match family >> 4 {
+        0x1 => {
+            let block = payload.get(..12).ok_or_else(truncated)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        0x2 => {
+            let block = payload.get(..36).ok_or_else(truncated)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(std::net::Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC or AF_UNIX carry no usable client address
+        _ => Ok(None),
+    }
+}
+
+/// Listener that strips the PROXY protocol header from each connection and reports
+/// the client address it carries. Headers are read on per-connection tasks so a
+/// client that never sends one cannot hold up the accept loop.
+struct ProxyProtocolListener {
+    connections: tokio::sync::mpsc::Receiver<(LimitedStream, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ProxyProtocolListener {
+    fn new(mut inner: LimitedListener) -> std::io::Result<Self> {
+        use axum::serve::Listener;
+
+        let local_addr = inner.local_addr()?;
+        let (sender, connections) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                let (mut stream, peer) = inner.accept().await;
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+                        .await
+                    {
+                        Ok(Ok(client)) => {
+                            let _ = sender.send((stream, client.unwrap_or(peer))).await;
+                        }
+                        Ok(Err(e)) => {
+                            warn!(peer = %peer, error = %e, "Dropping connection with invalid PROXY header");
+                        }
+                        Err(_) => {
+                            warn!(peer = %peer, "Dropping connection that sent no PROXY header");
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for ProxyProtocolListener {
+    type Io = LimitedStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept task only stops once this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// The smallest read buffer hyper accepts. Lower `max_header_bytes` limits are only
+/// enforced once the headers are parsed, by `limit_header_size`.
+const MIN_READ_BUFFER_BYTES: usize = 8192;
+
+/// How `serve` treats client connections.
+#[derive(Debug, Clone, Copy)]
+struct ServeOptions {
+    /// At most this many connections are open at once, shared across all listeners.
+    max_connections: Option<usize>,
+    /// Every connection must start with a PROXY protocol header.
+    proxy_protocol: bool,
+    /// How long a connection may take to send a request's headers. The clock starts
+    /// when the connection opens and again after every response, so it also closes
+    /// idle keep-alive connections.
+    header_read_timeout: Duration,
+    /// Caps how many bytes of a request's head (request line and headers) are buffered
+    /// before it is answered with `431`.
+    max_header_bytes: Option<usize>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions {
+            max_connections: None,
+            proxy_protocol: false,
+            header_read_timeout: Duration::from_secs(default_header_read_timeout_secs()),
+            max_header_bytes: None,
+        }
+    }
+}
+
+impl ServeOptions {
+    /// The options for the public listeners.
+    fn public(config: &Config) -> Self {
+        ServeOptions {
+            max_connections: config.max_connections,
+            proxy_protocol: config.proxy_protocol,
+            ..Self::admin(config)
+        }
+    }
+
+    /// The options for the admin listener, which neither limits connections nor expects
+    /// a PROXY header.
+    fn admin(config: &Config) -> Self {
+        ServeOptions {
+            header_read_timeout: Duration::from_secs(config.header_read_timeout_secs),
+            max_header_bytes: config.max_header_bytes,
+            ..Self::default()
+        }
+    }
+}
+
+/// Starts serving the same app on every listener, as configured by `options`.
+///
+/// # Returns
+/// The addresses actually bound, which differ from the configured ones for port 0,
+/// and a future that runs until one of the servers fails
+fn serve(
+    listeners: Vec<tokio::net::TcpListener>,
+    app: Router,
+    options: ServeOptions,
+) -> std::io::Result<(Vec<SocketAddr>, impl Future<Output = std::io::Result<()>>)> {
+    let addrs = listeners
+        .iter()
+        .map(tokio::net::TcpListener::local_addr)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let permits = Arc::new(tokio::sync::Semaphore::new(
+        options
+            .max_connections
+            .unwrap_or(tokio::sync::Semaphore::MAX_PERMITS)
+            .min(tokio::sync::Semaphore::MAX_PERMITS),
+    ));
+
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        let app = app.clone();
+        let listener = LimitedListener {
+            inner: listener,
+            permits: Arc::clone(&permits),
+        };
+        if options.proxy_protocol {
+            let listener = ProxyProtocolListener::new(listener)?;
+            servers.spawn(serve_connections(listener, app, options));
+        } else {
+            servers.spawn(serve_connections(listener, app, options));
+        }
+    }
+
+    Ok((addrs, async move {
+        while let Some(result) = servers.join_next().await {
+            result.map_err(std::io::Error::other)??;
+        }
+        Ok(())
+    }))
+}
+
+/// Serves every connection accepted from `listener` over HTTP/1, each on its own task.
+/// Handlers see the address reported by the listener as `ConnectInfo<SocketAddr>`.
+async fn serve_connections<L>(
+    mut listener: L,
+    app: Router,
+    options: ServeOptions,
+) -> std::io::Result<()>
+where
+    L: axum::serve::Listener<Addr = SocketAddr>,
+{
+    use tower::ServiceExt;
+
+    let mut http = hyper::server::conn::http1::Builder::new();
+    http.timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(options.header_read_timeout);
+    if let Some(limit) = options.max_header_bytes {
+        // Oversized heads are refused while they are read, before taking more memory
+        http.max_buf_size(limit.max(MIN_READ_BUFFER_BYTES));
+    }
+
+    loop {
+        let (io, addr) = listener.accept().await;
+        let service = app.clone().map_request(
+            move |mut request: axum::http::Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(addr));
+                request
+            },
+        );
+        let connection = http.serve_connection(
+            hyper_util::rt::TokioIo::new(io),
+            hyper_util::service::TowerToHyperService::new(service),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!(client = %addr, error = %e, "Connection closed with an error");
+            }
+        });
+    }
+}
+
+fn print_config_info(config: &Config) {
+    let domain_names: Vec<&str> = config.domains.iter().map(|d| d.name.as_str()).collect();
+    info!(
+        unbound_config_path = ?config.unbound_config_path,
+        domain_count = config.domains.len(),
+        domains = ?domain_names,
+        "Loaded configuration"
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    // Initialize tracing subscriber for logging
+    // Log level can be controlled via RUST_LOG env var (e.g., RUST_LOG=info)
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::from_default_env()
+                .add_directive(tracing::Level::INFO.into()),
+        )
+        .init();
+
+    // One-shot bootstrap mode: `unbound_ddns --init-unbound <path>`
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("--init-unbound") {
+        let Some(unbound_path) = args.get(2) else {
+            error!("Usage: unbound_ddns --init-unbound <path>");
+            std::process::exit(2);
+        };
+        let result = Config::read("config.toml").and_then(|config| {
+            init_unbound_config(
+                Path::new(unbound_path),
+                &config.domains,
+                config.entry_format(),
+            )
+        });
+        match result {
+            Ok(added) => {
+                info!(path = %unbound_path, domains = ?added, "Initialized Unbound config");
+                std::process::exit(0);
+            }
+            Err(e) => {
+                error!(path = %unbound_path, error = %e, "Failed to initialize Unbound config");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Load configuration
+    let config = match Config::load("config.toml") {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!(error = %e, "Failed to load configuration");
+            std::process::exit(1);
+        }
+    };
+
+    print_config_info(&config);
+
+    let listen_addrs = config.listen_addr.addrs();
+
+    // Bind every address up front so a single failure aborts startup
+    let listeners = match bind_listeners(&listen_addrs).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            error!(error = %e, "Failed to start server");
+            std::process::exit(1);
+        }
+    };
+
+    let admin_listeners = match &config.admin_listen_addr {
+        Some(addr) => match bind_listeners(&[addr.as_str()]).await {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                error!(error = %e, "Failed to start admin server");
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let public_options = ServeOptions::public(&config);
+    let admin_options = ServeOptions::admin(&config);
+
+    // Build the routers, sharing a config that SIGHUP can replace
+    let config = Arc::new(ArcSwap::new(config));
+    #[cfg(unix)]
+    tokio::spawn(reload_config_on_hangup("config.toml", Arc::clone(&config)));
+    let dns_config = Arc::clone(&config);
+    let txt_resolver: TxtResolver = Arc::new(move |name: &str| {
+        let dns_server = dns_config.load().verify_resolution_server.clone();
+        let name = name.to_string();
+        Box::pin(async move { query_txt(&dns_server, &name).await })
+    });
+    tokio::spawn(discover_domains(Arc::clone(&config), txt_resolver));
+    let (app, admin_app) = create_apps(config);
+
+    // Start the server, reporting the bound addresses so port 0 can be discovered
+    let (addrs, server) = match serve(listeners, app, public_options) {
+        Ok(started) => started,
+        Err(e) => {
+            error!(error = %e, "Failed to start server");
+            std::process::exit(1);
+        }
+    };
+    info!(addresses = ?addrs, "Server started");
+
+    let result = match admin_app {
+        Some(admin_app) => {
+            let (admin_addrs, admin_server) = match serve(admin_listeners, admin_app, admin_options)
+            {
+                Ok(started) => started,
+                Err(e) => {
+                    error!(error = %e, "Failed to start admin server");
+                    std::process::exit(1);
+                }
+            };
+            info!(addresses = ?admin_addrs, "Admin server started");
+            tokio::try_join!(server, admin_server).map(|_| ())
+        }
+        None => server.await,
+    };
+    if let Err(e) = result {
+        error!(error = %e, "Server error");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    // ============================================================================
+    // TEST HELPERS
+    // ============================================================================
+
+    /// Builds the public router only.
+    fn create_app(config: Arc<Config>) -> Router {
+        create_apps(Arc::new(ArcSwap::new(config))).0
+    }
+
+    /// Unbound config held in memory, counting writes.
+    #[derive(Default)]
+    struct MemoryStore {
+        content: Mutex<String>,
+        writes: std::sync::atomic::AtomicUsize,
+    }
+
+    impl MemoryStore {
+        fn new(content: &str) -> Self {
+            Self {
+                content: Mutex::new(content.to_string()),
+                ..Self::default()
+            }
+        }
+
+        fn content(&self) -> String {
+            self.content.lock().unwrap().clone()
+        }
+
+        fn writes(&self) -> usize {
+            self.writes.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    impl ConfigStore for MemoryStore {
+        fn read(&self) -> Result<String, String> {
+            Ok(self.content())
+        }
+
+        fn write(&self, content: &str) -> Result<(), String> {
+            self.writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            *self.content.lock().unwrap() = content.to_string();
+            Ok(())
+        }
+    }
+
+    /// Creates a temporary unbound config file with optional domain entries.
+    ///
+    /// # Arguments
+    /// * `domains` - Optional slice of tuples (domain_name, ip_address) to add as local-data entries
+    ///
+    /// # Returns
+    /// A NamedTempFile that will be automatically deleted when dropped
+    fn create_unbound_config(domains: Option<&[(&str, &str)]>) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "server:").unwrap();
+        writeln!(file, "  verbosity: 1").unwrap();
+
+        if let Some(domain_entries) = domains {
+            for (domain, ip) in domain_entries {
+                writeln!(file, "local-data: \"{} IN A {}\"", domain, ip).unwrap();
+            }
+        }
+
+        file
+    }
+
+    /// Creates a test Config struct with optional parameters.
+    ///
+    /// # Arguments
+    /// * `unbound_config_path` - Optional path to unbound config file
+    /// * `domains` - Optional slice of tuples (domain_name, api_key) for domain configs
+    ///
+    /// # Returns
+    /// A Config struct ready for testing
+    fn create_test_config(
+        unbound_config_path: Option<PathBuf>,
+        domains: Option<&[(&str, &str)]>,
+    ) -> Config {
+        Config {
+            unbound_config_path: unbound_config_path
+                .unwrap_or_else(|| PathBuf::from("/tmp/test.conf")),
+            domains: domains
+                .map(|d| {
+                    d.iter()
+                        .map(|(name, key)| DomainConfig {
+                            name: name.to_string(),
+                            key: key.to_string(),
+                            ttl: None,
+                            ttl_v4: None,
+                            ttl_v6: None,
+                            config_path: None,
+                            rate_limit: None,
+                            reload_command: None,
+                            ip_version: IpVersion::Any,
+                            section: None,
+                            exclusive_family: false,
+                            discovered: false,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            proxy_token: None,
+            min_free_bytes: None,
+            listen_addr: ListenAddr::default(),
+            reload_enabled: true,
+            reload_command: None,
+            min_reload_interval_secs: None,
+            verify_resolution: false,
+            verify_resolution_server: default_verify_resolution_server(),
+            verify_resolution_timeout_ms: default_verify_resolution_timeout_ms(),
+            post_reload_delay_ms: 0,
+            verbose_errors: false,
+            max_updates_per_minute: None,
+            metrics_enabled: false,
+            normalize_idn: false,
+            server_ip_enabled: false,
+            server_ip_echo_url: default_server_ip_echo_url(),
+            server_ip_cache_secs: default_server_ip_cache_secs(),
+            max_connections: None,
+            header_read_timeout_secs: default_header_read_timeout_secs(),
+            proxy_protocol: false,
+            max_header_bytes: None,
+            startup_delay_secs: 0,
+            locked_retry_after_secs: default_locked_retry_after_secs(),
+            admin_listen_addr: None,
+            quiet_noop: false,
+            noop_status_304: false,
+            entry_template: None,
+            entry_match_regex: None,
+            trailing_dot: false,
+            verify_after_write: false,
+            sort_managed_entries: false,
+            group_by_type: false,
+            header_params: false,
+            success_status: default_success_status(),
+            diagnostics_token: None,
+            events_enabled: false,
+            diagnostics_unbound_version: false,
+            max_records_per_domain: default_max_records_per_domain(),
+            create_unbound_file_if_missing: false,
+            freeze_schedule: Vec::new(),
+            max_request_ttl: None,
+            status_page_enabled: false,
+            state_path: None,
+            unwrap_mapped_v4: false,
+            keys_file: None,
+            access_log_format: AccessLogFormat::Tracing,
+            verbose_response: false,
+            ignore_missing_unbound_control: false,
+            require_local_zone: false,
+            denied_domains: Vec::new(),
+            allowed_domains: None,
+            domain_groups: Vec::new(),
+            require_content_type: false,
+            trim_submitted_key: default_trim_submitted_key(),
+            cache_control: default_cache_control(),
+            idempotency_ttl_secs: default_idempotency_ttl_secs(),
+            coalesce_updates: false,
+            key_lookup_command: None,
+            key_lookup_cache_secs: default_key_lookup_cache_secs(),
+            domain_discovery_txt: None,
+            domain_discovery_interval_secs: default_domain_discovery_interval_secs(),
+            discovery_keys: HashMap::new(),
+            domain_lock_timeout_ms: None,
+            health_path: default_health_path(),
+        }
+    }
+
+    /// The built-in entry format with FQDN names (`name.`), as written with `trailing_dot`.
+    fn fqdn_format() -> EntryFormat<'static> {
+        EntryFormat {
+            trailing_dot: true,
+            ..EntryFormat::default()
+        }
+    }
+
+    /// Writer that appends formatted log output to a shared buffer.
+    struct LogWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Captures log output on the current thread until the returned guard is dropped.
+    ///
+    /// # Returns
+    /// The subscriber guard and the buffer the logs are written to
+    fn capture_logs() -> (
+        tracing::subscriber::DefaultGuard,
+        Arc<std::sync::Mutex<Vec<u8>>>,
+    ) {
+        let logs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer_logs = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || LogWriter(writer_logs.clone()))
+            .finish();
+        (tracing::subscriber::set_default(subscriber), logs)
+    }
+
+    // ============================================================================
+    // TESTS
+    // ============================================================================
+
+    #[test]
+    fn test_config_parsing() {
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("server.example.com", "192.168.1.2"),
+        ]));
+
+        let toml_content = format!(
+            r#"
+unbound_config_path = "{}"
+
+[[domains]]
+name = "home.example.com"
+key = "secret-key-1"
+
+[[domains]]
+name = "server.example.com"
+key = "secret-key-2"
+"#,
+            unbound_file.path().display()
+        );
+
+        let config: Config = toml::from_str(&toml_content).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.unbound_config_path, unbound_file.path());
+        assert_eq!(config.domains.len(), 2);
+        assert_eq!(config.domains[0].name, "home.example.com");
+        assert_eq!(config.domains[0].key, "secret-key-1");
+    }
+
+    #[test]
+    fn test_config_validation_no_domains() {
+        let config = create_test_config(None, None);
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("at least one domain"));
+    }
+
+    #[test]
+    fn test_config_validation_empty_domain_name() {
+        let config = create_test_config(None, Some(&[("", "key1")]));
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("empty name"));
+    }
+
+    #[test]
+    fn test_config_validation_empty_key() {
+        let config = create_test_config(None, Some(&[("test.example.com", "")]));
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("empty key"));
+    }
+
+    #[test]
+    fn test_config_validation_duplicate_domains() {
+        let config = create_test_config(
+            None,
+            Some(&[("test.example.com", "key1"), ("test.example.com", "key2")]),
+        );
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Duplicate domain"));
+    }
+
+    #[test]
+    fn test_domain_ttl_per_family() {
+        let mut config = create_test_config(None, Some(&[("test.example.com", "key1")]));
+        let domain = &mut config.domains[0];
+        domain.ttl_v4 = Some(3600);
+        domain.ttl_v6 = Some(60);
+
+        let v4: IpAddr = "203.0.113.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(domain.ttl_for(&v4), Some(3600));
+        assert_eq!(domain.ttl_for(&v6), Some(60));
+    }
+
+    #[test]
+    fn test_domain_ttl_falls_back_to_shared_ttl() {
+        let mut config = create_test_config(None, Some(&[("test.example.com", "key1")]));
+        let domain = &mut config.domains[0];
+        domain.ttl = Some(300);
+        domain.ttl_v6 = Some(60);
+
+        let v4: IpAddr = "203.0.113.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(domain.ttl_for(&v4), Some(300));
+        assert_eq!(domain.ttl_for(&v6), Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_applies_v4_ttl() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("ttl.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("ttl.example.com", "ttl-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        config.domains[0].ttl = Some(300);
+        config.domains[0].ttl_v4 = Some(120);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer ttl-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"ttl.example.com","ip":"203.0.113.7"}"#,
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Either succeeds or fails on unbound-control (expected in test environment)
+        assert!(
+            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
+            "Unexpected response: {} - {}",
+            status,
+            body_str
+        );
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"ttl.example.com. 120 IN A 203.0.113.7\""));
+    }
+
+    #[test]
+    fn test_config_listen_addr_single_and_list() {
+        let single: Config = toml::from_str(
+            r#"
+unbound_config_path = "/tmp/test.conf"
+listen_addr = "127.0.0.1:8080"
+domains = []
+"#,
+        )
+        .unwrap();
+        assert_eq!(single.listen_addr.addrs(), vec!["127.0.0.1:8080"]);
+
+        let multiple: Config = toml::from_str(
+            r#"
+unbound_config_path = "/tmp/test.conf"
+listen_addr = ["0.0.0.0:3000", "[::]:3000"]
+domains = []
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            multiple.listen_addr.addrs(),
+            vec!["0.0.0.0:3000", "[::]:3000"]
+        );
+
+        let default: Config = toml::from_str(
+            r#"
+unbound_config_path = "/tmp/test.conf"
+domains = []
+"#,
+        )
+        .unwrap();
+        assert_eq!(default.listen_addr.addrs(), vec!["0.0.0.0:3000"]);
+
+        let alias: Config = toml::from_str(
+            r#"
+unbound_config_path = "/tmp/test.conf"
+listen = "127.0.0.1:8080"
+domains = []
+"#,
+        )
+        .unwrap();
+        assert_eq!(alias.listen_addr.addrs(), vec!["127.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn test_config_validation_invalid_listen_addr() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "key1")]),
+        );
+        config.listen_addr =
+            ListenAddr::Multiple(vec!["127.0.0.1:3000".to_string(), "localhost".to_string()]);
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .contains("Invalid listen_addr 'localhost'")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bind_listeners_fails_if_any_bind_fails() {
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let taken_addr = taken.local_addr().unwrap().to_string();
+
+        let result = bind_listeners(&["127.0.0.1:0", &taken_addr]).await;
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains(&taken_addr));
+    }
+
+    #[tokio::test]
+    async fn test_serve_multiple_listeners() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let listeners = bind_listeners(&["127.0.0.1:0", "127.0.0.1:0"])
+            .await
+            .unwrap();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_ne!(addrs[0], addrs[1]);
+
+        let server = tokio::spawn(
+            serve(listeners, create_app(config), ServeOptions::default())
+                .unwrap()
+                .1,
+        );
+
+        for addr in addrs {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(
+                    b"POST /update HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+            assert!(response.contains("Missing Authorization header"));
+        }
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_reports_bound_addresses() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let listeners = bind_listeners(&["127.0.0.1:0", "127.0.0.1:0"])
+            .await
+            .unwrap();
+        let (addrs, server) =
+            serve(listeners, create_app(config), ServeOptions::default()).unwrap();
+        let server = tokio::spawn(server);
+
+        // Port 0 resolves to a real ephemeral port for each listener
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs.iter().all(|addr| addr.port() != 0));
+        assert_ne!(addrs[0], addrs[1]);
+
+        let mut stream = tokio::net::TcpStream::connect(addrs[1]).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_enforces_max_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let (addrs, server) = serve(
+            listeners,
+            create_app(config),
+            ServeOptions {
+                max_connections: Some(1),
+                ..ServeOptions::default()
+            },
+        )
+        .unwrap();
+        let addr = addrs[0];
+        let server = tokio::spawn(server);
+
+        // An idle connection occupies the only slot
+        let idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut waiting = tokio::net::TcpStream::connect(addr).await.unwrap();
+        waiting
+            .write_all(
+                b"POST /update HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 1];
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(300), waiting.read(&mut buf)).await;
+        assert!(
+            blocked.is_err(),
+            "second connection was served past the limit"
+        );
+
+        // Closing the idle connection frees the slot for the waiting one
+        drop(idle);
+        let mut response = String::new();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            waiting.read_to_string(&mut response),
+        )
+        .await
+        .expect("waiting connection was never served")
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header() {
+        let mut v1: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 443\r\nGET / HTTP/1.1";
+        assert_eq!(
+            read_proxy_header(&mut v1).await.unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        // Only the header is consumed
+        assert_eq!(v1, b"GET / HTTP/1.1");
+
+        let mut v1_ipv6: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 443\r\n";
+        assert_eq!(
+            read_proxy_header(&mut v1_ipv6).await.unwrap(),
+            Some("[2001:db8::7]:51234".parse().unwrap())
+        );
+
+        let mut unknown: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut unknown).await.unwrap(), None);
+
+        let mut v2 = PROXY_V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+        v2.extend_from_slice(&51234u16.to_be_bytes());
+        v2.extend_from_slice(&443u16.to_be_bytes());
+        v2.extend_from_slice(b"GET");
+        let mut v2 = v2.as_slice();
+        assert_eq!(
+            read_proxy_header(&mut v2).await.unwrap(),
+            Some("203.0.113.7:51234".parse().unwrap())
+        );
+        assert_eq!(v2, b"GET");
+
+        let mut local = PROXY_V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut local.as_slice()).await.unwrap(),
+            None
+        );
+
+        for invalid in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 2001:db8::7 192.0.2.1 51234 443\r\n",
+            b"PROXY TCP4 203.0.113.7 192.0.2.1\r\n",
+        ] {
+            let mut invalid = invalid;
+            assert!(read_proxy_header(&mut invalid).await.is_err());
+        }
+        let mut too_long = b"PROXY ".repeat(30);
+        too_long.extend_from_slice(b"\r\n");
+        assert!(read_proxy_header(&mut too_long.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_uses_proxy_protocol_client_address() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+
+        let listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let (addrs, server) = serve(
+            listeners,
+            create_app(Arc::new(config)),
+            ServeOptions {
+                proxy_protocol: true,
+                ..ServeOptions::default()
+            },
+        )
+        .unwrap();
+        let server = tokio::spawn(server);
+
+        let body = r#"{"domain":"test.example.com"}"#;
+        let mut stream = tokio::net::TcpStream::connect(addrs[0]).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "PROXY TCP4 203.0.113.7 127.0.0.1 51234 80\r\n\
+                     POST /update HTTP/1.1\r\nHost: localhost\r\n\
+                     Authorization: Bearer test-key\r\n\
+                     Content-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("203.0.113.7"), "{}", content);
+
+        // A connection without the header is dropped
+        let mut stream = tokio::net::TcpStream::connect(addrs[0]).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        assert!(response.is_empty(), "{}", response);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_closes_idle_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let (addrs, server) = serve(
+            listeners,
+            create_app(config),
+            ServeOptions {
+                max_connections: Some(1),
+                header_read_timeout: Duration::from_millis(200),
+                ..ServeOptions::default()
+            },
+        )
+        .unwrap();
+        let addr = addrs[0];
+        let server = tokio::spawn(server);
+
+        // A connection that never sends a request is closed once the timeout passes
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let closed = tokio::time::timeout(Duration::from_secs(5), idle.read(&mut buf))
+            .await
+            .expect("idle connection was never closed");
+        assert!(matches!(closed, Ok(0) | Err(_)));
+
+        // As is a keep-alive connection that goes quiet after its first request
+        let mut kept_alive = tokio::net::TcpStream::connect(addr).await.unwrap();
+        kept_alive
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let read = kept_alive.read(&mut buf).await.unwrap();
+        assert!(buf[..read].starts_with(b"HTTP/1.1 200"));
+        let closed = tokio::time::timeout(Duration::from_secs(5), kept_alive.read(&mut buf))
+            .await
+            .expect("idle keep-alive connection was never closed");
+        assert!(matches!(closed, Ok(0) | Err(_)));
+
+        // So the only slot is free for the next client
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await
+            .expect("connection was never served")
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_stops_reading_oversized_headers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let (addrs, server) = serve(
+            listeners,
+            create_app(config),
+            ServeOptions {
+                max_header_bytes: Some(MIN_READ_BUFFER_BYTES),
+                ..ServeOptions::default()
+            },
+        )
+        .unwrap();
+        let server = tokio::spawn(server);
+
+        // The headers never end, so only a limit on the buffer can answer this
+        let mut stream = tokio::net::TcpStream::connect(addrs[0]).await.unwrap();
+        let mut head = b"GET /health HTTP/1.1\r\nHost: localhost\r\nX-Padding: ".to_vec();
+        head.extend(std::iter::repeat_n(b'a', 2 * MIN_READ_BUFFER_BYTES));
+        stream.write_all(&head).await.unwrap();
+        let mut response = [0u8; 12];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response))
+            .await
+            .expect("oversized headers were never answered")
+            .unwrap();
+        assert_eq!(&response, b"HTTP/1.1 431");
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_max_header_bytes_rejects_oversized_headers() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.max_header_bytes = Some(1024);
+        let app = create_app(Arc::new(config));
+
+        let request = |padding: usize| {
+            Request::builder()
+                .uri("/health")
+                .header("x-padding", "a".repeat(padding))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request(100)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(request(2048)).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["message"], "Request headers too large");
+    }
+
+    #[test]
+    fn test_config_validation_zero_max_connections() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.max_connections = Some(0);
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("max_connections"));
+    }
+
+    #[test]
+    fn test_find_domain() {
+        let config = create_test_config(
+            None,
+            Some(&[("home.example.com", "key1"), ("server.example.com", "key2")]),
+        );
+
+        assert!(config.find_domain("home.example.com").is_some());
+        assert!(config.find_domain("nonexistent.com").is_none());
+    }
+
+    #[test]
+    fn test_config_validation_domain_not_in_unbound_config() {
+        let unbound_file = create_unbound_config(None);
+
+        let config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("missing.example.com", "key1")]),
+        );
+
+        let result = config.validate();
+        assert!(result.is_err());
+        let error_msg = result.unwrap_err();
+        assert!(error_msg.contains("missing.example.com"));
+        assert!(error_msg.contains("not found in Unbound config"));
+    }
+
+    #[test]
+    fn test_config_validation_require_local_zone() {
+        let mut unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("stray.example.org", "192.168.1.2"),
+        ]));
+        writeln!(unbound_file, "local-zone: \"example.com.\" static").unwrap();
+        writeln!(unbound_file, "# local-zone: \"example.org.\" static").unwrap();
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.require_local_zone = true;
+        assert!(config.validate().is_ok());
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("stray.example.org", "key1")]),
+        );
+        config.require_local_zone = true;
+        let error_msg = config.validate().unwrap_err();
+        assert!(error_msg.contains("stray.example.org"));
+        assert!(error_msg.contains("not covered by any local-zone"));
+    }
+
+    #[test]
+    fn test_has_covering_local_zone() {
+        let content = "  local-zone: \"Example.com.\" static\nlocal-zone: lan transparent\n";
+        assert!(has_covering_local_zone(content, "example.com"));
+        assert!(has_covering_local_zone(content, "home.example.com"));
+        assert!(has_covering_local_zone(content, "nas.lan"));
+        assert!(!has_covering_local_zone(content, "badexample.com"));
+        assert!(!has_covering_local_zone(content, "example.org"));
+    }
+
+    #[test]
+    fn test_update_unbound_config_nonexistent_domain() {
+        let unbound_file = create_unbound_config(None);
+
+        // Try to update non-existent domain - should fail
+        let result = update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["192.168.1.1"],
+            None,
+            EntryFormat::default(),
+            false,
+            false,
+        );
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("not found in Unbound config"));
+    }
+
+    #[test]
+    fn test_update_unbound_config_replace_entry() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+
+        // Update existing entry
+        update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+
+        // Verify - now writes with trailing dot (proper FQDN)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+        assert!(!content.contains("192.168.1.1"));
+    }
+
+    #[test]
+    fn test_update_unbound_config_memory_store() {
+        let store = MemoryStore::new(
+            "server:\n  local-data: \"a.example.com IN A 192.168.1.1\"\n  local-data: \"b.example.com IN A 192.168.1.2\"\n",
+        );
+
+        let changed = update_unbound_config(
+            &store,
+            "a.example.com",
+            &["10.0.0.1"],
+            Some(60),
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(changed);
+        assert_eq!(
+            store.content(),
+            "server:\n  local-data: \"a.example.com. 60 IN A 10.0.0.1\"\n  local-data: \"b.example.com IN A 192.168.1.2\"\n"
+        );
+
+        // Writing the same record again leaves the content as it is, without a write
+        let changed = update_unbound_config(
+            &store,
+            "a.example.com",
+            &["10.0.0.1"],
+            Some(60),
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(!changed);
+        assert_eq!(store.writes(), 1);
+    }
+
+    #[test]
+    fn test_update_unbound_config_memory_store_unknown_domain() {
+        let store = MemoryStore::new("server:\n");
+        let result = update_unbound_config(
+            &store,
+            "missing.example.com",
+            &["10.0.0.1"],
+            None,
+            EntryFormat::default(),
+            false,
+            false,
+        );
+        assert!(result.unwrap_err().contains("not found in Unbound config"));
+        assert_eq!(store.writes(), 0);
+        assert_eq!(store.content(), "server:\n");
+    }
+
+    #[test]
+    fn test_update_unbound_config_verify_after_write() {
+        /// A store whose writes never show up in later reads.
+        struct StaleStore(String);
+
+        impl ConfigStore for StaleStore {
+            fn read(&self) -> Result<String, String> {
+                Ok(self.0.clone())
+            }
+
+            fn write(&self, _content: &str) -> Result<(), String> {
+                Ok(())
+            }
+        }
+
+        let content = "server:\n  local-data: \"a.example.com IN A 192.168.1.1\"\n";
+
+        let stale = StaleStore(content.to_string());
+        let result = update_unbound_config(
+            &stale,
+            "a.example.com",
+            &["10.0.0.1"],
+            None,
+            EntryFormat::default(),
+            true,
+            false,
+        );
+        assert_eq!(result.unwrap_err(), READ_BACK_MISMATCH);
+
+        // A store that keeps its writes passes the check
+        let store = MemoryStore::new(content);
+        let result = update_unbound_config(
+            &store,
+            "a.example.com",
+            &["10.0.0.1"],
+            None,
+            EntryFormat::default(),
+            true,
+            false,
+        );
+        assert!(result.unwrap());
+    }
+
+    #[test]
+    fn test_update_unbound_config_trailing_dot_convention() {
+        let mut config = create_test_config(None, Some(&[("a.example.com", "key")]));
+        for existing in [
+            "local-data: \"a.example.com IN A 192.168.1.1\"\n",
+            "local-data: \"a.example.com. IN A 192.168.1.1\"\n",
+        ] {
+            config.trailing_dot = false;
+            let store = MemoryStore::new(existing);
+            update_unbound_config(
+                &store,
+                "a.example.com",
+                &["10.0.0.1"],
+                None,
+                config.entry_format(),
+                false,
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                store.content(),
+                "local-data: \"a.example.com IN A 10.0.0.1\"\n"
+            );
+
+            config.trailing_dot = true;
+            let store = MemoryStore::new(existing);
+            update_unbound_config(
+                &store,
+                "a.example.com",
+                &["10.0.0.1"],
+                Some(60),
+                config.entry_format(),
+                false,
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                store.content(),
+                "local-data: \"a.example.com. 60 IN A 10.0.0.1\"\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_update_unbound_config_keeps_crlf_line_endings() {
+        let mut unbound_file = NamedTempFile::new().unwrap();
+        write!(
+            unbound_file,
+            "server:\r\n  local-data: \"test.example.com IN A 1.2.3.4\"\r\n  local-data: \"other.example.com IN A 5.6.7.8\"\r\n"
+        )
+        .unwrap();
+        let path = unbound_file.path().to_path_buf();
+
+        update_unbound_config(
+            &path,
+            "test.example.com",
+            &["10.0.0.1"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            content,
+            "server:\r\n  local-data: \"test.example.com. IN A 10.0.0.1\"\r\n  local-data: \"other.example.com IN A 5.6.7.8\"\r\n"
+        );
+    }
+
+    #[test]
+    fn test_entry_template_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let unbound_path = dir.path().join("unbound.conf");
+        let mut config = create_test_config(
+            Some(unbound_path.clone()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.domains[0].ttl = Some(300);
+        config.entry_template =
+            Some("local-data: '{domain}. {ttl} {class} {type} {value}' # ddns".to_string());
+        config.entry_match_regex =
+            Some(r"local-data: '{domain}\. \d* ?IN {type} [^']+' # ddns".to_string());
+        let format = config.entry_format();
+
+        init_unbound_config(&unbound_path, &config.domains, format).unwrap();
+        assert_eq!(
+            fs::read_to_string(&unbound_path).unwrap(),
+            "server:\n  local-data: 'home.example.com. 300 IN A 0.0.0.0' # ddns\n"
+        );
+        assert!(config.validate().is_ok());
+
+        for ip in ["203.0.113.1", "203.0.113.2"] {
+            update_unbound_config(
+                &unbound_path,
+                "home.example.com",
+                &[ip],
+                Some(300),
+                format,
+                false,
+                false,
+            )
+            .unwrap();
+            assert_eq!(
+                fs::read_to_string(&unbound_path).unwrap(),
+                format!(
+                    "server:\n  local-data: 'home.example.com. 300 IN A {}' # ddns\n",
+                    ip
+                )
+            );
+        }
+
+        // Both families live side by side, and each write only replaces its own
+        for ip in ["2001:db8::1", "203.0.113.3"] {
+            update_unbound_config(
+                &unbound_path,
+                "home.example.com",
+                &[ip],
+                Some(300),
+                format,
+                false,
+                false,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            fs::read_to_string(&unbound_path).unwrap(),
+            "server:\n  local-data: 'home.example.com. 300 IN A 203.0.113.3' # ddns\n  local-data: 'home.example.com. 300 IN AAAA 2001:db8::1' # ddns\n"
+        );
+
+        // Entries in the default format are not recognized by the custom regex
+        fs::write(
+            &unbound_path,
+            "server:\n  local-data: \"home.example.com. IN A 1.2.3.4\"\n",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_validation_entry_template_requires_match_regex() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.entry_template = Some("local-data: \"{domain}. IN A {value}\"".to_string());
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("entry_match_regex"));
+
+        config.entry_match_regex = Some("local-data: \"{domain}\\. IN A [^\"]+\"".to_string());
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("must use `{type}`"));
+
+        config.entry_match_regex = Some("local-data: \"{domain}( IN {type}".to_string());
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid entry_match_regex"));
+    }
+
+    #[test]
+    fn test_update_unbound_config_primary_and_backup() {
+        let unbound_file = create_unbound_config(Some(&[
+            ("test.example.com", "1.2.3.4"),
+            ("other.example.com", "5.6.7.8"),
+        ]));
+        let path = unbound_file.path().to_path_buf();
+
+        update_unbound_config(
+            &path,
+            "test.example.com",
+            &["10.0.0.1", "10.0.0.2"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server:\n  verbosity: 1\n\
+             local-data: \"test.example.com. IN A 10.0.0.1\"\n\
+             local-data: \"test.example.com. IN A 10.0.0.2\"\n\
+             local-data: \"other.example.com IN A 5.6.7.8\"\n"
+        );
+
+        // The next update replaces both records
+        update_unbound_config(
+            &path,
+            "test.example.com",
+            &["10.0.0.3", "10.0.0.4"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let records = parse_records(&content, "test.example.com");
+        let values: Vec<&str> = records.iter().map(|r| r.value.as_str()).collect();
+        assert_eq!(values, vec!["10.0.0.3", "10.0.0.4"]);
+
+        // Without a backup, the old backup record is removed
+        update_unbound_config(
+            &path,
+            "test.example.com",
+            &["10.0.0.5"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server:\n  verbosity: 1\n\
+             local-data: \"test.example.com. IN A 10.0.0.5\"\n\
+             local-data: \"other.example.com IN A 5.6.7.8\"\n"
+        );
+    }
+
+    #[test]
+    fn test_line_ending_detection() {
+        assert_eq!(line_ending(""), "\n");
+        assert_eq!(line_ending("a\nb\n"), "\n");
+        assert_eq!(line_ending("a\r\nb\r\n"), "\r\n");
+        assert_eq!(line_ending("a\r\nb\r\nc\n"), "\r\n");
+    }
+
+    #[test]
+    fn test_update_unbound_config_zero_ttl_vs_no_ttl() {
+        let zero_ttl_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let no_ttl_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+
+        update_unbound_config(
+            &zero_ttl_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
+            Some(0),
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        update_unbound_config(
+            &no_ttl_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+
+        let zero_ttl_content = fs::read_to_string(zero_ttl_file.path()).unwrap();
+        let no_ttl_content = fs::read_to_string(no_ttl_file.path()).unwrap();
+        assert!(zero_ttl_content.contains("local-data: \"test.example.com. 0 IN A 10.0.0.1\""));
+        assert!(no_ttl_content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+        assert_ne!(zero_ttl_content, no_ttl_content);
+    }
+
+    #[test]
+    fn test_update_unbound_config_group_by_type() {
+        let content = concat!(
+            "server:\n",
+            "  local-data: \"one.example.com IN A 192.168.1.1\"\n",
+            "  local-data: \"two.example.com IN A 192.168.1.2\"\n",
+            "  local-data: \"one.example.com IN AAAA 2001:db8::1\"\n",
+            "  local-data: \"three.example.com IN A 192.168.1.3\"\n",
+        );
+        let update = |format: EntryFormat| {
+            let file = create_unbound_config(None);
+            fs::write(file.path(), content).unwrap();
+            update_unbound_config(
+                &file.path().to_path_buf(),
+                "two.example.com",
+                &["2001:db8::2"],
+                None,
+                format,
+                false,
+                false,
+            )
+            .unwrap();
+            fs::read_to_string(file.path()).unwrap()
+        };
+
+        // By default the AAAA goes beside the domain's A record
+        let beside = update(EntryFormat::default());
+        assert!(beside.contains(concat!(
+            "  local-data: \"two.example.com IN A 192.168.1.2\"\n",
+            "  local-data: \"two.example.com IN AAAA 2001:db8::2\"\n",
+            "  local-data: \"one.example.com IN AAAA 2001:db8::1\"\n",
+        )));
+
+        // Grouped, it joins the existing AAAA block
+        let grouped = update(EntryFormat {
+            group_by_type: true,
+            ..EntryFormat::default()
+        });
+        assert!(grouped.contains(concat!(
+            "  local-data: \"one.example.com IN AAAA 2001:db8::1\"\n",
+            "  local-data: \"two.example.com IN AAAA 2001:db8::2\"\n",
+            "  local-data: \"three.example.com IN A 192.168.1.3\"\n",
+        )));
+        assert!(grouped.contains("  local-data: \"two.example.com IN A 192.168.1.2\"\n"));
+    }
+
+    #[test]
+    fn test_update_unbound_config_replaces_entry_with_ttl() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com. 300", "192.168.1.1")]));
+
+        // An existing TTL is replaced rather than duplicated
+        update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+        assert!(!content.contains("300"));
+        assert!(!content.contains("192.168.1.1"));
+    }
+
+    // ============================================================================
+    // INTEGRATION TESTS - DO NOT REMOVE
+    // These tests verify the actual HTTP endpoint behavior with form data
+    // ============================================================================
+
+    #[tokio::test]
+    async fn test_update_endpoint_unauthorized_domain() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("allowed.example.com", "secret123")]),
+        ));
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer secret123")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=notallowed.example.com"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Unauthorized"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_invalid_key() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "correct-key")]),
+        ));
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer wrong-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com&ip=10.0.0.1"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Unauthorized"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_with_explicit_ip() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        // Note: This will fail to reload unbound, but that's expected in test
+        // We're mainly testing the request parsing and validation
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Either succeeds or fails on unbound-control (expected in test environment)
+        assert!(
+            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
+            "Unexpected response: {} - {}",
+            status,
+            body_str
+        );
+
+        // Verify config was updated with proper FQDN (trailing dot)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.42\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_auto_detect_ip() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("auto.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("auto.example.com", "auto-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer auto-key")
+            .extension(ConnectInfo(
+                "198.51.100.42:54321".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=auto.example.com"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Either succeeds or fails on unbound-control (expected in test environment)
+        assert!(
+            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
+            "Unexpected response: {} - {}",
+            status,
+            body_str
+        );
+
+        // Verify config was updated with client IP and proper FQDN (trailing dot)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"auto.example.com. IN A 198.51.100.42\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_reload_disabled() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("noreload.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("noreload.example.com", "noreload-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer noreload-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=noreload.example.com&ip=203.0.113.42"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        // unbound-control is unavailable in tests, so success means no reload was attempted
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(status, StatusCode::OK, "{}", body_str);
+        assert!(body_str.contains("Updated noreload.example.com to 203.0.113.42"));
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"noreload.example.com. IN A 203.0.113.42\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_hides_internal_error_detail() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing_path = dir.path().join("missing-unbound.conf");
+
+        let mut config = create_test_config(
+            Some(missing_path.clone()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.verbose_errors = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.42"}"#,
+            ))
+            .unwrap();
+
+        let (_guard, logs) = capture_logs();
+        let response = app.oneshot(request).await.unwrap();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Internal error"));
+        assert!(!body_str.contains("Failed to read Unbound config"));
+
+        // The detail still goes to the log
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Failed to update configuration"));
+        assert!(logs.contains("Failed to read Unbound config"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_denied_domain() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("open.example.com", "192.168.1.1"),
+            ("paused.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("open.example.com", "open-key"),
+                ("paused.example.com", "paused-key"),
+            ]),
+        );
+        config.reload_enabled = false;
+        config.denied_domains = vec!["paused.example.com".to_string()];
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let update = |domain: &str, key: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain={}.&ip=203.0.113.42", domain)))
+                .unwrap()
+        };
+
+        // Refused even with the right key, and the record is left alone
+        let response = app
+            .clone()
+            .oneshot(update("paused.example.com", "paused-key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"paused.example.com IN A 192.168.1.2\""));
+
+        let response = app
+            .oneshot(update("open.example.com", "open-key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_domain_blocked_allowed_domains() {
+        let mut config = create_test_config(None, Some(&[("a.example.com", "key")]));
+        assert!(!config.domain_blocked("a.example.com"));
+
+        config.allowed_domains = Some(vec!["a.example.com".to_string()]);
+        assert!(!config.domain_blocked("a.example.com"));
+        assert!(config.domain_blocked("b.example.com"));
+
+        config.denied_domains = vec!["a.example.com".to_string()];
+        assert!(config.domain_blocked("a.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_empty_body() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["message"], "Empty request body");
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_require_content_type() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.require_content_type = true;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        // JSON sent without the header would otherwise be parsed as form data
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain": "test.example.com", "ip": "203.0.113.42"}"#,
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Missing Content-Type header"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_key_lookup_command() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "")]),
+        );
+        config.reload_enabled = false;
+        config.key_lookup_command = Some(vec!["secret-tool".to_string()]);
+        assert!(config.validate().is_ok());
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.key_lookup = Some(Arc::new(move |domain: &str| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            assert_eq!(domain, "test.example.com");
+            Ok("issued-key".to_string())
+        }));
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let update = |key: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(update("issued-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(update("wrong-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // The second request reused the cached key
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_domain_lock_conflict() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.domain_lock_timeout_ms = Some(50);
+
+        let state = AppState::new(Arc::new(config));
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state.clone());
+        let update = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        // An update that is still in flight holds the domain's lock
+        let in_flight = state
+            .domain_locks
+            .get("test.example.com")
+            .lock_owned()
+            .await;
+        let response = app.clone().oneshot(update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(!content.contains("203.0.113.42"));
+
+        // Once it completes, the next update goes through
+        drop(in_flight);
+        let response = app.oneshot(update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_verbose_response() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.verbose_response = true;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        // No ip in the request, so the connection address is used
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "198.51.100.42:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(r#"{"domain": "test.example.com."}"#))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["domain"], "test.example.com");
+        assert_eq!(json["ip_source"], "auto_detected");
+        assert_eq!(json["record_type"], "A");
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_per_domain_config_path() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let inside_file = create_unbound_config(Some(&[("inside.example.com", "192.168.1.1")]));
+        let outside_file = create_unbound_config(Some(&[("outside.example.com", "192.168.1.2")]));
+        let mut config = create_test_config(
+            Some(inside_file.path().to_path_buf()),
+            Some(&[
+                ("inside.example.com", "inside-key"),
+                ("outside.example.com", "outside-key"),
+            ]),
+        );
+        config.trailing_dot = true;
+        config.domains[1].config_path = Some(outside_file.path().to_path_buf());
+        config.reload_enabled = false;
+        assert!(config.validate().is_ok());
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        for (domain, key, ip) in [
+            ("inside.example.com", "inside-key", "10.0.0.1"),
+            ("outside.example.com", "outside-key", "203.0.113.42"),
+        ] {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/x-www-form-urlencoded")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain={}&ip={}", domain, ip)))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let inside = fs::read_to_string(inside_file.path()).unwrap();
+        assert!(inside.contains("local-data: \"inside.example.com. IN A 10.0.0.1\""));
+        assert!(!inside.contains("outside.example.com"));
+        let outside = fs::read_to_string(outside_file.path()).unwrap();
+        assert!(outside.contains("local-data: \"outside.example.com. IN A 203.0.113.42\""));
+        assert!(!outside.contains("inside.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_logs_key_fingerprint() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+            .unwrap();
+
+        let (_guard, logs) = capture_logs();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // First 8 hex digits of sha256("test-key")
+        assert_eq!(key_fingerprint("test-key"), "62af8704");
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("key_fingerprint=62af8704"), "{}", logs);
+        assert!(!logs.contains("test-key"));
+    }
+
+    #[tokio::test]
+    async fn test_patch_domain_ttl_only() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+        let app = create_app(Arc::new(config));
+
+        let request = |body: &str| {
+            Request::builder()
+                .method("PATCH")
+                .uri("/domains/test.example.com")
+                .header("authorization", "Bearer test-key")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request(r#"{"ttl":600}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. 600 IN A 1.2.3.4\""));
+
+        // Changing only the IP keeps the TTL that was just set
+        let response = app
+            .clone()
+            .oneshot(request(r#"{"ip":"203.0.113.42"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. 600 IN A 203.0.113.42\""));
+
+        let response = app.oneshot(request("{}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_patch_domain_shares_update_responses() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(None);
+        fs::write(
+            unbound_file.path(),
+            "server:\n  local-data: \"test.example.com 86400 IN A 1.2.3.4\"\n",
+        )
+        .unwrap();
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.max_request_ttl = Some(3600);
+        config.verbose_response = true;
+        let app = create_app(Arc::new(config.clone()));
+
+        let request = |body: &str| {
+            Request::builder()
+                .method("PATCH")
+                .uri("/domains/test.example.com")
+                .header("authorization", "Bearer test-key")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let json = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        // The TTL already in the file isn't held to the maximum, only a requested one is
+        let response = app
+            .clone()
+            .oneshot(request(r#"{"ip":"203.0.113.42"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            fs::read_to_string(unbound_file.path())
+                .unwrap()
+                .contains("local-data: \"test.example.com 86400 IN A 203.0.113.42\"")
+        );
+        let response = app
+            .clone()
+            .oneshot(request(r#"{"ttl":86400}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Repeating the patch is answered as a no-op
+        let response = app
+            .clone()
+            .oneshot(request(r#"{"ip":"203.0.113.42"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json(response).await;
+        assert_eq!(body["message"], "No change, already 203.0.113.42");
+        assert_eq!(body["ip_source"], "explicit");
+
+        // A TTL-only patch keeps the current address
+        config.noop_status_304 = true;
+        let app = create_app(Arc::new(config.clone()));
+        let response = app.clone().oneshot(request(r#"{"ttl":60}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json(response).await["ip_source"], "kept");
+        let response = app.oneshot(request(r#"{"ttl":60}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // A successful write follows success_status like /update
+        config.success_status = 204;
+        let app = create_app(Arc::new(config));
+        let response = app.oneshot(request(r#"{"ttl":120}"#)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_domain_group() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let main_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let other_file = create_unbound_config(Some(&[("nas.example.com", "192.168.1.3")]));
+        let mut config = create_test_config(
+            Some(main_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "home-key"),
+                ("vpn.example.com", "vpn-key"),
+                ("nas.example.com", "nas-key"),
+            ]),
+        );
+        config.trailing_dot = true;
+        config.domains[2].config_path = Some(other_file.path().to_path_buf());
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+                "nas.example.com".to_string(),
+            ],
+        }];
+        assert!(config.validate().is_ok());
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let request = |key: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=wan&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        // A member's own key doesn't unlock the group
+        let response = app.clone().oneshot(request("home-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(
+            !fs::read_to_string(main_file.path())
+                .unwrap()
+                .contains("203.0.113.42")
+        );
+
+        let response = app.oneshot(request("group-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let main_content = fs::read_to_string(main_file.path()).unwrap();
+        assert!(main_content.contains("local-data: \"home.example.com. IN A 203.0.113.42\""));
+        assert!(main_content.contains("local-data: \"vpn.example.com. IN A 203.0.113.42\""));
+        let other_content = fs::read_to_string(other_file.path()).unwrap();
+        assert!(other_content.contains("local-data: \"nas.example.com. IN A 203.0.113.42\""));
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_group_update_is_a_noop() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "home-key"),
+                ("vpn.example.com", "vpn-key"),
+            ]),
+        );
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+            ],
+        }];
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let app = |config: Config| {
+            let counter = reloads.clone();
+            let mut state = AppState::new(Arc::new(config));
+            state.reloader = Arc::new(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+            Router::new()
+                .route("/update", post(update_handler))
+                .with_state(state)
+        };
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer group-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=wan&ip=203.0.113.42"))
+                .unwrap()
+        };
+        let message = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            json["message"].as_str().unwrap().to_string()
+        };
+
+        let response = app(config.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            message(response).await,
+            "Updated home.example.com, vpn.example.com to 203.0.113.42"
+        );
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+        // Nothing to write or reload the second time
+        let response = app(config.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(message(response).await, "No change, already 203.0.113.42");
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+        config.quiet_noop = true;
+        let response = app(config.clone()).oneshot(request()).await.unwrap();
+        assert_eq!(message(response).await, "nochg");
+
+        config.noop_status_304 = true;
+        let response = app(config).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_group_update_with_blocked_member_is_refused_before_auth() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "home-key"),
+                ("vpn.example.com", "vpn-key"),
+            ]),
+        );
+        config.reload_enabled = false;
+        config.denied_domains = vec!["vpn.example.com".to_string()];
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+            ],
+        }];
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = |key: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=wan&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        // Denied before the key is looked at, so a wrong key gets the same answer
+        for key in ["wrong-key", "group-key"] {
+            let response = app.clone().oneshot(request(key)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+        assert!(
+            !fs::read_to_string(unbound_file.path())
+                .unwrap()
+                .contains("203.0.113.42")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_and_stream_updates_take_domain_locks() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("one.example.com", "key"), ("two.example.com", "key")]),
+        );
+        config.reload_enabled = false;
+        config.domain_lock_timeout_ms = Some(50);
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec!["one.example.com".to_string(), "two.example.com".to_string()],
+        }];
+
+        let state = AppState::new(Arc::new(config));
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .route("/update/stream", post(update_stream_handler))
+            .with_state(state.clone());
+        let request = |uri: &str, key: &str, body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // An update of two.example.com is still in flight
+        let in_flight = state.domain_locks.get("two.example.com").lock_owned().await;
+        let response = app
+            .clone()
+            .oneshot(request("/update", "group-key", "domain=wan&ip=203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(!content.contains("203.0.113.1"));
+
+        let ndjson_body = concat!(
+            r#"{"domain":"one.example.com","ip":"203.0.113.2"}"#,
+            "\n",
+            r#"{"domain":"two.example.com","ip":"203.0.113.2"}"#,
+            "\n",
+        );
+        let response = app
+            .clone()
+            .oneshot(request("/update/stream", "key", ndjson_body))
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["success"], true);
+        assert_eq!(lines[1]["success"], false);
+        assert_eq!(lines[1]["message"], DOMAIN_LOCKED_ERROR);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("one.example.com IN A 203.0.113.2"));
+        assert!(content.contains("two.example.com IN A 192.168.1.2"));
+
+        // Once it completes, the group goes through
+        drop(in_flight);
+        let response = app
+            .oneshot(request("/update", "group-key", "domain=wan&ip=203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_group_and_stream_updates_verify_resolution() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("one.example.com", "key"), ("two.example.com", "key")]),
+        );
+        config.verify_after_write = true;
+        config.verify_resolution = true;
+        config.verify_resolution_timeout_ms = 300;
+        config.verbose_errors = true;
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec!["one.example.com".to_string(), "two.example.com".to_string()],
+        }];
+
+        // Unbound answers from the file, unless it is stuck on an old address
+        let stale = Arc::new(AtomicBool::new(true));
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(|_| Ok(()));
+        let unbound_path = unbound_file.path().to_path_buf();
+        let stuck = stale.clone();
+        state.dns_resolver = Arc::new(move |domain, _| {
+            let ips = if stuck.load(Ordering::SeqCst) {
+                vec!["192.0.2.1".parse().unwrap()]
+            } else {
+                let content = fs::read_to_string(&unbound_path).unwrap();
+                parse_records(&content, domain)
+                    .iter()
+                    .filter_map(|record| record.value.parse().ok())
+                    .collect()
+            };
+            Box::pin(async move { Ok(ips) })
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .route("/update/stream", post(update_stream_handler))
+            .with_state(state);
+        let request = |uri: &str, key: &str, body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let body_of = |response: Response| async move {
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/update", "group-key", "domain=wan&ip=203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(
+            body_of(response)
+                .await
+                .contains("Failed to verify resolution")
+        );
+
+        let ndjson_body = concat!(r#"{"domain":"one.example.com","ip":"203.0.113.2"}"#, "\n");
+        let response = app
+            .clone()
+            .oneshot(request("/update/stream", "key", ndjson_body))
+            .await
+            .unwrap();
+        let body = body_of(response).await;
+        let verify: serde_json::Value = serde_json::from_str(
+            body.lines()
+                .find(|line| line.contains("\"verify\""))
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(verify["domain"], "one.example.com");
+        assert_eq!(verify["success"], false);
+
+        stale.store(false, Ordering::SeqCst);
+        let response = app
+            .clone()
+            .oneshot(request("/update", "group-key", "domain=wan&ip=203.0.113.3"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let ndjson_body = concat!(r#"{"domain":"two.example.com","ip":"203.0.113.4"}"#, "\n");
+        let response = app
+            .oneshot(request("/update/stream", "key", ndjson_body))
+            .await
+            .unwrap();
+        assert!(
+            body_of(response)
+                .await
+                .contains(r#"{"verify":true,"domain":"two.example.com","success":true"#)
+        );
+    }
+
+    #[test]
+    fn test_apply_group_update_is_all_or_nothing() {
+        let main_file = create_unbound_config(Some(&[("home.example.com", "192.168.1.1")]));
+        // nas.example.com has no entry in its file, so staging it fails
+        let other_file = create_unbound_config(None);
+        let mut config = create_test_config(
+            Some(main_file.path().to_path_buf()),
+            Some(&[("home.example.com", "k1"), ("nas.example.com", "k2")]),
+        );
+        config.domains[1].config_path = Some(other_file.path().to_path_buf());
+        let group = DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "nas.example.com".to_string(),
+            ],
+        };
+        let state = AppState::new(Arc::new(config));
+
+        let result = apply_group_update(&state, &group, "203.0.113.42", None);
+        assert_eq!(result.unwrap_err().0, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(
+            !fs::read_to_string(main_file.path())
+                .unwrap()
+                .contains("203.0.113.42")
+        );
+    }
+
+    #[test]
+    fn test_config_validation_domain_groups() {
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key")]),
+        );
+        let group = |name: &str, domains: &[&str]| DomainGroup {
+            name: name.to_string(),
+            key: "group-key".to_string(),
+            domains: domains.iter().map(|d| d.to_string()).collect(),
+        };
+
+        config.domain_groups = vec![group("wan", &["missing.example.com"])];
+        assert!(config.validate().unwrap_err().contains("unknown domain"));
+
+        config.domain_groups = vec![group("home.example.com", &["home.example.com"])];
+        assert!(config.validate().unwrap_err().contains("clashes"));
+
+        config.domain_groups = vec![group("wan", &[])];
+        assert!(config.validate().unwrap_err().contains("no domains"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_quiet_noop() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.quiet_noop = true;
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(
+                    r#"{"domain":"test.example.com","ip":"203.0.113.42"}"#,
+                ))
+                .unwrap()
+        };
+
+        // The first update changes the record and is reported as usual
+        let response = app.clone().oneshot(request()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Updated test.example.com to 203.0.113.42"));
+
+        // Repeating it is a no-op
+        let (_guard, logs) = capture_logs();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["message"], "nochg");
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("DEBUG"));
+        assert!(logs.contains("DNS record unchanged"));
+        assert!(!logs.contains("INFO"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_header_params() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .header("x-ddns-domain", "test.example.com")
+                .header("x-ddns-ip", "203.0.113.9")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Ignored unless enabled
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config.clone())));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        config.header_params = true;
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.9\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_success_status_204() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.success_status = 204;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = |domain: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain={}&ip=203.0.113.42", domain)))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("test.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+
+        // Failures still carry a JSON body
+        let response = app.oneshot(request("other.example.com")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("Unauthorized")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_noop_status_304() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.noop_status_304 = true;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_config_validation_success_status() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.success_status = 204;
+        assert!(config.validate().is_ok());
+
+        config.success_status = 201;
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("success_status"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_backup_ip_respects_cap() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(
+                    "domain=test.example.com&ip=203.0.113.1&backup_ip=203.0.113.2",
+                ))
+                .unwrap()
+        };
+
+        // The default cap of one record rejects a backup
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config.clone())));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("backup_ip is not allowed")
+        );
+
+        config.max_records_per_domain = 2;
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("Updated test.example.com to 203.0.113.1 (backup 203.0.113.2)")
+        );
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.1\""));
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.2\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_freeze_schedule() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.freeze_schedule = vec![FreezeWindow {
+            days: vec![Weekday::Sat, Weekday::Sun],
+            start_hour: 0,
+            end_hour: 24,
+        }];
+        let config = Arc::new(config);
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+        let app_at = |unix_secs: u64| {
+            let mut state = AppState::new(config.clone());
+            state.clock = Arc::new(move || SystemTime::UNIX_EPOCH + Duration::from_secs(unix_secs));
+            Router::new()
+                .route("/update", post(update_handler))
+                .with_state(state)
+        };
+
+        // Saturday 2024-01-06 12:00 UTC
+        let response = app_at(1_704_542_400).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("Updates are frozen")
+        );
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("test.example.com IN A 1.2.3.4"));
+
+        // Monday 2024-01-08 12:00 UTC
+        let response = app_at(1_704_715_200).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_utc_weekday_and_hour() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(utc_weekday_and_hour(at(0)), (Weekday::Thu, 0));
+        // 2024-01-07 23:59:59 UTC, a Sunday
+        assert_eq!(utc_weekday_and_hour(at(1_704_671_999)), (Weekday::Sun, 23));
+        // 2024-01-08 00:00:00 UTC, a Monday
+        assert_eq!(utc_weekday_and_hour(at(1_704_672_000)), (Weekday::Mon, 0));
+    }
+
+    #[test]
+    fn test_config_validation_freeze_schedule_hours() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.freeze_schedule = vec![FreezeWindow {
+            days: vec![Weekday::Fri],
+            start_hour: 18,
+            end_hour: 18,
+        }];
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("freeze_schedule"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_request_ttl_overrides_domain_ttl() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+        config.domains[0].ttl = Some(3600);
+        config.max_request_ttl = Some(300);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = |ttl: u32| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain":"test.example.com","ip":"203.0.113.42","ttl":{}}}"#,
+                    ttl
+                )))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request(60)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. 60 IN A 203.0.113.42\""));
+
+        // Above the configured maximum
+        let response = app.oneshot(request(301)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("exceeds the maximum of 300")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_state_path_survives_restart() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.quiet_noop = true;
+        config.domains[0].ttl = Some(60);
+        config.state_path = Some(state_path.clone());
+        let config = Arc::new(config);
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        let state = AppState::new(config.clone());
+        assert_eq!(state.last_known.get("test.example.com"), None);
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state_path.exists());
+
+        // A fresh state, as after a restart, picks up what was written
+        let state = AppState::new(config);
+        let known = state.last_known.get("test.example.com").unwrap();
+        assert_eq!(known.ips, ["203.0.113.42".parse::<IpAddr>().unwrap()]);
+        assert_eq!(known.ttl, Some(60));
+        assert_eq!(
+            known.written_to.unwrap().path,
+            unbound_file.path().to_path_buf()
+        );
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+        let response = app.oneshot(request()).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["message"], "nochg");
+    }
+
+    #[tokio::test]
+    async fn test_state_path_notices_edits_and_moved_files() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.state_path = Some(dir.path().join("state.json"));
+
+        let update = |config: Config| async move {
+            let app = Router::new()
+                .route("/update", post(update_handler))
+                .with_state(AppState::new(Arc::new(config)));
+            let request = Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            json["message"].as_str().unwrap().to_string()
+        };
+        let updated = "Updated test.example.com to 203.0.113.42";
+
+        assert_eq!(update(config.clone()).await, updated);
+        assert_eq!(
+            update(config.clone()).await,
+            "No change, already 203.0.113.42"
+        );
+
+        // Someone puts the old address back by hand
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        fs::write(
+            unbound_file.path(),
+            content.replace("203.0.113.42", "1.2.3.4"),
+        )
+        .unwrap();
+        assert_eq!(update(config.clone()).await, updated);
+        assert!(
+            fs::read_to_string(unbound_file.path())
+                .unwrap()
+                .contains("local-data: \"test.example.com IN A 203.0.113.42\"")
+        );
+
+        // A reload pointing the domain at another file writes that file
+        let other_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        config.domains[0].config_path = Some(other_file.path().to_path_buf());
+        assert_eq!(update(config).await, updated);
+        assert!(
+            fs::read_to_string(other_file.path())
+                .unwrap()
+                .contains("local-data: \"test.example.com IN A 203.0.113.42\"")
+        );
+    }
+
+    #[test]
+    fn test_last_known_store_without_path_tracks_nothing() {
+        let store = LastKnownStore::load(None).unwrap();
+        let record = KnownRecord {
+            ips: vec!["203.0.113.42".parse().unwrap()],
+            ttl: None,
+            written_to: None,
+        };
+        store.record("test.example.com", record).unwrap();
+        assert_eq!(store.get("test.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_unwraps_mapped_v4_client_ip() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+        config.unwrap_mapped_v4 = true;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        // No explicit IP, so the mapped connection address is used
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "[::ffff:203.0.113.5]:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.5\""));
+    }
+
+    #[test]
+    fn test_record_ip_unwrap_mapped_v4() {
+        let mut config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        assert_eq!(
+            config.record_ip("::ffff:203.0.113.5".to_string()),
+            "::ffff:203.0.113.5"
+        );
+
+        config.unwrap_mapped_v4 = true;
+        assert_eq!(
+            config.record_ip("::ffff:203.0.113.5".to_string()),
+            "203.0.113.5"
+        );
+        assert_eq!(config.record_ip("2001:db8::1".to_string()), "2001:db8::1");
+        assert_eq!(config.record_ip("203.0.113.5".to_string()), "203.0.113.5");
+    }
+
+    #[test]
+    fn test_canonical_ip() {
+        assert_eq!(canonical_ip("203.000.113.042"), "203.0.113.42");
+        assert_eq!(canonical_ip("010.0.0.1"), "10.0.0.1");
+        assert_eq!(
+            canonical_ip("2001:0db8:0000:0000:0000:0000:0000:0001"),
+            "2001:db8::1"
+        );
+        assert_eq!(canonical_ip("2001:DB8::1"), "2001:db8::1");
+        assert_eq!(canonical_ip("203.0.113.5:8080"), "203.0.113.5");
+        assert_eq!(canonical_ip("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(canonical_ip("203.0.113.42"), "203.0.113.42");
+
+        // Anything else is left for validation to reject
+        assert_eq!(canonical_ip("203.0.113.256"), "203.0.113.256");
+        assert_eq!(canonical_ip("203.0.113.0042"), "203.0.113.0042");
+        assert_eq!(canonical_ip("not-an-ip"), "not-an-ip");
+        assert_eq!(canonical_ip("203.0.113.5:http"), "203.0.113.5:http");
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_writes_canonical_ip() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com&ip=203.000.113.042"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.42\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_strips_port_from_ip() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let update = |ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain": "test.example.com", "ip": "{}"}}"#,
+                    ip
+                )))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(update("203.0.113.5:8080"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.5\""));
+
+        // Still not an address once the port is gone
+        let response = app.oneshot(update("host:8080")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_ipv4_precedence() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // ipv4 alone is used as the address
+        let response = app
+            .clone()
+            .oneshot(request("domain=test.example.com&ipv4=203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.1\""));
+
+        // Matching ip and ipv4 are fine
+        let response = app
+            .clone()
+            .oneshot(request(
+                "domain=test.example.com&ip=203.0.113.2&ipv4=203.0.113.2",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Conflicting ip and ipv4 are rejected without touching the file
+        let response = app
+            .oneshot(request(
+                "domain=test.example.com&ip=203.0.113.3&ipv4=203.0.113.4",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(
+            String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("Conflicting ip '203.0.113.3' and ipv4 '203.0.113.4'")
+        );
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.2\""));
+    }
+
+    #[test]
+    fn test_resolve_ip_fields() {
+        let request = |ip: Option<&str>, ipv4: Option<&str>, ipv6: Option<&str>| UpdateRequest {
+            domain: "test.example.com".to_string(),
+            ip: ip.map(String::from),
+            backup_ip: None,
+            ttl: None,
+            ipv4: ipv4.map(String::from),
+            ipv6: ipv6.map(String::from),
+        };
+
+        let mut payload = request(Some("203.0.113.1"), None, None);
+        assert!(payload.resolve_ip_fields().is_ok());
+        assert_eq!(payload.ip.as_deref(), Some("203.0.113.1"));
+
+        let mut payload = request(None, Some("203.0.113.2"), None);
+        assert!(payload.resolve_ip_fields().is_ok());
+        assert_eq!(payload.ip.as_deref(), Some("203.0.113.2"));
+
+        let mut payload = request(Some("203.0.113.1"), Some("203.0.113.2"), None);
+        assert!(
+            payload
+                .resolve_ip_fields()
+                .unwrap_err()
+                .contains("Conflicting")
+        );
+
+        let mut payload = request(None, None, Some("2001:db8::1"));
+        assert!(payload.resolve_ip_fields().is_ok());
+        assert_eq!(payload.ip.as_deref(), Some("2001:db8::1"));
+
+        let mut payload = request(None, Some("203.0.113.2"), Some("2001:db8::1"));
+        assert!(
+            payload
+                .resolve_ip_fields()
+                .unwrap_err()
+                .contains("either ipv4 or ipv6")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_verbose_internal_error_detail() {
+        use axum::body::Body;
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing_path = dir.path().join("missing-unbound.conf");
+
+        let mut config = create_test_config(
+            Some(missing_path),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.verbose_errors = true;
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.42"}"#,
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        // The server failed, not the request
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Failed to update configuration: Failed to read Unbound config"));
+    }
+
+    #[test]
+    fn test_write_failure_status() {
+        assert_eq!(
+            write_failure_status(DISK_FULL),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            write_failure_status("Failed to write Unbound config: Permission denied"),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_json_with_explicit_ip() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("json.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("json.example.com", "json-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let json_body = r#"{"domain":"json.example.com","ip":"203.0.113.100"}"#;
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer json-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(json_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Either succeeds or fails on unbound-control (expected in test environment)
+        assert!(
+            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
+            "Unexpected response: {} - {}",
+            status,
+            body_str
+        );
+
+        // Verify config was updated with proper FQDN (trailing dot)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"json.example.com. IN A 203.0.113.100\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_json_auto_detect_ip() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("autoip.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("autoip.example.com", "autoip-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let json_body = r#"{"domain":"autoip.example.com"}"#;
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer autoip-key")
+            .extension(ConnectInfo(
+                "198.51.100.99:54321".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(json_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Either succeeds or fails on unbound-control (expected in test environment)
+        assert!(
+            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
+            "Unexpected response: {} - {}",
+            status,
+            body_str
+        );
+
+        // Verify config was updated with client IP and proper FQDN (trailing dot)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"autoip.example.com. IN A 198.51.100.99\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_stream_endpoint() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+            ("three.example.com", "192.168.1.3"),
+        ]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("one.example.com", "shared-key"),
+                ("two.example.com", "shared-key"),
+                ("three.example.com", "other-key"),
+            ]),
+        );
+        config.trailing_dot = true;
+        let config = Arc::new(config);
+
+        let app = create_app(config);
+
+        let ndjson_body = concat!(
+            r#"{"domain":"one.example.com","ip":"203.0.113.1"}"#,
+            "\n",
+            r#"{"domain":"two.example.com"}"#,
+            "\n",
+            r#"{"domain":"three.example.com","ip":"203.0.113.3"}"#,
+            "\n",
+        );
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("content-type", "application/x-ndjson")
+            .header("authorization", "Bearer shared-key")
+            .extension(ConnectInfo(
+                "198.51.100.2:54321".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(ndjson_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/x-ndjson"
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        // Three per-update results followed by the single reload result
+        let results: Vec<&serde_json::Value> =
+            lines.iter().filter(|l| l.get("line").is_some()).collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["success"], true);
+        assert_eq!(results[1]["success"], true);
+        assert_eq!(results[2]["success"], false);
+        assert_eq!(results[2]["message"], "Unauthorized");
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3]["reload"], true);
+        assert_eq!(lines[4]["summary"]["failed"], 1);
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"one.example.com. IN A 203.0.113.1\""));
+        // Omitted IP falls back to the client's address
+        assert!(content.contains("local-data: \"two.example.com. IN A 198.51.100.2\""));
+        assert!(content.contains("local-data: \"three.example.com IN A 192.168.1.3\""));
+    }
+
+    #[test]
+    fn test_unbound_control_not_found_message() {
+        let not_found = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let message = unbound_control_exec_error(not_found);
+        assert!(message.contains("unbound-control not found on PATH"));
+        assert!(message.contains("reload_enabled = false"));
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert!(
+            unbound_control_exec_error(denied).starts_with("Failed to execute unbound-control")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_ignore_missing_unbound_control() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.trailing_dot = true;
+        config.ignore_missing_unbound_control = true;
+
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(|_| {
+            Err(unbound_control_exec_error(std::io::Error::from(
+                std::io::ErrorKind::NotFound,
+            )))
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The write still happened even though the reload was skipped
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.42\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_min_reload_interval() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.min_reload_interval_secs = Some(1);
+        config.verify_resolution = true;
+
+        let reloads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            recorded.lock().unwrap().push(Instant::now());
+            Ok(())
+        });
+        // Unbound keeps serving the first address until the deferred reload runs
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = queries.clone();
+        state.dns_resolver = Arc::new(move |_, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(vec!["203.0.113.1".parse().unwrap()]) })
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let request = |ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain=test.example.com&ip={}", ip)))
+                .unwrap()
+        };
+
+        // The first update reloads right away, the second one is deferred
+        let response = app.clone().oneshot(request("203.0.113.1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request("203.0.113.2")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("(reload pending)"), "{}", body_str);
+        assert_eq!(reloads.lock().unwrap().len(), 1);
+        // Only the update that was reloaded got verified
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        let reloads = reloads.lock().unwrap();
+        assert_eq!(reloads.len(), 2);
+        assert!(reloads[1] - reloads[0] >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_verify_resolution() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.verify_resolution = true;
+        config.verify_resolution_timeout_ms = 300;
+
+        // Unbound serves whatever the file held at the last reload
+        let served = Arc::new(Mutex::new("192.168.1.1".to_string()));
+        let mut state = AppState::new(Arc::new(config));
+        let unbound_path = unbound_file.path().to_path_buf();
+        let reloaded = served.clone();
+        state.reloader = Arc::new(move |_| {
+            let content = fs::read_to_string(&unbound_path).unwrap();
+            let ip = content.rsplit(" IN A ").next().unwrap();
+            *reloaded.lock().unwrap() = ip.trim_end_matches(['"', '\n']).to_string();
+            Ok(())
+        });
+        let queried = served.clone();
+        state.dns_resolver = Arc::new(move |domain, qtype| {
+            assert_eq!(domain, "test.example.com");
+            assert_eq!(qtype, QTYPE_A);
+            let ip = queried.lock().unwrap().parse().unwrap();
+            Box::pin(async move { Ok(vec![ip]) })
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(*served.lock().unwrap(), "203.0.113.42");
+
+        // A server stuck on the old value fails the update once the timeout passes
+        fs::write(
+            unbound_file.path(),
+            "local-data: \"test.example.com IN A 192.168.1.1\"\n",
+        )
+        .unwrap();
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.verbose_errors = true;
+        config.verify_resolution = true;
+        config.verify_resolution_timeout_ms = 300;
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(|_| Ok(()));
+        state.dns_resolver =
+            Arc::new(|_, _| Box::pin(async { Ok(vec!["192.168.1.1".parse().unwrap()]) }));
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let started = Instant::now();
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(
+            json["message"]
+                .as_str()
+                .unwrap()
+                .contains("did not resolve to 203.0.113.42")
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let mut response = build_query(0x1234, "home.example.com", QTYPE_A).unwrap();
+        response[2] |= 0x80; // QR: this is a response
+        response[7] = 2; // Two answers
+        for ip in [[203, 0, 113, 42], [203, 0, 113, 43]] {
+            // Name compressed to the question, type A, class IN, TTL 300, 4 bytes
+            response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4]);
+            response.extend_from_slice(&ip);
+        }
+
+        assert_eq!(
+            parse_response(&response, 0x1234).unwrap(),
+            vec![
+                "203.0.113.42".parse::<IpAddr>().unwrap(),
+                "203.0.113.43".parse().unwrap()
+            ]
+        );
+        assert!(parse_response(&response, 0x4321).is_err());
+        assert!(parse_response(&response[..response.len() - 2], 0x1234).is_err());
+
+        response[3] |= 0x03; // NXDOMAIN
+        assert!(
+            parse_response(&response, 0x1234)
+                .unwrap_err()
+                .contains("rcode 3")
+        );
+    }
+
+    #[test]
+    fn test_parse_response_aaaa() {
+        let mut response = build_query(0x1234, "home.example.com", QTYPE_AAAA).unwrap();
+        assert_eq!(response[response.len() - 4..], [0, 28, 0, 1]);
+        response[2] |= 0x80; // QR: this is a response
+        response[7] = 1; // One answer
+        // Name compressed to the question, type AAAA, class IN, TTL 300, 16 bytes
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 28, 0, 1, 0, 0, 1, 44, 0, 16]);
+        response.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+
+        assert_eq!(
+            parse_response(&response, 0x1234).unwrap(),
+            vec!["2001:db8::1".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_parse_txt_response() {
+        let mut response = build_query(0x1234, "_ddns.example.com", QTYPE_TXT).unwrap();
+        response[2] |= 0x80; // QR: this is a response
+        response[7] = 1; // One answer
+        // One record split into two strings
+        let rdata = b"\x09one.a.com\x0a two.a.com";
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 16, 0, 1, 0, 0, 1, 44, 0]);
+        response.push(rdata.len() as u8);
+        response.extend_from_slice(rdata);
+
+        assert_eq!(
+            parse_txt_response(&response, 0x1234).unwrap(),
+            vec!["one.a.com two.a.com".to_string()]
+        );
+
+        // A string running past the record is rejected
+        let last = response.len() - rdata.len();
+        response[last] = 0x30;
+        assert!(parse_txt_response(&response, 0x1234).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_stream_endpoint_summary() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("one.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("one.example.com", "shared-key")]),
+        );
+        config.reload_enabled = false;
+        let app = Router::new()
+            .route("/update/stream", post(update_stream_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        // The second line repeats the first, so it leaves the file as it is
+        let ndjson_body = concat!(
+            r#"{"domain":"one.example.com","ip":"203.0.113.1"}"#,
+            "\n",
+            r#"{"domain":"one.example.com","ip":"203.0.113.1"}"#,
+            "\n",
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("content-type", "application/x-ndjson")
+            .header("authorization", "Bearer shared-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(ndjson_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+
+        assert_eq!(lines[0]["changed"], true);
+        assert_eq!(lines[1]["changed"], false);
+        assert_eq!(lines[1]["message"], "No change, already 203.0.113.1");
+        assert_eq!(
+            lines.last().unwrap()["summary"],
+            serde_json::json!({"changed": 1, "unchanged": 1, "failed": 0})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_stream_endpoint_reloads_once() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+            ("three.example.com", "192.168.1.3"),
+        ]));
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("one.example.com", "shared-key"),
+                ("two.example.com", "shared-key"),
+                ("three.example.com", "shared-key"),
+            ]),
+        ));
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(config);
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let app = Router::new()
+            .route("/update/stream", post(update_stream_handler))
+            .with_state(state);
+
+        let ndjson_body = concat!(
+            r#"{"domain":"one.example.com","ip":"203.0.113.1"}"#,
+            "\n",
+            r#"{"domain":"two.example.com","ip":"203.0.113.2"}"#,
+            "\n",
+            r#"{"domain":"three.example.com","ip":"203.0.113.3"}"#,
+            "\n",
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("content-type", "application/x-ndjson")
+            .header("authorization", "Bearer shared-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(ndjson_body))
+            .unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Reloaded Unbound after 3 updates"));
+
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+        // Sending the same batch again changes nothing, so nothing is reloaded
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("content-type", "application/x-ndjson")
+            .header("authorization", "Bearer shared-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(ndjson_body))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!body_str.contains("\"reload\""));
+        assert!(body_str.contains(r#"{"summary":{"changed":0,"unchanged":3,"failed":0}}"#));
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_update_stream_endpoint_per_domain_reload_command() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+            ("three.example.com", "192.168.1.3"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("one.example.com", "shared-key"),
+                ("two.example.com", "shared-key"),
+                ("three.example.com", "shared-key"),
+            ]),
+        );
+        let command = vec!["systemctl".to_string(), "reload".to_string()];
+        config.domains[0].reload_command = Some(command.clone());
+        config.domains[2].reload_command = Some(command.clone());
+
+        let reloads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |command| {
+            recorded
+                .lock()
+                .unwrap()
+                .push(command.map(<[String]>::to_vec));
+            Ok(())
+        });
+        let app = Router::new()
+            .route("/update/stream", post(update_stream_handler))
+            .with_state(state);
+
+        let ndjson_body = concat!(
+            r#"{"domain":"one.example.com","ip":"203.0.113.1"}"#,
+            "\n",
+            r#"{"domain":"two.example.com","ip":"203.0.113.2"}"#,
+            "\n",
+            r#"{"domain":"three.example.com","ip":"203.0.113.3"}"#,
+            "\n",
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("content-type", "application/x-ndjson")
+            .header("authorization", "Bearer shared-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(ndjson_body))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        // The shared per-domain command runs once, and so does the default reload
+        assert_eq!(*reloads.lock().unwrap(), vec![Some(command), None]);
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_global_reload_command() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("one.example.com", "192.168.1.1"),
+            ("two.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("one.example.com", "key1"), ("two.example.com", "key2")]),
+        );
+        let global = vec![
+            "/usr/local/sbin/unbound-control".to_string(),
+            "-c".to_string(),
+            "/etc/unbound/unbound.conf".to_string(),
+            "reload".to_string(),
+        ];
+        let own = vec![
+            "systemctl".to_string(),
+            "reload".to_string(),
+            "unbound".to_string(),
+        ];
+        config.reload_command = Some(global.clone());
+        config.domains[1].reload_command = Some(own.clone());
+
+        let reloads = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |command| {
+            recorded
+                .lock()
+                .unwrap()
+                .push(command.map(<[String]>::to_vec));
+            Ok(())
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        for (domain, key) in [("one.example.com", "key1"), ("two.example.com", "key2")] {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain={}&ip=203.0.113.1", domain)))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        // The domain's own command wins over the global one
+        assert_eq!(*reloads.lock().unwrap(), vec![Some(global), Some(own)]);
+    }
+
+    #[test]
+    fn test_config_validation_empty_reload_command() {
+        let mut config = create_test_config(None, Some(&[("test.example.com", "key1")]));
+        config.reload_command = Some(Vec::new());
+        assert!(
+            config
+                .problems()
+                .iter()
+                .any(|p| p.field.as_deref() == Some("reload_command"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_stream_endpoint_invalid_line() {
+        use axum::http::Request;
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("one.example.com", "192.168.1.1")]));
+
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("one.example.com", "key")]),
+        ));
+
+        let app = create_app(config);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update/stream")
+            .header("authorization", "Bearer key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("not json\n\n"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+
+        // Blank lines are skipped and nothing was written, so there is no reload line,
+        // only the result and the summary
+        assert_eq!(body_str.lines().count(), 2);
+        assert!(body_str.contains("Invalid JSON"));
+        assert!(!body_str.contains("\"reload\""));
+        assert!(body_str.contains("\"failed\":1"));
+    }
+
+    #[test]
+    fn test_config_load() {
+        let unbound_file = create_unbound_config(Some(&[("example.com", "192.168.1.1")]));
+        let config_file = NamedTempFile::new().unwrap();
+
+        // Create config file
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+
+[[domains]]
+name = "example.com"
+key = "test-key"
+"#,
+            unbound_file.path().display()
+        );
+        fs::write(config_file.path(), config_content).unwrap();
+
+        // Test successful load
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_ok());
+        let config = result.unwrap();
+        assert_eq!(config.domains.len(), 1);
+        assert_eq!(config.domains[0].name, "example.com");
+    }
+
+    #[test]
+    fn test_init_unbound_config_appends_stubs() {
+        let unbound_file = create_unbound_config(Some(&[("existing.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("existing.example.com", "key1"),
+                ("home.example.com", "key2"),
+                ("vpn.example.com", "key3"),
+            ]),
+        );
+        config.trailing_dot = true;
+        config.domains[2].ttl = Some(60);
+
+        let added =
+            init_unbound_config(unbound_file.path(), &config.domains, config.entry_format())
+                .unwrap();
+        assert_eq!(added, vec!["home.example.com", "vpn.example.com"]);
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"existing.example.com IN A 192.168.1.1\""));
+        assert!(content.contains("  local-data: \"home.example.com. IN A 0.0.0.0\""));
+        assert!(content.contains("  local-data: \"vpn.example.com. 60 IN A 0.0.0.0\""));
+        assert!(config.validate().is_ok());
+
+        // Running again is a no-op
+        let added =
+            init_unbound_config(unbound_file.path(), &config.domains, config.entry_format())
+                .unwrap();
+        assert!(added.is_empty());
+        assert_eq!(fs::read_to_string(unbound_file.path()).unwrap(), content);
+    }
+
+    #[test]
+    fn test_init_unbound_config_creates_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let unbound_path = dir.path().join("unbound.conf");
+        let mut config = create_test_config(
+            Some(unbound_path.clone()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.trailing_dot = true;
+
+        init_unbound_config(&unbound_path, &config.domains, config.entry_format()).unwrap();
+
+        let content = fs::read_to_string(&unbound_path).unwrap();
+        assert_eq!(
+            content,
+            "server:\n  local-data: \"home.example.com. IN A 0.0.0.0\"\n"
+        );
+    }
+
+    #[test]
+    fn test_init_unbound_config_keeps_crlf_line_endings() {
+        let mut unbound_file = NamedTempFile::new().unwrap();
+        write!(
+            unbound_file,
+            "server:\r\n  local-data: \"existing.example.com IN A 192.168.1.1\"\r\n"
+        )
+        .unwrap();
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.trailing_dot = true;
+
+        init_unbound_config(unbound_file.path(), &config.domains, config.entry_format()).unwrap();
+
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(
+            content.ends_with("server:\r\n  local-data: \"home.example.com. IN A 0.0.0.0\"\r\n")
+        );
+        assert_eq!(
+            content.matches('\n').count(),
+            content.matches("\r\n").count()
+        );
+    }
+
+    #[test]
+    fn test_config_load_creates_missing_unbound_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let unbound_path = dir.path().join("unbound.conf");
+        let config_file = NamedTempFile::new().unwrap();
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+
+[[domains]]
+name = "example.com"
+key = "test-key"
+"#,
+            unbound_path.display()
+        );
+        fs::write(config_file.path(), &config_content).unwrap();
+
+        // Missing files are still an error by default
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(!unbound_path.exists());
+
+        fs::write(
+            config_file.path(),
+            format!("create_unbound_file_if_missing = true\n{}", config_content),
+        )
+        .unwrap();
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_ok(), "{:?}", result.err());
+        // Names are written without a trailing dot unless `trailing_dot` is set
+        assert_eq!(
+            fs::read_to_string(&unbound_path).unwrap(),
+            "server:\n  local-data: \"example.com IN A 0.0.0.0\"\n"
+        );
+    }
+
+    #[test]
+    fn test_config_restart_only_changes() {
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "1.2.3.4")]));
+        let config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1")]),
+        );
+
+        // Settings read per request reload fine
+        let mut reloaded = config.clone();
+        reloaded.domains[0].key = "key2".to_string();
+        reloaded.verify_resolution = true;
+        assert!(config.restart_only_changes(&reloaded).is_empty());
+
+        reloaded.metrics_enabled = !config.metrics_enabled;
+        reloaded.listen_addr = ListenAddr::Single("127.0.0.1:4000".to_string());
+        reloaded.state_path = Some(PathBuf::from("/var/lib/unbound_ddns/state.json"));
+        assert_eq!(
+            config.restart_only_changes(&reloaded),
+            ["listen_addr", "metrics_enabled", "state_path"]
+        );
+
+        // Only adding or removing the diagnostics token changes the routes
+        let mut with_token = config.clone();
+        with_token.diagnostics_token = Some("a".to_string());
+        assert_eq!(
+            config.restart_only_changes(&with_token),
+            ["diagnostics_token"]
+        );
+        let mut other_token = with_token.clone();
+        other_token.diagnostics_token = Some("b".to_string());
+        assert!(with_token.restart_only_changes(&other_token).is_empty());
+    }
+
+    #[test]
+    fn test_config_load_merges_keys_file() {
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let keys_file = NamedTempFile::new().unwrap();
+        fs::write(
+            keys_file.path(),
+            "\"home.example.com\" = \"home-key\"\n\"vpn.example.com.\" = \"vpn-key\"\n",
+        )
+        .unwrap();
+
+        let config_file = NamedTempFile::new().unwrap();
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+keys_file = "{}"
+
+[[domains]]
+name = "home.example.com"
+
+[[domains]]
+name = "vpn.example.com"
+"#,
+            unbound_file.path().display(),
+            keys_file.path().display()
+        );
+        fs::write(config_file.path(), &config_content).unwrap();
+
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.domains[0].key, "home-key");
+        assert_eq!(config.domains[1].key, "vpn-key");
+
+        // A domain left without a key from either source is rejected
+        fs::write(keys_file.path(), "\"home.example.com\" = \"home-key\"\n").unwrap();
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .contains("'vpn.example.com' has an empty key")
+        );
+
+        // Keys for unknown domains are a mistake
+        fs::write(keys_file.path(), "\"typo.example.com\" = \"key\"\n").unwrap();
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .contains("unknown domain 'typo.example.com'")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_domain_discovery_txt_adds_domains() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::Mutex;
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("new.example.com", "192.168.1.2"),
+        ]));
+        let keys_file = NamedTempFile::new().unwrap();
+        fs::write(
+            keys_file.path(),
+            "\"new.example.com\" = \"new-key\"\n\"other.example.com\" = \"other-key\"\n",
+        )
+        .unwrap();
+        let config_file = NamedTempFile::new().unwrap();
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+keys_file = "{}"
+domain_discovery_txt = "_ddns.example.com"
+reload_enabled = false
+
+[[domains]]
+name = "home.example.com"
+key = "home-key"
+"#,
+            unbound_file.path().display(),
+            keys_file.path().display()
+        );
+        fs::write(config_file.path(), &config_content).unwrap();
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.domains.len(), 1);
+
+        let answer = Arc::new(Mutex::new(vec![
+            "new.example.com, home.example.com".to_string(),
+            "unkeyed.example.com".to_string(),
+        ]));
+        let records = answer.clone();
+        let resolver: TxtResolver = Arc::new(move |name: &str| {
+            assert_eq!(name, "_ddns.example.com");
+            let texts = records.lock().unwrap().clone();
+            Box::pin(async move { Ok(texts) })
+        });
+
+        // Configured domains keep their settings and domains without a key are skipped
+        let live = Arc::new(ArcSwap::new(Arc::new(config)));
+        refresh_discovered_domains(&live, &resolver).await.unwrap();
+        let names: Vec<_> = live.load().domains.iter().map(|d| d.name.clone()).collect();
+        assert_eq!(names, ["home.example.com", "new.example.com"]);
+        assert_eq!(live.load().domains[0].key, "home-key");
+        assert_eq!(live.load().domains[1].key, "new-key");
+
+        let app = create_apps(live.clone()).0;
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/update")
+                    .header("authorization", "Bearer new-key")
+                    .extension(ConnectInfo(
+                        "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                    ))
+                    .body(Body::from("domain=new.example.com&ip=203.0.113.5"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            fs::read_to_string(unbound_file.path())
+                .unwrap()
+                .contains("new.example.com IN A 203.0.113.5")
+        );
+
+        // A later answer replaces the discovered domains
+        *answer.lock().unwrap() = vec!["other.example.com".to_string()];
+        refresh_discovered_domains(&live, &resolver).await.unwrap();
+        assert_eq!(live.load().discovered_domains(), ["other.example.com"]);
+
+        // A failed lookup keeps them
+        let failing: TxtResolver =
+            Arc::new(|_: &str| Box::pin(async { Err("timed out".to_string()) }));
+        assert!(refresh_discovered_domains(&live, &failing).await.is_err());
+        assert_eq!(live.load().discovered_domains(), ["other.example.com"]);
+    }
+
+    #[test]
+    fn test_config_load_rejects_key_in_both_sources() {
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "192.168.1.1")]));
+        let keys_file = NamedTempFile::new().unwrap();
+        fs::write(keys_file.path(), "\"home.example.com\" = \"other-key\"\n").unwrap();
+
+        let config_file = NamedTempFile::new().unwrap();
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+keys_file = "{}"
+
+[[domains]]
+name = "home.example.com"
+key = "home-key"
+"#,
+            unbound_file.path().display(),
+            keys_file.path().display()
+        );
+        fs::write(config_file.path(), config_content).unwrap();
+
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .contains("both the config and keys_file")
+        );
+    }
+
+    #[test]
+    fn test_config_load_file_not_found() {
+        let result = Config::load("/nonexistent/path/config.toml");
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Failed to read config file"));
+    }
+
+    #[test]
+    fn test_config_load_invalid_toml() {
+        let mut config_file = NamedTempFile::new().unwrap();
+        writeln!(config_file, "invalid toml {{{{").unwrap();
+
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Failed to parse config file"));
+    }
+
+    #[test]
+    fn test_extract_auth_key_with_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer my-secret-key".parse().unwrap());
+
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "my-secret-key");
+    }
+
+    #[test]
+    fn test_extract_auth_key_with_lowercase_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "bearer my-secret-key".parse().unwrap());
+
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "my-secret-key");
+    }
+
+    #[test]
+    fn test_extract_auth_key_with_uppercase_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "BEARER my-secret-key".parse().unwrap());
+
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "my-secret-key");
+    }
+
+    #[test]
+    fn test_extract_auth_key_without_bearer() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "my-secret-key".parse().unwrap());
+
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "my-secret-key");
+    }
+
+    #[test]
+    fn test_extract_auth_key_trims_whitespace() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer  my-secret-key \t".parse().unwrap());
+
+        assert_eq!(extract_auth_key(&headers, true).unwrap(), "my-secret-key");
+        assert_eq!(
+            extract_auth_key(&headers, false).unwrap(),
+            " my-secret-key \t"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_accepts_key_with_trailing_whitespace() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key ")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_extract_auth_key_missing() {
+        let headers = HeaderMap::new();
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Missing Authorization header"));
+    }
+
+    #[test]
+    fn test_extract_auth_key_empty() {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer ".parse().unwrap());
+
+        let result = extract_auth_key(&headers, true);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("cannot be empty"));
+    }
+
+    #[test]
+    fn test_update_response_success() {
+        let response = UpdateResponse {
+            success: true,
+            message: "Updated successfully".to_string(),
+            details: None,
+            record_type: None,
+        };
+        let axum_response = response.into_response();
+        assert_eq!(axum_response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_update_response_failure() {
+        let response = UpdateResponse {
+            success: false,
+            message: "Update failed".to_string(),
+            details: None,
+            record_type: None,
+        };
+        let axum_response = response.into_response();
+        assert_eq!(axum_response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parse_update_request_invalid_json() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        let body = Bytes::from("invalid json {{{");
+
+        let result = parse_update_request(&headers, &body);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid JSON"));
+    }
+
+    #[test]
+    fn test_parse_update_request_invalid_utf8() {
+        let headers = HeaderMap::new();
+        let body = Bytes::from(vec![0xFF, 0xFE, 0xFD]);
+
+        let result = parse_update_request(&headers, &body);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid UTF-8"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_missing_auth_header() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=test.example.com"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Missing Authorization header"));
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_invalid_json() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("invalid json {{{"))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Failed to parse request"));
+    }
+
+    #[test]
+    fn test_create_app() {
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let app = create_app(config);
+        // Just verify the router is created successfully
+        // The actual route testing is done in other tests
+        assert!(format!("{:?}", app).contains("Router"));
+    }
+
+    #[test]
+    fn test_check_free_space_below_threshold() {
+        let result = check_free_space(1024, 4096);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Insufficient disk space"));
+    }
+
+    #[test]
+    fn test_check_free_space_at_or_above_threshold() {
+        assert!(check_free_space(4096, 4096).is_ok());
+        assert!(check_free_space(u64::MAX, 4096).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_insufficient_storage() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        // No filesystem has this much free space
+        config.min_free_bytes = Some(u64::MAX);
+
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.42"}"#,
+            ))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        // The write must have been skipped
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("192.168.1.1"));
+        assert!(!content.contains("203.0.113.42"));
+    }
+
+    #[test]
+    fn test_parse_records_multiple_types() {
+        let content = "server:\n\
+            local-data: \"dual.example.com. IN A 203.0.113.1\"\n\
+            local-data: \"dual.example.com. 300 IN AAAA 2001:db8::1\"\n\
+            local-data: \"other.example.com. IN A 203.0.113.2\"\n";
+
+        let records = parse_records(content, "dual.example.com");
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    record_type: "A".to_string(),
+                    value: "203.0.113.1".to_string(),
+                    ttl: None,
+                },
+                Record {
+                    record_type: "AAAA".to_string(),
+                    value: "2001:db8::1".to_string(),
+                    ttl: Some(300),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_records_unknown_domain() {
+        let content = "local-data: \"home.example.com. IN A 203.0.113.1\"";
+        assert!(parse_records(content, "missing.example.com").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_current_endpoint_returns_all_records() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let mut unbound_file = create_unbound_config(Some(&[("dual.example.com", "203.0.113.1")]));
+        writeln!(
+            unbound_file,
+            "local-data: \"dual.example.com. IN AAAA 2001:db8::1\""
+        )
+        .unwrap();
+
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("dual.example.com", "dual-key")]),
+        ));
+
+        let app = create_app(config);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/current?domain=dual.example.com")
+            .header("authorization", "Bearer dual-key")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let records = json["records"].as_array().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["record_type"], "A");
+        assert_eq!(records[0]["value"], "203.0.113.1");
+        assert_eq!(records[1]["record_type"], "AAAA");
+        assert_eq!(records[1]["value"], "2001:db8::1");
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoint_custom_path() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let mut config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        config.health_path = "/healthz".to_string();
+        let app = create_app(Arc::new(config));
+
+        let request = |path: &str| Request::builder().uri(path).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request("/healthz")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request("/health")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_config_validation_health_path() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.health_path = "/status.html".to_string();
+        assert!(config.validate().is_ok());
+
+        for path in ["healthz", "/update"] {
+            config.health_path = path.to_string();
+            assert!(config.validate().unwrap_err().contains("health_path"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_current_endpoint_cache_control() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "203.0.113.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        let current = || {
+            Request::builder()
+                .uri("/current?domain=test.example.com")
+                .header("authorization", "Bearer test-key")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = create_app(Arc::new(config.clone()))
+            .oneshot(current())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+
+        config.cache_control = "private, max-age=10".to_string();
+        let response = create_app(Arc::new(config.clone()))
+            .oneshot(current())
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["cache-control"], "private, max-age=10");
+
+        config.cache_control = String::new();
+        let response = create_app(Arc::new(config))
+            .oneshot(current())
+            .await
+            .unwrap();
+        assert!(response.headers().get("cache-control").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_current_endpoint_invalid_key() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "203.0.113.1")]));
+
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "correct-key")]),
+        ));
+
+        let app = create_app(config);
+
+        let request = Request::builder()
+            .method("GET")
+            .uri("/current?domain=test.example.com")
+            .header("authorization", "Bearer wrong-key")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Unauthorized"));
+        assert!(!body_str.contains("203.0.113.1"));
+    }
+
+    #[test]
+    fn test_update_rate_tracker_enforces_limit() {
+        let tracker = UpdateRateTracker::default();
+        let start = Instant::now();
+
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i);
+            assert!(
+                tracker
+                    .check_and_record("a.example.com", Some(3), now)
+                    .is_ok()
+            );
+        }
+
+        // The fourth update within the minute hits the ceiling
+        let result =
+            tracker.check_and_record("a.example.com", Some(3), start + Duration::from_secs(10));
+        assert!(result.unwrap_err().contains("Rate limit exceeded"));
+        assert_eq!(
+            tracker.rate("a.example.com", start + Duration::from_secs(10)),
+            3
+        );
+
+        // The limit lifts when the first update leaves the window
+        assert_eq!(
+            tracker.retry_after("a.example.com", Some(3), start + Duration::from_secs(10)),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(
+            tracker.retry_after("a.example.com", None, start + Duration::from_secs(10)),
+            None
+        );
+
+        // Other domains are counted independently
+        assert!(
+            tracker
+                .check_and_record("b.example.com", Some(3), start)
+                .is_ok()
+        );
+
+        // Once the oldest update leaves the window, updates are accepted again
+        let later = start + Duration::from_secs(60);
+        assert!(
+            tracker
+                .check_and_record("a.example.com", Some(3), later)
+                .is_ok()
+        );
+        assert_eq!(tracker.rate("a.example.com", later), 3);
+    }
+
+    #[test]
+    fn test_update_rate_tracker_without_limit() {
+        let tracker = UpdateRateTracker::default();
+        let start = Instant::now();
+
+        for _ in 0..10 {
+            assert!(
+                tracker
+                    .check_and_record("a.example.com", None, start)
+                    .is_ok()
+            );
+        }
+        assert_eq!(tracker.rate("a.example.com", start), 10);
+        assert_eq!(tracker.rate("a.example.com", start + RATE_WINDOW), 0);
+        assert_eq!(tracker.rate("unknown.example.com", start), 0);
+    }
+
+    #[tokio::test]
+    async fn test_per_domain_rate_limit() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("normal.example.com", "192.168.1.1"),
+            ("failover.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("normal.example.com", "shared-key"),
+                ("failover.example.com", "shared-key"),
+            ]),
+        );
+        config.max_updates_per_minute = Some(1);
+        config.domains[1].rate_limit = Some(3);
+        config.reload_enabled = false;
+
+        let app = create_app(Arc::new(config));
+        let update = |domain: &str, ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer shared-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!("domain={}&ip={}", domain, ip)))
+                .unwrap()
+        };
+
+        let statuses = |domain: &'static str| {
+            let app = app.clone();
+            async move {
+                let mut statuses = Vec::new();
+                for i in 1..=4 {
+                    let ip = format!("203.0.113.{}", i);
+                    let response = app.clone().oneshot(update(domain, &ip)).await.unwrap();
+                    statuses.push(response.status());
+                }
+                statuses
+            }
+        };
+
+        // The global limit applies to the normal domain, the override to the failover one
+        assert_eq!(
+            statuses("normal.example.com").await,
+            [
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+        assert_eq!(
+            statuses("failover.example.com").await,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_updates_do_not_count_towards_rate_limit() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.max_updates_per_minute = Some(1);
+        config.max_request_ttl = Some(3600);
+        config.reload_enabled = false;
+
+        let app = create_app(Arc::new(config));
+        let update = |body: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        for body in [
+            "domain=test.example.com&ip=not-an-ip",
+            "domain=test.example.com&ip=203.0.113.1&ttl=86400",
+        ] {
+            let response = app.clone().oneshot(update(body)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // The malformed requests above left the whole budget for this one
+        let response = app
+            .clone()
+            .oneshot(update("domain=test.example.com&ip=203.0.113.1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(update("domain=test.example.com&ip=203.0.113.2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_group_and_patch_send_retry_after() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "home-key"),
+                ("vpn.example.com", "vpn-key"),
+            ]),
+        );
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+            ],
+        }];
+        config.max_updates_per_minute = Some(1);
+        config.reload_enabled = false;
+        let app = create_app(Arc::new(config));
+
+        let group_update = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer group-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=wan&ip=203.0.113.1"))
+                .unwrap()
+        };
+        let patch = || {
+            Request::builder()
+                .method("PATCH")
+                .uri("/domains/home.example.com")
+                .header("authorization", "Bearer home-key")
+                .body(Body::from(r#"{"ttl":600}"#))
+                .unwrap()
+        };
+        let retry_after = |response: &Response| -> u64 {
+            response.headers()["retry-after"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(group_update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.clone().oneshot(group_update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!((1..=60).contains(&retry_after(&response)));
+
+        let response = app.oneshot(patch()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!((1..=60).contains(&retry_after(&response)));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_metrics_share_counters() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("busy.example.com", "192.168.1.1"),
+            ("idle.example.com", "192.168.1.2"),
+        ]));
+
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("busy.example.com", "busy-key"),
+                ("idle.example.com", "idle-key"),
+            ]),
+        );
+        config.max_updates_per_minute = Some(2);
+        config.metrics_enabled = true;
+        config.reload_enabled = false;
+
+        let app = create_app(Arc::new(config));
+
+        let update = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer busy-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=busy.example.com&ip=203.0.113.1"))
+                .unwrap()
+        };
+
+        let first = app.clone().oneshot(update()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let second = app.clone().oneshot(update()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        let third = app.clone().oneshot(update()).await.unwrap();
+        assert_eq!(third.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = third.headers()["retry-after"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after), "{}", retry_after);
+
+        let metrics = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(metrics.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(metrics.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("# TYPE ddns_domain_update_rate gauge"));
+        assert!(body_str.contains("ddns_domain_update_rate{domain=\"busy.example.com\"} 2"));
+        assert!(body_str.contains("ddns_domain_update_rate{domain=\"idle.example.com\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_disabled_by_default() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let response = create_app(config)
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_events_stream_reports_updates() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.diagnostics_token = Some("admin-token".to_string());
+        config.events_enabled = true;
+        let app = create_app(Arc::new(config));
+
+        let subscribe = |token: &str| {
+            Request::builder()
+                .uri("/events")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Domain keys don't grant access
+        let response = app.clone().oneshot(subscribe("test-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.clone().oneshot(subscribe("admin-token")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut events = response.into_body().into_data_stream();
+
+        let update = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
+            ))
+            .unwrap();
+        let response = app.oneshot(update).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let frame = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .expect("no event received")
+            .unwrap()
+            .unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(frame.starts_with("event: update\n"), "{}", frame);
+        let data = frame
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(event["domain"], "test.example.com");
+        assert_eq!(event["ip"], "203.0.113.1");
+        assert_eq!(event["success"], true);
+        assert!(event["time"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_events_cover_every_write_path_and_late_failures() {
+        use axum::http::Request;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key"), ("vpn.example.com", "key")]),
+        );
+        config.diagnostics_token = Some("admin-token".to_string());
+        config.events_enabled = true;
+        config.verify_resolution = true;
+        config.verify_resolution_timeout_ms = 300;
+        config.verbose_errors = true;
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+            ],
+        }];
+
+        // Unbound answers from the file, unless it is stuck on an old address
+        let stale = Arc::new(AtomicBool::new(false));
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(|_| Ok(()));
+        let unbound_path = unbound_file.path().to_path_buf();
+        let stuck = stale.clone();
+        state.dns_resolver = Arc::new(move |domain, _| {
+            let ips = if stuck.load(Ordering::SeqCst) {
+                vec!["192.0.2.1".parse().unwrap()]
+            } else {
+                let content = fs::read_to_string(&unbound_path).unwrap();
+                parse_records(&content, domain)
+                    .iter()
+                    .filter_map(|record| record.value.parse().ok())
+                    .collect()
+            };
+            Box::pin(async move { Ok(ips) })
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .route("/update/stream", post(update_stream_handler))
+            .route("/domains/{name}", patch(patch_domain_handler))
+            .route("/events", get(events_handler))
+            .with_state(state);
+
+        let request = |method: &str, uri: &str, key: &str, body: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(request("GET", "/events", "admin-token", ""))
+            .await
+            .unwrap();
+        let mut frames = response.into_body().into_data_stream();
+
+        for (method, uri, key, body) in [
+            ("POST", "/update", "group-key", "domain=wan&ip=203.0.113.1"),
+            (
+                "PATCH",
+                "/domains/home.example.com",
+                "key",
+                r#"{"ip":"203.0.113.2"}"#,
+            ),
+            (
+                "POST",
+                "/update/stream",
+                "key",
+                "{\"domain\":\"vpn.example.com\",\"ip\":\"203.0.113.3\"}\n",
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(request(method, uri, key, body))
+                .await
+                .unwrap();
+            axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+        }
+        stale.store(true, Ordering::SeqCst);
+        let response = app
+            .oneshot(request(
+                "POST",
+                "/update",
+                "key",
+                "domain=home.example.com&ip=203.0.113.4",
+            ))
+            .await
+            .unwrap();
+        assert!(!response.status().is_success());
+
+        let mut events = Vec::new();
+        while events.len() < 6 {
+            let frame = tokio::time::timeout(Duration::from_secs(5), frames.next())
+                .await
+                .expect("no event received")
+                .unwrap()
+                .unwrap();
+            let frame = String::from_utf8(frame.to_vec()).unwrap();
+            for data in frame.lines().filter_map(|line| line.strip_prefix("data: ")) {
+                events.push(serde_json::from_str::<serde_json::Value>(data).unwrap());
+            }
+        }
+        let outcomes: Vec<(&str, &str, bool)> = events
+            .iter()
+            .map(|event| {
+                (
+                    event["domain"].as_str().unwrap(),
+                    event["ip"].as_str().unwrap(),
+                    event["success"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("home.example.com", "203.0.113.1", true),
+                ("vpn.example.com", "203.0.113.1", true),
+                ("home.example.com", "203.0.113.2", true),
+                ("vpn.example.com", "203.0.113.3", true),
+                ("home.example.com", "203.0.113.4", true),
+                ("home.example.com", "203.0.113.4", false),
+            ]
+        );
+        assert!(
+            events[5]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Failed to verify resolution")
+        );
+    }
+
+    #[test]
+    fn test_config_validation_events_require_token() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.events_enabled = true;
+        let result = config.validate();
+        assert!(result.unwrap_err().contains("events_enabled"));
+
+        config.diagnostics_token = Some("admin-token".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_swapped_config_applies_to_new_requests() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("test.example.com", "192.168.1.1"),
+            ("new.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        let live = Arc::new(ArcSwap::from_pointee(config));
+        let (app, _) = create_apps(Arc::clone(&live));
+
+        let request = |domain: &str, key: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", format!("Bearer {}", key))
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain":"{}","ip":"203.0.113.1"}}"#,
+                    domain
+                )))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("new.example.com", "new-key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Adding the domain takes effect without rebuilding the router
+        let mut reloaded = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("test.example.com", "test-key"),
+                ("new.example.com", "new-key"),
+            ]),
+        );
+        reloaded.reload_enabled = false;
+        live.store(Arc::new(reloaded));
+        assert!(live.load().find_domain("new.example.com").is_some());
+
+        let response = app
+            .clone()
+            .oneshot(request("new.example.com", "new-key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(request("test.example.com", "test-key"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_admin_listen_addr_separates_routes() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        async fn status_line(addr: SocketAddr, request: &[u8]) -> String {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response.lines().next().unwrap_or_default().to_string()
+        }
+
+        let mut config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        config.metrics_enabled = true;
+        config.admin_listen_addr = Some("127.0.0.1:0".to_string());
+        let (app, admin_app) = create_apps(Arc::new(ArcSwap::new(Arc::new(config))));
+
+        let listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let admin_listeners = bind_listeners(&["127.0.0.1:0"]).await.unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let admin_addr = admin_listeners[0].local_addr().unwrap();
+        let server = tokio::spawn(serve(listeners, app, ServeOptions::default()).unwrap().1);
+        let admin_server = tokio::spawn(
+            serve(admin_listeners, admin_app.unwrap(), ServeOptions::default())
+                .unwrap()
+                .1,
+        );
+
+        let update = b"POST /update HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let metrics = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+        assert_eq!(status_line(addr, update).await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(status_line(addr, metrics).await, "HTTP/1.1 404 Not Found");
+        assert_eq!(
+            status_line(admin_addr, update).await,
+            "HTTP/1.1 404 Not Found"
+        );
+        assert_eq!(status_line(admin_addr, metrics).await, "HTTP/1.1 200 OK");
+
+        server.abort();
+        admin_server.abort();
+    }
+
+    #[test]
+    fn test_config_validation_invalid_admin_listen_addr() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.admin_listen_addr = Some("localhost".to_string());
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("Invalid admin_listen_addr"));
+    }
+
+    #[tokio::test]
+    async fn test_export_endpoint() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let mut unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "1.2.3.4"),
+            ("vpn.example.com", "5.6.7.8"),
+            ("other.example.com", "9.9.9.9"),
+        ]));
+        writeln!(
+            unbound_file,
+            "  local-data: \"unmanaged.example.com IN A 10.0.0.1\""
+        )
+        .unwrap();
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "shared-key"),
+                ("vpn.example.com", "shared-key"),
+                ("other.example.com", "other-key"),
+            ]),
+        ));
+        let app = create_app(config);
+
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/export")
+                .header("authorization", format!("Bearer {}", key))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("shared-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            "local-data: \"home.example.com IN A 1.2.3.4\"\n\
+             local-data: \"vpn.example.com IN A 5.6.7.8\"\n"
+        );
+
+        let response = app.oneshot(request("wrong-key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_report() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1"), ("vpn.example.com", "key2")]),
+        );
+        config.diagnostics_token = Some("diag-token".to_string());
+        let app = create_app(Arc::new(config));
+
+        let request = |token: &str| {
+            Request::builder()
+                .uri("/diagnostics")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Domain keys don't grant access
+        let response = app.clone().oneshot(request("key1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(request("diag-token")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(
+            json["unbound_config"]["path"],
+            unbound_file.path().to_str().unwrap()
+        );
+        assert_eq!(json["unbound_config"]["readable"], true);
+        assert_eq!(json["unbound_config"]["writable"], true);
+        // Depends on whether Unbound is installed where the tests run
+        assert!(json["unbound_control"]["reachable"].is_boolean());
+        assert!(json["unbound_control"]["message"].is_string());
+        assert_eq!(json["domain_count"], 2);
+        assert_eq!(
+            json["domains"],
+            serde_json::json!([
+                {"name": "home.example.com", "has_entry": true},
+                {"name": "vpn.example.com", "has_entry": false},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_info_endpoint() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1"), ("vpn.example.com", "key2")]),
+        );
+        config.diagnostics_token = Some("diag-token".to_string());
+        config.reload_enabled = false;
+        let app = create_app(Arc::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("authorization", "Bearer key1")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from("domain=home.example.com&ip=203.0.113.1"))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let info = |token: &str| {
+            Request::builder()
+                .uri("/config-info")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.clone().oneshot(info("key1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(info("diag-token")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let metadata = fs::metadata(unbound_file.path()).unwrap();
+        let modified = metadata
+            .modified()
+            .unwrap()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert_eq!(json["unbound_config"]["modified"], modified);
+        assert_eq!(json["unbound_config"]["size"], metadata.len());
+        assert_eq!(json["domain_count"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_validate_endpoint_lists_problems() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.diagnostics_token = Some("diag-token".to_string());
+        let app = create_app(Arc::new(config));
+
+        let request = |token: &str, body: String| {
+            Request::builder()
+                .method("POST")
+                .uri("/validate")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let validate = |body: String| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request("diag-token", body)).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+            }
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("key1", String::new()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let path = unbound_file.path().display();
+        let json = validate(format!(
+            r#"
+            unbound_config_path = "{path}"
+
+            [[domains]]
+            name = "home.example.com"
+            key = ""
+
+            [[domains]]
+            name = "vpn.example.com"
+            key = "key2"
+
+            [[domains]]
+            name = "home.example.com"
+            key = "key3"
+            "#
+        ))
+        .await;
+        assert_eq!(json["valid"], false);
+        let fields: Vec<&str> = json["problems"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            ["domains[0].key", "domains[2].name", "domains[1].name"]
+        );
+        let messages = json["problems"].to_string();
+        assert!(messages.contains("Domain 'home.example.com' has an empty key"));
+        assert!(messages.contains("Duplicate domain 'home.example.com'"));
+        assert!(messages.contains("Domain 'vpn.example.com' not found in Unbound config file"));
+
+        let json = validate(format!(
+            "unbound_config_path = \"{path}\"\n[[domains]]\nname = \"home.example.com\"\nkey = \"k\"\n"
+        ))
+        .await;
+        assert_eq!(json, serde_json::json!({"valid": true, "problems": []}));
+
+        // A config that doesn't parse is reported as a single problem without a field
+        let json = validate("domains = 3".to_string()).await;
+        assert_eq!(json["valid"], false);
+        assert!(json["problems"][0].get("field").is_none());
+        assert!(
+            json["problems"][0]["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Failed to parse config file")
+        );
+
+        // Files the running config doesn't use are never read
+        let secret = NamedTempFile::new().unwrap();
+        fs::write(secret.path(), "root:x:0:0:secret\n").unwrap();
+        let secret_path = secret.path().display();
+        let json = validate(format!(
+            r#"
+            unbound_config_path = "{secret_path}"
+            keys_file = "{secret_path}"
+
+            [[domains]]
+            name = "home.example.com"
+
+            [[domains]]
+            name = "vpn.example.com"
+            config_path = "{path}"
+            "#
+        ))
+        .await;
+        assert_eq!(json["valid"], false);
+        let fields: Vec<&str> = json["problems"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["field"].as_str().unwrap())
+            .collect();
+        assert_eq!(fields, ["keys_file", "unbound_config_path"]);
+        assert!(!json.to_string().contains("secret"));
+
+        let json = validate(format!(
+            "unbound_config_path = \"{path}\"\n[[domains]]\nname = \"home.example.com\"\nkey = \"k\"\nconfig_path = \"{secret_path}\"\n"
+        ))
+        .await;
+        assert_eq!(json["problems"][0]["field"], "domains[0].config_path");
+        assert!(!json.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_unbound_version() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("home.example.com", "1.2.3.4")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1")]),
+        );
+        config.diagnostics_token = Some("diag-token".to_string());
+        config.diagnostics_unbound_version = true;
+
+        let mut state = AppState::new(Arc::new(config));
+        state.version_probe = Arc::new(|| {
+            parse_unbound_version("Version 1.19.0\n\nConfigure line: --prefix=/usr\n")
+                .ok_or_else(|| "no version".to_string())
+        });
+        let app = Router::new()
+            .route("/diagnostics", get(diagnostics_handler))
+            .with_state(state);
+
+        let request = Request::builder()
+            .uri("/diagnostics")
+            .header("authorization", "Bearer diag-token")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json["unbound_version"],
+            serde_json::json!({"version": "1.19.0"})
+        );
+    }
+
+    #[test]
+    fn test_parse_unbound_version() {
+        assert_eq!(
+            parse_unbound_version("Version 1.17.1\n\nConfigure line: --with-libevent\n"),
+            Some("1.17.1".to_string())
+        );
+        assert_eq!(parse_unbound_version("usage: unbound [options]\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_disabled_by_default() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let response = create_app(config)
+            .oneshot(
+                Request::builder()
+                    .uri("/diagnostics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_status_page() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "203.0.113.42"),
+            ("vpn.example.com", "203.0.113.43"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("home.example.com", "key1"), ("vpn.example.com", "key2")]),
+        );
+        config.status_page_enabled = true;
+
+        let response = create_app(Arc::new(config))
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            response.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("text/html")
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let html = String::from_utf8(body.to_vec()).unwrap();
+        assert!(html.contains("<td>home.example.com</td><td>203.0.113.42</td>"));
+        assert!(html.contains("<td>vpn.example.com</td><td>203.0.113.43</td>"));
+        assert!(html.contains("Status: OK"));
+        // Keys are never shown
+        assert!(!html.contains("key1"));
+    }
+
+    #[tokio::test]
+    async fn test_access_log_combined_format() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let mut config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        config.access_log_format = AccessLogFormat::Combined;
+        let app = create_app(Arc::new(config));
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("user-agent", "ddclient/3.11")
+            .extension(ConnectInfo(
+                "198.51.100.7:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::empty())
+            .unwrap();
+
+        let (_guard, logs) = capture_logs();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|l| l.contains("\"POST /update HTTP/1.1\""))
+            .expect("no access log line");
+        assert!(line.contains("198.51.100.7 - - ["), "{}", line);
+        assert!(line.contains("\"POST /update HTTP/1.1\" 401 "), "{}", line);
+        assert!(line.contains("\"-\" \"ddclient/3.11\""), "{}", line);
+        assert!(line.trim_end().ends_with("ms"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn test_access_log_follows_live_config() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let mut config = create_test_config(None, Some(&[("test.example.com", "test-key")]));
+        let live = Arc::new(ArcSwap::from_pointee(config.clone()));
+        let app = create_apps(live.clone()).0;
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("x-forwarded-for", "203.0.113.9")
+                .header("x-proxy-token", "proxy-secret")
+                .extension(ConnectInfo(
+                    "198.51.100.7:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The default is the structured tracing line
+        let (_guard, logs) = capture_logs();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let output = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|l| l.contains("Request handled"))
+            .expect("no access log line");
+        assert!(line.contains("method=POST"), "{}", line);
+        assert!(line.contains("path=/update"), "{}", line);
+        assert!(line.contains("status=401"), "{}", line);
+        assert!(line.contains("client=203.0.113.9"), "{}", line);
+        logs.lock().unwrap().clear();
+
+        // A reloaded config changes the format and the trusted proxy of the same router
+        config.access_log_format = AccessLogFormat::Combined;
+        config.proxy_token = Some("other-secret".to_string());
+        live.store(Arc::new(config));
+        app.oneshot(request()).await.unwrap();
+        let output = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|l| l.contains("\"POST /update HTTP/1.1\""))
+            .expect("no access log line");
+        assert!(line.contains("198.51.100.7 - - ["), "{}", line);
+    }
+
+    #[test]
+    fn test_format_clf_time() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(format_clf_time(at(0)), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(
+            format_clf_time(at(971_186_136)),
+            "10/Oct/2000:13:55:36 +0000"
+        );
+        // Leap day
+        assert_eq!(
+            format_clf_time(at(1_709_164_800)),
+            "29/Feb/2024:00:00:00 +0000"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_ip_endpoint_caches_resolver_result() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver_lookups = lookups.clone();
+        let resolver: ServerIpResolver = Arc::new(move || {
+            resolver_lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok("203.0.113.200".parse().unwrap()) })
+        });
+
+        let mut state = AppState::new(config);
+        state.server_ip = Arc::new(ServerIpCache::new(resolver, Duration::from_secs(300)));
+
+        let app = Router::new()
+            .route("/server-ip", get(server_ip_handler))
+            .with_state(state);
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/server-ip")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["ip"], "203.0.113.200");
+        }
+
+        // The second request was served from the cache
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_server_ip_cache_expires() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver_lookups = lookups.clone();
+        let resolver: ServerIpResolver = Arc::new(move || {
+            resolver_lookups.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok("203.0.113.200".parse().unwrap()) })
+        });
+
+        let cache = ServerIpCache::new(resolver, Duration::ZERO);
+        cache.get().await.unwrap();
+        cache.get().await.unwrap();
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_server_ip_endpoint_disabled_by_default() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let config = Arc::new(create_test_config(
+            None,
+            Some(&[("test.example.com", "test-key")]),
+        ));
+
+        let response = create_app(config)
+            .oneshot(
+                Request::builder()
+                    .uri("/server-ip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_public_ip_from_echo_service() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let echo = Router::new().route("/ip", get(|| async { "203.0.113.77\n" }));
+        let server = tokio::spawn(async move { axum::serve(listener, echo).await });
+
+        let ip = fetch_public_ip(&format!("http://{}/ip", addr))
+            .await
+            .unwrap();
+        assert_eq!(ip, "203.0.113.77".parse::<IpAddr>().unwrap());
+
+        let result = fetch_public_ip(&format!("https://{}/ip", addr)).await;
+        assert!(result.unwrap_err().contains("must use http://"));
+
+        server.abort();
+    }
+
+    #[test]
+    fn test_print_config_info() {
+        let config = create_test_config(
+            None,
+            Some(&[("example1.com", "key1"), ("example2.com", "key2")]),
+        );
+
+        // Just ensure it doesn't panic - we can't easily test stdout
+        print_config_info(&config);
+    }
+
+    #[test]
+    fn test_extract_client_ip_from_x_forwarded_for_single() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    #[test]
+    fn test_extract_client_ip_from_x_forwarded_for_multiple() {
+        let mut headers = HeaderMap::new();
+        // Client IP is the leftmost one
+        headers.insert(
+            "x-forwarded-for",
+            "203.0.113.1, 198.51.100.1, 192.0.2.1".parse().unwrap(),
+        );
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    #[test]
+    fn test_extract_client_ip_from_x_real_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-real-ip", "203.0.113.42".parse().unwrap());
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        assert_eq!(ip, "203.0.113.42");
+    }
+
+    #[test]
+    fn test_extract_client_ip_x_forwarded_for_takes_precedence() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        headers.insert("x-real-ip", "203.0.113.2".parse().unwrap());
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        // X-Forwarded-For should take precedence
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    #[test]
+    fn test_extract_client_ip_fallback_to_connection() {
+        let headers = HeaderMap::new();
+        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        assert_eq!(ip, "198.51.100.99");
+    }
+
+    #[test]
+    fn test_extract_client_ip_empty_x_forwarded_for() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "".parse().unwrap());
+        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        // Should fall back to connection IP
+        assert_eq!(ip, "198.51.100.99");
+    }
+
+    #[test]
+    fn test_extract_client_ip_with_spaces() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "  203.0.113.1  ".parse().unwrap());
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, None);
+        // Should trim whitespace
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    #[test]
+    fn test_extract_client_ip_proxy_token_valid() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        headers.insert("x-proxy-token", "proxy-secret".parse().unwrap());
+        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, Some("proxy-secret"));
+        // Trusted proxy: forwarded IP is honored
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    #[test]
+    fn test_extract_client_ip_proxy_token_missing() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        headers.insert("x-real-ip", "203.0.113.2".parse().unwrap());
+        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, Some("proxy-secret"));
+        // Spoofed headers without the token are ignored
+        assert_eq!(ip, "198.51.100.99");
+    }
+
+    #[test]
+    fn test_extract_client_ip_proxy_token_invalid() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
+        headers.insert("x-proxy-token", "wrong-secret".parse().unwrap());
+        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
+
+        let ip = extract_client_ip(&headers, &addr, Some("proxy-secret"));
+        assert_eq!(ip, "198.51.100.99");
+    }
+
+    #[test]
+    fn test_config_validation_empty_proxy_token() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "key1")]),
+        );
+        config.proxy_token = Some("  ".to_string());
+
+        let result = config.validate();
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("proxy_token"));
+    }
 
     // ============================================================================
-    // TESTS
+    // TRAILING DOT NORMALIZATION TESTS
     // ============================================================================
 
     #[test]
-    fn test_config_parsing() {
-        let unbound_file = create_unbound_config(Some(&[
-            ("home.example.com", "192.168.1.1"),
-            ("server.example.com", "192.168.1.2"),
-        ]));
-
-        let toml_content = format!(
-            r#"
-unbound_config_path = "{}"
-
-[[domains]]
-name = "home.example.com"
-key = "secret-key-1"
-
-[[domains]]
-name = "server.example.com"
-key = "secret-key-2"
-"#,
-            unbound_file.path().display()
-        );
+    fn test_normalize_domain_with_trailing_dot() {
+        assert_eq!(normalize_domain("example.com."), "example.com");
+        assert_eq!(normalize_domain("foo.example.com."), "foo.example.com");
+    }
 
-        let config: Config = toml::from_str(&toml_content).unwrap();
-        config.validate().unwrap();
-        assert_eq!(config.unbound_config_path, unbound_file.path());
-        assert_eq!(config.domains.len(), 2);
-        assert_eq!(config.domains[0].name, "home.example.com");
-        assert_eq!(config.domains[0].key, "secret-key-1");
+    #[test]
+    fn test_normalize_domain_without_trailing_dot() {
+        assert_eq!(normalize_domain("example.com"), "example.com");
+        assert_eq!(normalize_domain("foo.example.com"), "foo.example.com");
     }
 
     #[test]
-    fn test_config_validation_no_domains() {
-        let config = create_test_config(None, None);
-        let result = config.validate();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("at least one domain"));
+    fn test_normalize_domain_multiple_trailing_dots() {
+        assert_eq!(normalize_domain("example.com.."), "example.com");
+        assert_eq!(normalize_domain("example.com..."), "example.com");
     }
 
     #[test]
-    fn test_config_validation_empty_domain_name() {
-        let config = create_test_config(None, Some(&[("", "key1")]));
-        let result = config.validate();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("empty name"));
+    fn test_domain_exists_in_config_with_trailing_dot() {
+        let config = "local-data: \"example.com. IN A 192.168.1.1\"";
+        assert!(domain_exists_in_config(config, "example.com"));
     }
 
     #[test]
-    fn test_config_validation_empty_key() {
-        let config = create_test_config(None, Some(&[("test.example.com", "")]));
-        let result = config.validate();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("empty key"));
+    fn test_domain_exists_in_config_without_trailing_dot() {
+        let config = "local-data: \"example.com IN A 192.168.1.1\"";
+        assert!(domain_exists_in_config(config, "example.com"));
     }
 
     #[test]
-    fn test_config_validation_duplicate_domains() {
-        let config = create_test_config(
-            None,
-            Some(&[("test.example.com", "key1"), ("test.example.com", "key2")]),
-        );
-        let result = config.validate();
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Duplicate domain"));
+    fn test_domain_exists_in_config_ignores_commented_entries() {
+        let config = "# local-data: \"example.com. IN A 192.168.1.1\"\n\
+                      ;local-data: \"example.com. IN A 192.168.1.1\"";
+        assert!(!domain_exists_in_config(config, "example.com"));
     }
 
     #[test]
-    fn test_find_domain() {
-        let config = create_test_config(
+    fn test_update_unbound_config_skips_commented_entry() {
+        let mut unbound_file = create_unbound_config(None);
+        writeln!(
+            unbound_file,
+            "# local-data: \"test.example.com. IN A 192.168.1.1\""
+        )
+        .unwrap();
+        writeln!(
+            unbound_file,
+            "    local-data: \"test.example.com. IN A 192.168.1.2\""
+        )
+        .unwrap();
+
+        update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
             None,
-            Some(&[("home.example.com", "key1"), ("server.example.com", "key2")]),
-        );
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
 
-        assert!(config.find_domain("home.example.com").is_some());
-        assert!(config.find_domain("nonexistent.com").is_none());
+        // The commented entry is preserved and the live entry keeps its indentation
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("# local-data: \"test.example.com. IN A 192.168.1.1\"\n"));
+        assert!(content.contains("\n    local-data: \"test.example.com. IN A 10.0.0.1\"\n"));
+        assert!(!content.contains("192.168.1.2"));
     }
 
     #[test]
-    fn test_config_validation_domain_not_in_unbound_config() {
-        let unbound_file = create_unbound_config(None);
+    fn test_update_unbound_config_preserves_adjacent_comments() {
+        let store = MemoryStore::new(concat!(
+            "server:\n",
+            "  # Home router, kept current by unbound_ddns\n",
+            "  local-data: \"a.example.com IN A 192.168.1.1\"\n",
+            "  # Static entry below, do not touch\n",
+            "  local-data: \"b.example.com IN A 192.168.1.2\"\n",
+        ));
 
-        let config = create_test_config(
-            Some(unbound_file.path().to_path_buf()),
-            Some(&[("missing.example.com", "key1")]),
-        );
+        // Growing to two entries and shrinking back must not eat the neighbouring lines
+        for ips in [
+            &["10.0.0.1"][..],
+            &["10.0.0.2", "10.0.0.3"],
+            &["10.0.0.4"],
+            &["10.0.0.4"],
+        ] {
+            update_unbound_config(
+                &store,
+                "a.example.com",
+                ips,
+                None,
+                fqdn_format(),
+                false,
+                false,
+            )
+            .unwrap();
+        }
 
-        let result = config.validate();
-        assert!(result.is_err());
-        let error_msg = result.unwrap_err();
-        assert!(error_msg.contains("missing.example.com"));
-        assert!(error_msg.contains("not found in Unbound config"));
+        assert_eq!(
+            store.content(),
+            concat!(
+                "server:\n",
+                "  # Home router, kept current by unbound_ddns\n",
+                "  local-data: \"a.example.com. IN A 10.0.0.4\"\n",
+                "  # Static entry below, do not touch\n",
+                "  local-data: \"b.example.com IN A 192.168.1.2\"\n",
+            )
+        );
     }
 
     #[test]
-    fn test_update_unbound_config_nonexistent_domain() {
-        let unbound_file = create_unbound_config(None);
+    fn test_update_unbound_config_only_commented_entry() {
+        let mut unbound_file = create_unbound_config(None);
+        writeln!(
+            unbound_file,
+            "# local-data: \"test.example.com. IN A 192.168.1.1\""
+        )
+        .unwrap();
 
-        // Try to update non-existent domain - should fail
         let result = update_unbound_config(
             &unbound_file.path().to_path_buf(),
             "test.example.com",
-            "192.168.1.1",
+            &["10.0.0.1"],
+            None,
+            EntryFormat::default(),
+            false,
+            false,
         );
-        assert!(result.is_err());
         assert!(result.unwrap_err().contains("not found in Unbound config"));
     }
 
     #[test]
-    fn test_update_unbound_config_replace_entry() {
+    fn test_config_load_normalizes_trailing_dots() {
         let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let config_file = NamedTempFile::new().unwrap();
 
-        // Update existing entry
-        update_unbound_config(
-            &unbound_file.path().to_path_buf(),
-            "test.example.com",
-            "10.0.0.1",
-        )
-        .unwrap();
+        // Create config with trailing dots
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
 
-        // Verify - now writes with trailing dot (proper FQDN)
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
-        assert!(!content.contains("192.168.1.1"));
+[[domains]]
+name = "test.example.com."
+key = "test-key"
+"#,
+            unbound_file.path().display()
+        );
+        fs::write(config_file.path(), config_content).unwrap();
+
+        // Load config and verify trailing dot is removed
+        let result = Config::load(config_file.path().to_str().unwrap());
+        assert!(result.is_ok());
+        let config = result.unwrap();
+        assert_eq!(config.domains[0].name, "test.example.com");
     }
 
-    // ============================================================================
-    // INTEGRATION TESTS - DO NOT REMOVE
-    // These tests verify the actual HTTP endpoint behavior with form data
-    // ============================================================================
+    #[test]
+    fn test_normalize_request_domain_idn() {
+        let mut config = create_test_config(None, Some(&[("xn--caf-dma.example.com", "key")]));
+
+        // Disabled by default: Unicode names are only stripped of the trailing dot
+        assert_eq!(
+            config.normalize_request_domain("café.example.com."),
+            "café.example.com"
+        );
+
+        config.normalize_idn = true;
+        assert_eq!(
+            config.normalize_request_domain("café.example.com."),
+            "xn--caf-dma.example.com"
+        );
+        assert_eq!(
+            config.normalize_request_domain("xn--caf-dma.example.com"),
+            "xn--caf-dma.example.com"
+        );
+    }
+
+    #[test]
+    fn test_config_load_converts_idn_domains() {
+        let unbound_file =
+            create_unbound_config(Some(&[("xn--caf-dma.example.com", "192.168.1.1")]));
+        let config_file = NamedTempFile::new().unwrap();
+
+        let config_content = format!(
+            r#"unbound_config_path = "{}"
+normalize_idn = true
+
+[[domains]]
+name = "café.example.com."
+key = "test-key"
+"#,
+            unbound_file.path().display()
+        );
+        fs::write(config_file.path(), config_content).unwrap();
+
+        let config = Config::load(config_file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.domains[0].name, "xn--caf-dma.example.com");
+    }
 
     #[tokio::test]
-    async fn test_update_endpoint_unauthorized_domain() {
-        use axum::body::Body;
+    async fn test_update_endpoint_unicode_domain_maps_to_punycode() {
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let config = Arc::new(create_test_config(
-            None,
-            Some(&[("allowed.example.com", "secret123")]),
-        ));
+        let unbound_file =
+            create_unbound_config(Some(&[("xn--caf-dma.example.com", "192.168.1.1")]));
 
-        let app = Router::new()
-            .route("/update", post(update_handler))
-            .with_state(config);
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("xn--caf-dma.example.com", "cafe-key")]),
+        );
+        config.trailing_dot = true;
+        config.normalize_idn = true;
+        config.reload_enabled = false;
+
+        let app = create_app(Arc::new(config));
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
-            .header("content-type", "application/x-www-form-urlencoded")
-            .header("authorization", "Bearer secret123")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer cafe-key")
             .extension(ConnectInfo(
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from("domain=notallowed.example.com"))
+            .body(Body::from(
+                r#"{"domain":"café.example.com","ip":"203.0.113.8"}"#,
+            ))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Unauthorized"));
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"xn--caf-dma.example.com. IN A 203.0.113.8\""));
     }
 
-    #[tokio::test]
-    async fn test_update_endpoint_invalid_key() {
-        use axum::body::Body;
-        use axum::http::{Request, StatusCode};
-        use tower::ServiceExt;
+    #[test]
+    fn test_update_unbound_config_writes_trailing_dot() {
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
 
-        let config = Arc::new(create_test_config(
+        // Update with normalized domain (no trailing dot)
+        update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
             None,
-            Some(&[("test.example.com", "correct-key")]),
-        ));
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
 
-        let app = Router::new()
-            .route("/update", post(update_handler))
-            .with_state(config);
+        // Verify the written config has trailing dot (proper FQDN)
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+    }
 
-        let request = Request::builder()
-            .method("POST")
-            .uri("/update")
-            .header("content-type", "application/x-www-form-urlencoded")
-            .header("authorization", "Bearer wrong-key")
-            .extension(ConnectInfo(
-                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            ))
-            .body(Body::from("domain=test.example.com&ip=10.0.0.1"))
-            .unwrap();
+    #[test]
+    fn test_update_unbound_config_handles_existing_trailing_dot() {
+        // Create config with trailing dot
+        let unbound_file = create_unbound_config(Some(&[("test.example.com.", "192.168.1.1")]));
 
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Update should work even though existing config has trailing dot
+        update_unbound_config(
+            &unbound_file.path().to_path_buf(),
+            "test.example.com",
+            &["10.0.0.1"],
+            None,
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Unauthorized"));
+        // Verify the updated config has trailing dot
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_with_explicit_ip() {
+    async fn test_update_endpoint_with_trailing_dot_in_domain() {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let unbound_file = create_unbound_config(Some(&[("trailing.example.com", "192.168.1.1")]));
 
-        let config = Arc::new(create_test_config(
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("test.example.com", "test-key")]),
-        ));
+            Some(&[("trailing.example.com", "trailing-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
 
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(config));
 
+        // Send request with trailing dot in domain name
         let request = Request::builder()
             .method("POST")
             .uri("/update")
-            .header("content-type", "application/x-www-form-urlencoded")
-            .header("authorization", "Bearer test-key")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer trailing-key")
             .extension(ConnectInfo(
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from("domain=test.example.com&ip=203.0.113.42"))
+            .body(Body::from(
+                r#"{"domain":"trailing.example.com.","ip":"203.0.113.99"}"#,
+            ))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
 
-        // Note: This will fail to reload unbound, but that's expected in test
-        // We're mainly testing the request parsing and validation
         let status = response.status();
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
@@ -750,35 +12988,39 @@ key = "secret-key-2"
 
         // Verify config was updated with proper FQDN (trailing dot)
         let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"test.example.com. IN A 203.0.113.42\""));
+        assert!(content.contains("local-data: \"trailing.example.com. IN A 203.0.113.99\""));
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_auto_detect_ip() {
+    async fn test_update_endpoint_with_x_forwarded_for() {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("auto.example.com", "192.168.1.1")]));
+        let unbound_file = create_unbound_config(Some(&[("proxy.example.com", "192.168.1.1")]));
 
-        let config = Arc::new(create_test_config(
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("auto.example.com", "auto-key")]),
-        ));
+            Some(&[("proxy.example.com", "proxy-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
 
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(config));
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
-            .header("content-type", "application/x-www-form-urlencoded")
-            .header("authorization", "Bearer auto-key")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer proxy-key")
+            .header("x-forwarded-for", "203.0.113.50")
             .extension(ConnectInfo(
-                "198.51.100.42:54321".parse::<SocketAddr>().unwrap(),
+                "192.168.1.100:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from("domain=auto.example.com"))
+            .body(Body::from(r#"{"domain":"proxy.example.com"}"#))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
@@ -797,39 +13039,42 @@ key = "secret-key-2"
             body_str
         );
 
-        // Verify config was updated with client IP and proper FQDN (trailing dot)
+        // Verify config was updated with the IP from X-Forwarded-For, not the connection IP
         let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"auto.example.com. IN A 198.51.100.42\""));
+        assert!(content.contains("local-data: \"proxy.example.com. IN A 203.0.113.50\""));
+        assert!(!content.contains("192.168.1.100")); // Should NOT use the proxy IP
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_json_with_explicit_ip() {
+    async fn test_update_endpoint_with_x_real_ip() {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("json.example.com", "192.168.1.1")]));
+        let unbound_file = create_unbound_config(Some(&[("realip.example.com", "192.168.1.1")]));
 
-        let config = Arc::new(create_test_config(
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("json.example.com", "json-key")]),
-        ));
+            Some(&[("realip.example.com", "realip-key")]),
+        );
+        config.trailing_dot = true;
+        config.verbose_errors = true;
+        let config = Arc::new(config);
 
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
-
-        let json_body = r#"{"domain":"json.example.com","ip":"203.0.113.100"}"#;
+            .with_state(AppState::new(config));
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
             .header("content-type", "application/json")
-            .header("authorization", "Bearer json-key")
+            .header("authorization", "Bearer realip-key")
+            .header("x-real-ip", "203.0.113.75")
             .extension(ConnectInfo(
-                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                "192.168.1.100:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from(json_body))
+            .body(Body::from(r#"{"domain":"realip.example.com"}"#))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
@@ -848,206 +13093,319 @@ key = "secret-key-2"
             body_str
         );
 
-        // Verify config was updated with proper FQDN (trailing dot)
+        // Verify config was updated with the IP from X-Real-IP and proper FQDN (trailing dot)
         let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"json.example.com. IN A 203.0.113.100\""));
+        assert!(content.contains("local-data: \"realip.example.com. IN A 203.0.113.75\""));
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_json_auto_detect_ip() {
+    async fn test_update_endpoint_invalid_ip_address() {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("autoip.example.com", "192.168.1.1")]));
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
 
         let config = Arc::new(create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("autoip.example.com", "autoip-key")]),
+            Some(&[("test.example.com", "test-key")]),
         ));
 
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
-
-        let json_body = r#"{"domain":"autoip.example.com"}"#;
+            .with_state(AppState::new(config));
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
             .header("content-type", "application/json")
-            .header("authorization", "Bearer autoip-key")
+            .header("authorization", "Bearer test-key")
             .extension(ConnectInfo(
-                "198.51.100.99:54321".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"not-an-ip-address"}"#,
             ))
-            .body(Body::from(json_body))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        let status = response.status();
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
         let body_str = String::from_utf8(body.to_vec()).unwrap();
-
-        // Either succeeds or fails on unbound-control (expected in test environment)
-        assert!(
-            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
-            "Unexpected response: {} - {}",
-            status,
-            body_str
-        );
-
-        // Verify config was updated with client IP and proper FQDN (trailing dot)
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"autoip.example.com. IN A 198.51.100.99\""));
+        assert!(body_str.contains("Invalid IP address"));
     }
 
-    #[test]
-    fn test_config_load() {
-        let unbound_file = create_unbound_config(Some(&[("example.com", "192.168.1.1")]));
-        let config_file = NamedTempFile::new().unwrap();
+    #[tokio::test]
+    async fn test_update_endpoint_malformed_ipv4_address() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
 
-        // Create config file
-        let config_content = format!(
-            r#"unbound_config_path = "{}"
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
 
-[[domains]]
-name = "example.com"
-key = "test-key"
-"#,
-            unbound_file.path().display()
-        );
-        fs::write(config_file.path(), config_content).unwrap();
+        let config = Arc::new(create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        ));
 
-        // Test successful load
-        let result = Config::load(config_file.path().to_str().unwrap());
-        assert!(result.is_ok());
-        let config = result.unwrap();
-        assert_eq!(config.domains.len(), 1);
-        assert_eq!(config.domains[0].name, "example.com");
-    }
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(config));
 
-    #[test]
-    fn test_config_load_file_not_found() {
-        let result = Config::load("/nonexistent/path/config.toml");
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Failed to read config file"));
-    }
+        let request = Request::builder()
+            .method("POST")
+            .uri("/update")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
+            .extension(ConnectInfo(
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"256.1.2.3"}"#,
+            ))
+            .unwrap();
 
-    #[test]
-    fn test_config_load_invalid_toml() {
-        let mut config_file = NamedTempFile::new().unwrap();
-        writeln!(config_file, "invalid toml {{{{").unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        let result = Config::load(config_file.path().to_str().unwrap());
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Failed to parse config file"));
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body_str.contains("Invalid IP address"));
     }
 
-    #[test]
-    fn test_extract_auth_key_with_bearer() {
-        let mut headers = HeaderMap::new();
-        headers.insert("authorization", "Bearer my-secret-key".parse().unwrap());
+    #[tokio::test]
+    async fn test_update_endpoint_ipv6_writes_aaaa() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
 
-        let result = extract_auth_key(&headers);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "my-secret-key");
-    }
+        let unbound_file = create_unbound_config(Some(&[("ipv6.example.com", "192.168.1.1")]));
 
-    #[test]
-    fn test_extract_auth_key_without_bearer() {
-        let mut headers = HeaderMap::new();
-        headers.insert("authorization", "my-secret-key".parse().unwrap());
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("ipv6.example.com", "ipv6-key")]),
+        );
+        config.trailing_dot = true;
+        config.reload_enabled = false;
 
-        let result = extract_auth_key(&headers);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "my-secret-key");
-    }
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let update = |body: &'static str, client: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer ipv6-key")
+                .extension(ConnectInfo(client.parse::<SocketAddr>().unwrap()))
+                .body(Body::from(body))
+                .unwrap()
+        };
 
-    #[test]
-    fn test_extract_auth_key_missing() {
-        let headers = HeaderMap::new();
-        let result = extract_auth_key(&headers);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Missing Authorization header"));
+        // The AAAA entry is added next to the existing A entry
+        let response = app
+            .clone()
+            .oneshot(update(
+                r#"{"domain":"ipv6.example.com","ip":"2001:db8::1"}"#,
+                "127.0.0.1:12345",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"ipv6.example.com IN A 192.168.1.1\""));
+        assert!(content.contains("local-data: \"ipv6.example.com. IN AAAA 2001:db8::1\""));
+
+        // Each family is then updated on its own, including an auto-detected v6 address
+        let response = app
+            .clone()
+            .oneshot(update(
+                r#"{"domain":"ipv6.example.com"}"#,
+                "[2001:db8::2]:12345",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(update(
+                r#"{"domain":"ipv6.example.com","ipv4":"203.0.113.1"}"#,
+                "127.0.0.1:12345",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("local-data: \"ipv6.example.com. IN A 203.0.113.1\""));
+        assert!(content.contains("local-data: \"ipv6.example.com. IN AAAA 2001:db8::2\""));
+        assert_eq!(content.matches("ipv6.example.com").count(), 2);
     }
 
     #[test]
-    fn test_extract_auth_key_empty() {
-        let mut headers = HeaderMap::new();
-        headers.insert("authorization", "Bearer ".parse().unwrap());
+    fn test_update_unbound_config_exclusive_family() {
+        let unbound_file = NamedTempFile::new().unwrap();
+        fs::write(
+            unbound_file.path(),
+            "server:\n    local-data: \"dual.example.com. IN A 192.168.1.1\"\n    local-data: \"other.example.com. IN A 192.168.1.2\"\n",
+        )
+        .unwrap();
+        let path = unbound_file.path().to_path_buf();
 
-        let result = extract_auth_key(&headers);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("cannot be empty"));
-    }
+        update_unbound_config(
+            &path,
+            "dual.example.com",
+            &["2001:db8::1"],
+            None,
+            fqdn_format(),
+            false,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server:\n    local-data: \"dual.example.com. IN AAAA 2001:db8::1\"\n    local-data: \"other.example.com. IN A 192.168.1.2\"\n"
+        );
 
-    #[test]
-    fn test_update_response_success() {
-        let response = UpdateResponse {
-            success: true,
-            message: "Updated successfully".to_string(),
-        };
-        let axum_response = response.into_response();
-        assert_eq!(axum_response.status(), StatusCode::OK);
+        // And back: the A record replaces the AAAA record
+        update_unbound_config(
+            &path,
+            "dual.example.com",
+            &["203.0.113.1"],
+            None,
+            fqdn_format(),
+            false,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server:\n    local-data: \"dual.example.com. IN A 203.0.113.1\"\n    local-data: \"other.example.com. IN A 192.168.1.2\"\n"
+        );
     }
 
     #[test]
-    fn test_update_response_failure() {
-        let response = UpdateResponse {
-            success: false,
-            message: "Update failed".to_string(),
-        };
-        let axum_response = response.into_response();
-        assert_eq!(axum_response.status(), StatusCode::BAD_REQUEST);
+    fn test_update_unbound_config_adds_aaaa_after_a() {
+        let unbound_file = NamedTempFile::new().unwrap();
+        fs::write(
+            unbound_file.path(),
+            "server:\n    local-data: \"dual.example.com. IN A 192.168.1.1\"\n    local-data: \"other.example.com. IN A 192.168.1.2\"\n",
+        )
+        .unwrap();
+        let path = unbound_file.path().to_path_buf();
+
+        let changed = update_unbound_config(
+            &path,
+            "dual.example.com",
+            &["2001:db8::1"],
+            Some(300),
+            fqdn_format(),
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(changed);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "server:\n    local-data: \"dual.example.com. IN A 192.168.1.1\"\n    local-data: \"dual.example.com. 300 IN AAAA 2001:db8::1\"\n    local-data: \"other.example.com. IN A 192.168.1.2\"\n"
+        );
     }
 
-    #[test]
-    fn test_parse_update_request_invalid_json() {
-        let mut headers = HeaderMap::new();
-        headers.insert("content-type", "application/json".parse().unwrap());
-        let body = Bytes::from("invalid json {{{");
+    #[tokio::test]
+    async fn test_update_endpoint_ip_version() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
 
-        let result = parse_update_request(&headers, &body);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Invalid JSON"));
-    }
+        let unbound_file = create_unbound_config(Some(&[
+            ("v4.example.com", "192.168.1.1"),
+            ("v6.example.com", "192.168.1.2"),
+            ("any.example.com", "192.168.1.3"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("v4.example.com", "key"),
+                ("v6.example.com", "key"),
+                ("any.example.com", "key"),
+            ]),
+        );
+        config.reload_enabled = false;
+        config.domains[0].ip_version = IpVersion::V4;
+        config.domains[1].ip_version = IpVersion::V6;
 
-    #[test]
-    fn test_parse_update_request_invalid_utf8() {
-        let headers = HeaderMap::new();
-        let body = Bytes::from(vec![0xFF, 0xFE, 0xFD]);
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        // Sends the update with no explicit IP, so the connection address is used
+        let update = |domain: &str, client: &str| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer key")
+                .extension(ConnectInfo(client.parse::<SocketAddr>().unwrap()))
+                .body(Body::from(format!("domain={}", domain)))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
 
-        let result = parse_update_request(&headers, &body);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("Invalid UTF-8"));
+        let (status, _) = update("v4.example.com", "203.0.113.1:1234").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = update("v4.example.com", "[2001:db8::1]:1234").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("v4.example.com only accepts IPv4 addresses"));
+
+        let (status, body) = update("v6.example.com", "203.0.113.2:1234").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("v6.example.com only accepts IPv6 addresses"));
+        let (status, _) = update("v6.example.com", "[2001:db8::2]:1234").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = update("any.example.com", "203.0.113.3:1234").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = update("any.example.com", "[2001:db8::3]:1234").await;
+        assert_eq!(status, StatusCode::OK);
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_missing_auth_header() {
+    async fn test_update_endpoint_empty_ip_address() {
         use axum::body::Body;
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+
         let config = Arc::new(create_test_config(
-            None,
+            Some(unbound_file.path().to_path_buf()),
             Some(&[("test.example.com", "test-key")]),
         ));
 
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(config));
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
-            .header("content-type", "application/x-www-form-urlencoded")
+            .header("content-type", "application/json")
+            .header("authorization", "Bearer test-key")
             .extension(ConnectInfo(
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from("domain=test.example.com"))
+            .body(Body::from(r#"{"domain":"test.example.com","ip":""}"#))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
@@ -1057,23 +13415,107 @@ key = "test-key"
             .await
             .unwrap();
         let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Missing Authorization header"));
+        assert!(body_str.contains("Invalid IP address"));
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_invalid_json() {
-        use axum::body::Body;
+    async fn test_update_endpoint_startup_delay() {
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let config = Arc::new(create_test_config(
-            None,
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
             Some(&[("test.example.com", "test-key")]),
-        ));
+        );
+        config.reload_enabled = false;
+        config.startup_delay_secs = 30;
+
+        let mut state = AppState::new(Arc::new(config));
+        let now = Arc::new(Mutex::new(state.ready_at - Duration::from_secs(10)));
+        let clock_now = now.clone();
+        state.clock = Arc::new(move || *clock_now.lock().unwrap());
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .route("/health", get(health_handler))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), warmup))
+            .with_state(state.clone());
+
+        let update = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.1"))
+                .unwrap()
+        };
+        let health = || {
+            Request::builder()
+                .uri("/health")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "10");
+        let response = app.clone().oneshot(health()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("192.168.1.1"));
 
+        *now.lock().unwrap() = state.ready_at;
+        let response = app.clone().oneshot(health()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(update()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("203.0.113.1"));
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(
+            parse_ip("203.0.113.1").unwrap(),
+            "203.0.113.1".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_ip("2001:db8::1").unwrap(),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        for invalid in [
+            "",
+            " ",
+            "1.2.3",
+            "256.1.1.1",
+            "1.2.3.4\"",
+            "1.2.3.4\" IN A 5.6.7.8",
+        ] {
+            assert_eq!(
+                parse_ip(invalid).unwrap_err(),
+                format!("Invalid IP address: {}", invalid)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_endpoint_ip_with_quote_leaves_config_untouched() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let original = fs::read_to_string(unbound_file.path()).unwrap();
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(Arc::new(config)));
 
         let request = Request::builder()
             .method("POST")
@@ -1083,382 +13525,700 @@ key = "test-key"
             .extension(ConnectInfo(
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
-            .body(Body::from("invalid json {{{"))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"1.2.3.4\"\nlocal-zone: \"evil\""}"#,
+            ))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Failed to parse request"));
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["success"], false);
+        assert!(
+            json["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Invalid IP address")
+        );
+        assert_eq!(fs::read_to_string(unbound_file.path()).unwrap(), original);
     }
 
     #[test]
-    fn test_create_app() {
-        let config = Arc::new(create_test_config(
-            None,
-            Some(&[("test.example.com", "test-key")]),
-        ));
-
-        let app = create_app(config);
-        // Just verify the router is created successfully
-        // The actual route testing is done in other tests
-        assert!(format!("{:?}", app).contains("Router"));
+    fn test_record_type_for() {
+        assert_eq!(record_type_for("203.0.113.1"), "A");
+        assert_eq!(record_type_for("2001:db8::1"), "AAAA");
     }
 
-    #[test]
-    fn test_print_config_info() {
-        let config = create_test_config(
-            None,
-            Some(&[("example1.com", "key1"), ("example2.com", "key2")]),
-        );
+    #[tokio::test]
+    async fn test_update_response_record_type() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
 
-        // Just ensure it doesn't panic - we can't easily test stdout
-        print_config_info(&config);
-    }
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(AppState::new(Arc::new(config)));
+
+        let request = |body: &'static str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(body))
+                .unwrap()
+        };
 
-    #[test]
-    fn test_extract_client_ip_from_x_forwarded_for_single() {
-        let mut headers = HeaderMap::new();
-        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
-        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+        let response = app
+            .clone()
+            .oneshot(request(
+                r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["record_type"], "A");
 
-        let ip = extract_client_ip(&headers, &addr);
-        assert_eq!(ip, "203.0.113.1");
+        let response = app
+            .oneshot(request(
+                r#"{"domain":"test.example.com","ip":"2001:db8::1"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["record_type"], "AAAA");
     }
 
-    #[test]
-    fn test_extract_client_ip_from_x_forwarded_for_multiple() {
-        let mut headers = HeaderMap::new();
-        // Client IP is the leftmost one
-        headers.insert(
-            "x-forwarded-for",
-            "203.0.113.1, 198.51.100.1, 192.0.2.1".parse().unwrap(),
-        );
-        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+    #[tokio::test]
+    async fn test_update_endpoint_query_parameters() {
+        use axum::http::{Request, StatusCode};
+        use tower::ServiceExt;
 
-        let ip = extract_client_ip(&headers, &addr);
-        assert_eq!(ip, "203.0.113.1");
-    }
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        let app = create_app(Arc::new(config));
+
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "198.51.100.7:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
 
-    #[test]
-    fn test_extract_client_ip_from_x_real_ip() {
-        let mut headers = HeaderMap::new();
-        headers.insert("x-real-ip", "203.0.113.42".parse().unwrap());
-        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+        let response = app
+            .clone()
+            .oneshot(request("/update?domain=test.example.com&ip=203.0.113.5"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("203.0.113.5"), "{}", content);
 
-        let ip = extract_client_ip(&headers, &addr);
-        assert_eq!(ip, "203.0.113.42");
-    }
+        // Without `ip`, the client address is used
+        let response = app
+            .clone()
+            .oneshot(request("/update?domain=test.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("198.51.100.7"), "{}", content);
 
-    #[test]
-    fn test_extract_client_ip_x_forwarded_for_takes_precedence() {
-        let mut headers = HeaderMap::new();
-        headers.insert("x-forwarded-for", "203.0.113.1".parse().unwrap());
-        headers.insert("x-real-ip", "203.0.113.2".parse().unwrap());
-        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
+        let response = app
+            .clone()
+            .oneshot(request("/update?ip=203.0.113.5"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(
+            json["message"]
+                .as_str()
+                .unwrap()
+                .starts_with("Failed to parse request"),
+            "{}",
+            json
+        );
 
-        let ip = extract_client_ip(&headers, &addr);
-        // X-Forwarded-For should take precedence
-        assert_eq!(ip, "203.0.113.1");
+        // The key is checked just like for POST
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/update?domain=test.example.com&ip=203.0.113.9")
+                    .header("authorization", "Bearer wrong-key")
+                    .extension(ConnectInfo(
+                        "198.51.100.7:12345".parse::<SocketAddr>().unwrap(),
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(!content.contains("203.0.113.9"), "{}", content);
     }
 
     #[test]
-    fn test_extract_client_ip_fallback_to_connection() {
-        let headers = HeaderMap::new();
-        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
-
-        let ip = extract_client_ip(&headers, &addr);
-        assert_eq!(ip, "198.51.100.99");
-    }
+    fn test_sort_managed_entries_converges() {
+        let initial = "server:\n  # home\n  local-data: \"b.example.com. IN A 10.0.0.2\"\n  local-data: \"other.example.org. IN A 10.9.9.9\"\n  local-data: \"a.example.com. IN AAAA 2001:db8::1\"\n\n  # office\n  local-data: \"c.example.com. IN A 10.0.0.3\"\n  local-data: \"a.example.com. IN A 10.0.0.1\"\n";
+        let mut config = create_test_config(
+            None,
+            Some(&[
+                ("a.example.com", "key"),
+                ("b.example.com", "key"),
+                ("c.example.com", "key"),
+            ]),
+        );
+        config.trailing_dot = true;
+
+        let apply = |store: &MemoryStore, updates: &[(&str, &str)]| {
+            let sorted = SortedStore::new(store, &config);
+            for (domain, ip) in updates {
+                update_unbound_config(
+                    &sorted,
+                    domain,
+                    &[ip],
+                    None,
+                    config.entry_format(),
+                    false,
+                    false,
+                )
+                .unwrap();
+            }
+            store.content()
+        };
 
-    #[test]
-    fn test_extract_client_ip_empty_x_forwarded_for() {
-        let mut headers = HeaderMap::new();
-        headers.insert("x-forwarded-for", "".parse().unwrap());
-        let addr: SocketAddr = "198.51.100.99:54321".parse().unwrap();
+        let first = MemoryStore::new(initial);
+        let second = MemoryStore::new(initial);
+        let first = apply(
+            &first,
+            &[
+                ("c.example.com", "10.0.0.30"),
+                ("a.example.com", "10.0.0.10"),
+            ],
+        );
+        let second = apply(
+            &second,
+            &[
+                ("a.example.com", "10.0.0.11"),
+                ("a.example.com", "10.0.0.10"),
+                ("c.example.com", "10.0.0.30"),
+            ],
+        );
+        assert_eq!(first, second);
 
-        let ip = extract_client_ip(&headers, &addr);
-        // Should fall back to connection IP
-        assert_eq!(ip, "198.51.100.99");
+        // Managed entries are sorted into the slots they held; the rest stays in place
+        assert_eq!(
+            first,
+            "server:\n  # home\n  local-data: \"a.example.com. IN A 10.0.0.10\"\n  local-data: \"other.example.org. IN A 10.9.9.9\"\n  local-data: \"a.example.com. IN AAAA 2001:db8::1\"\n\n  # office\n  local-data: \"b.example.com. IN A 10.0.0.2\"\n  local-data: \"c.example.com. IN A 10.0.0.30\"\n"
+        );
     }
 
     #[test]
-    fn test_extract_client_ip_with_spaces() {
-        let mut headers = HeaderMap::new();
-        headers.insert("x-forwarded-for", "  203.0.113.1  ".parse().unwrap());
-        let addr: SocketAddr = "192.168.1.1:12345".parse().unwrap();
-
-        let ip = extract_client_ip(&headers, &addr);
-        // Should trim whitespace
-        assert_eq!(ip, "203.0.113.1");
-    }
+    fn test_path_store_write_is_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ddns.conf");
+        fs::write(
+            &path,
+            "server:\n  local-data: \"a.example.com IN A 10.0.0.1\"\n",
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        }
 
-    // ============================================================================
-    // TRAILING DOT NORMALIZATION TESTS
-    // ============================================================================
+        let updated = "server:\n  local-data: \"a.example.com. IN A 10.0.0.2\"\n";
+        path.write(updated).unwrap();
+        assert_eq!(path.read().unwrap(), updated);
+
+        // The temporary file was renamed into place, leaving nothing else behind
+        let entries: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![std::ffi::OsString::from("ddns.conf")]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+
+            // A symlinked config stays a symlink; the file it points to is replaced
+            let link = dir.path().join("link.conf");
+            std::os::unix::fs::symlink(&path, &link).unwrap();
+            link.write("server:\n").unwrap();
+            assert!(
+                fs::symlink_metadata(&link)
+                    .unwrap()
+                    .file_type()
+                    .is_symlink()
+            );
+            assert_eq!(fs::read_to_string(&path).unwrap(), "server:\n");
+        }
 
-    #[test]
-    fn test_normalize_domain_with_trailing_dot() {
-        assert_eq!(normalize_domain("example.com."), "example.com");
-        assert_eq!(normalize_domain("foo.example.com."), "foo.example.com");
+        // A missing file is created
+        let created = dir.path().join("new.conf");
+        created.write("server:\n").unwrap();
+        assert_eq!(fs::read_to_string(&created).unwrap(), "server:\n");
     }
 
+    #[cfg(unix)]
     #[test]
-    fn test_normalize_domain_without_trailing_dot() {
-        assert_eq!(normalize_domain("example.com"), "example.com");
-        assert_eq!(normalize_domain("foo.example.com"), "foo.example.com");
-    }
+    fn test_path_store_write_needs_writable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ddns.conf");
+        fs::write(&path, "server:\n").unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions don't stop root, so there is nothing to check then
+        let writable = fs::File::create(dir.path().join("probe")).is_ok();
+        let result = path.write("server:\n  # updated\n");
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        if writable {
+            return;
+        }
 
-    #[test]
-    fn test_normalize_domain_multiple_trailing_dots() {
-        assert_eq!(normalize_domain("example.com.."), "example.com");
-        assert_eq!(normalize_domain("example.com..."), "example.com");
+        let error = result.unwrap_err();
+        assert!(error.contains("must be writable"), "{}", error);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "server:\n");
     }
 
     #[test]
-    fn test_domain_exists_in_config_with_trailing_dot() {
-        let config = "local-data: \"example.com. IN A 192.168.1.1\"";
-        assert!(domain_exists_in_config(config, "example.com"));
+    fn test_is_lock_contention() {
+        assert!(is_lock_contention(&std::io::Error::from(
+            std::io::ErrorKind::WouldBlock
+        )));
+        assert!(is_lock_contention(&fs2::lock_contended_error()));
+        // Permanent failures are not mistaken for a lock
+        assert!(!is_lock_contention(&std::io::Error::from(
+            std::io::ErrorKind::PermissionDenied
+        )));
+        assert!(!is_lock_contention(&std::io::Error::from(
+            std::io::ErrorKind::NotFound
+        )));
     }
 
-    #[test]
-    fn test_domain_exists_in_config_without_trailing_dot() {
-        let config = "local-data: \"example.com IN A 192.168.1.1\"";
-        assert!(domain_exists_in_config(config, "example.com"));
-    }
+    #[tokio::test]
+    async fn test_update_endpoint_config_locked() {
+        use axum::http::{Request, StatusCode};
+        use fs2::FileExt;
+        use tower::ServiceExt;
 
-    #[test]
-    fn test_config_load_normalizes_trailing_dots() {
         let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
-        let config_file = NamedTempFile::new().unwrap();
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.reload_enabled = false;
+        config.locked_retry_after_secs = 7;
+        let app = create_app(Arc::new(config));
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(
+                    r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
+                ))
+                .unwrap()
+        };
 
-        // Create config with trailing dots
-        let config_content = format!(
-            r#"unbound_config_path = "{}"
+        // Another process, such as an editor, holds an exclusive lock on the file
+        let holder = fs::File::open(unbound_file.path()).unwrap();
+        holder.lock_exclusive().unwrap();
 
-[[domains]]
-name = "test.example.com."
-key = "test-key"
-"#,
-            unbound_file.path().display()
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "7");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["message"], CONFIG_LOCKED);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("192.168.1.1"), "{}", content);
+
+        // The retry succeeds once the lock is released
+        FileExt::unlock(&holder).unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("203.0.113.1"), "{}", content);
+    }
+
+    #[tokio::test]
+    async fn test_group_and_patch_config_locked() {
+        use axum::http::{Request, StatusCode};
+        use fs2::FileExt;
+        use tower::ServiceExt;
+
+        let unbound_file = create_unbound_config(Some(&[
+            ("home.example.com", "192.168.1.1"),
+            ("vpn.example.com", "192.168.1.2"),
+        ]));
+        let mut config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[
+                ("home.example.com", "home-key"),
+                ("vpn.example.com", "vpn-key"),
+            ]),
         );
-        fs::write(config_file.path(), config_content).unwrap();
+        config.reload_enabled = false;
+        config.locked_retry_after_secs = 7;
+        config.domain_groups = vec![DomainGroup {
+            name: "wan".to_string(),
+            key: "group-key".to_string(),
+            domains: vec![
+                "home.example.com".to_string(),
+                "vpn.example.com".to_string(),
+            ],
+        }];
+        let app = create_app(Arc::new(config));
+
+        let group = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer group-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=wan&ip=203.0.113.1"))
+                .unwrap()
+        };
+        let patch = || {
+            Request::builder()
+                .method("PATCH")
+                .uri("/domains/home.example.com")
+                .header("authorization", "Bearer home-key")
+                .body(Body::from(r#"{"ip":"203.0.113.2"}"#))
+                .unwrap()
+        };
 
-        // Load config and verify trailing dot is removed
-        let result = Config::load(config_file.path().to_str().unwrap());
-        assert!(result.is_ok());
-        let config = result.unwrap();
-        assert_eq!(config.domains[0].name, "test.example.com");
+        let holder = fs::File::open(unbound_file.path()).unwrap();
+        holder.lock_exclusive().unwrap();
+
+        for request in [group(), patch()] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(response.headers()["retry-after"], "7");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["message"], CONFIG_LOCKED);
+        }
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(!content.contains("203.0.113."), "{}", content);
+
+        FileExt::unlock(&holder).unwrap();
+        for request in [group(), patch()] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
     }
 
     #[test]
-    fn test_update_unbound_config_writes_trailing_dot() {
-        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+    fn test_update_unbound_config_equivalent_ipv6_unchanged() {
+        let store = MemoryStore::new(
+            "server:\n  local-data: \"v6.example.com. IN AAAA 0:0:0:0:0:0:0:1\"\n",
+        );
+        for ip in ["::1", "0::1", "0000:0000::0001"] {
+            let changed = update_unbound_config(
+                &store,
+                "v6.example.com",
+                &[ip],
+                None,
+                EntryFormat::default(),
+                false,
+                false,
+            )
+            .unwrap();
+            assert!(!changed, "{} was treated as a change", ip);
+        }
+        assert_eq!(store.writes(), 0);
 
-        // Update with normalized domain (no trailing dot)
-        update_unbound_config(
-            &unbound_file.path().to_path_buf(),
-            "test.example.com",
-            "10.0.0.1",
+        let changed = update_unbound_config(
+            &store,
+            "v6.example.com",
+            &["::2"],
+            None,
+            EntryFormat::default(),
+            false,
+            false,
         )
         .unwrap();
-
-        // Verify the written config has trailing dot (proper FQDN)
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+        assert!(changed);
     }
 
-    #[test]
-    fn test_update_unbound_config_handles_existing_trailing_dot() {
-        // Create config with trailing dot
-        let unbound_file = create_unbound_config(Some(&[("test.example.com.", "192.168.1.1")]));
+    #[tokio::test]
+    async fn test_update_endpoint_equivalent_ipv6_is_noop() {
+        use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tower::ServiceExt;
 
-        // Update should work even though existing config has trailing dot
-        update_unbound_config(
-            &unbound_file.path().to_path_buf(),
-            "test.example.com",
-            "10.0.0.1",
+        let unbound_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(
+            unbound_file.path(),
+            "local-data: \"v6.example.com IN AAAA 0:0:0:0:0:0:0:1\"\n",
         )
         .unwrap();
+        let config = create_test_config(
+            Some(unbound_file.path().to_path_buf()),
+            Some(&[("v6.example.com", "test-key")]),
+        );
 
-        // Verify the updated config has trailing dot
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let app = Router::new()
+            .route("/update", post(update_handler))
+            .with_state(state);
+
+        let request = |ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain":"v6.example.com","ip":"{}"}}"#,
+                    ip
+                )))
+                .unwrap()
+        };
+
+        // Both the config comparison and the remembered record see through the format
+        for ip in ["::1", "0:0::1"] {
+            let response = app.clone().oneshot(request(ip)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["message"], "No change, already ::1");
+        }
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
         let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"test.example.com. IN A 10.0.0.1\""));
+        assert!(content.contains("0:0:0:0:0:0:0:1"), "{}", content);
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_with_trailing_dot_in_domain() {
-        use axum::body::Body;
+    async fn test_update_endpoint_skips_unchanged_record() {
         use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("trailing.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "203.0.113.5")]));
+        let config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("trailing.example.com", "trailing-key")]),
-        ));
+            Some(&[("test.example.com", "test-key")]),
+        );
+        let original = fs::read_to_string(unbound_file.path()).unwrap();
+        let modified = fs::metadata(unbound_file.path())
+            .unwrap()
+            .modified()
+            .unwrap();
 
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(state);
 
-        // Send request with trailing dot in domain name
         let request = Request::builder()
             .method("POST")
             .uri("/update")
             .header("content-type", "application/json")
-            .header("authorization", "Bearer trailing-key")
+            .header("authorization", "Bearer test-key")
             .extension(ConnectInfo(
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
             .body(Body::from(
-                r#"{"domain":"trailing.example.com.","ip":"203.0.113.99"}"#,
+                r#"{"domain":"test.example.com","ip":"203.0.113.5"}"#,
             ))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
-
-        let status = response.status();
+        assert_eq!(response.status(), StatusCode::OK);
         let body = axum::body::to_bytes(response.into_body(), usize::MAX)
             .await
             .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-
-        // Either succeeds or fails on unbound-control (expected in test environment)
-        assert!(
-            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
-            "Unexpected response: {} - {}",
-            status,
-            body_str
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["message"], "No change, already 203.0.113.5");
+
+        assert_eq!(reloads.load(Ordering::SeqCst), 0);
+        assert_eq!(fs::read_to_string(unbound_file.path()).unwrap(), original);
+        assert_eq!(
+            fs::metadata(unbound_file.path())
+                .unwrap()
+                .modified()
+                .unwrap(),
+            modified
         );
-
-        // Verify config was updated with proper FQDN (trailing dot)
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"trailing.example.com. IN A 203.0.113.99\""));
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_with_x_forwarded_for() {
-        use axum::body::Body;
+    async fn test_update_retry_reloads_after_failed_reload() {
         use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("proxy.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("proxy.example.com", "proxy-key")]),
-        ));
+            Some(&[("test.example.com", "test-key")]),
+        );
 
+        // The first reload fails, every later one succeeds
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| match counter.fetch_add(1, Ordering::SeqCst) {
+            0 => Err("unbound-control failed".to_string()),
+            _ => Ok(()),
+        });
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
-
-        let request = Request::builder()
-            .method("POST")
-            .uri("/update")
-            .header("content-type", "application/json")
-            .header("authorization", "Bearer proxy-key")
-            .header("x-forwarded-for", "203.0.113.50")
-            .extension(ConnectInfo(
-                "192.168.1.100:12345".parse::<SocketAddr>().unwrap(),
-            ))
-            .body(Body::from(r#"{"domain":"proxy.example.com"}"#))
-            .unwrap();
-
-        let response = app.oneshot(request).await.unwrap();
+            .with_state(state);
+
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from("domain=test.example.com&ip=203.0.113.5"))
+                .unwrap()
+        };
 
-        let status = response.status();
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
 
-        // Either succeeds or fails on unbound-control (expected in test environment)
-        assert!(
-            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
-            "Unexpected response: {} - {}",
-            status,
-            body_str
-        );
+        // The record is already written, but the retry still gets it loaded
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
 
-        // Verify config was updated with the IP from X-Forwarded-For, not the connection IP
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"proxy.example.com. IN A 203.0.113.50\""));
-        assert!(!content.contains("192.168.1.100")); // Should NOT use the proxy IP
+        // Once loaded, repeating it is a plain no-op again
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_with_x_real_ip() {
-        use axum::body::Body;
+    async fn test_update_endpoint_post_reload_delay() {
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("realip.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("realip.example.com", "realip-key")]),
-        ));
-
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.post_reload_delay_ms = 250;
+
+        let slept = Arc::new(Mutex::new(Vec::new()));
+        let recorded = slept.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(|_| Ok(()));
+        state.sleep = Arc::new(move |duration| {
+            recorded.lock().unwrap().push(duration);
+            Box::pin(async {})
+        });
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(state);
 
         let request = Request::builder()
             .method("POST")
             .uri("/update")
             .header("content-type", "application/json")
-            .header("authorization", "Bearer realip-key")
-            .header("x-real-ip", "203.0.113.75")
+            .header("authorization", "Bearer test-key")
             .extension(ConnectInfo(
-                "192.168.1.100:12345".parse::<SocketAddr>().unwrap(),
+                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+            ))
+            .body(Body::from(
+                r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
             ))
-            .body(Body::from(r#"{"domain":"realip.example.com"}"#))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
-
-        let status = response.status();
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-
-        // Either succeeds or fails on unbound-control (expected in test environment)
-        assert!(
-            status == StatusCode::OK || body_str.contains("Failed to reload Unbound"),
-            "Unexpected response: {} - {}",
-            status,
-            body_str
-        );
-
-        // Verify config was updated with the IP from X-Real-IP and proper FQDN (trailing dot)
-        let content = fs::read_to_string(unbound_file.path()).unwrap();
-        assert!(content.contains("local-data: \"realip.example.com. IN A 203.0.113.75\""));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(*slept.lock().unwrap(), vec![Duration::from_millis(250)]);
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_invalid_ip_address() {
-        use axum::body::Body;
+    async fn test_update_endpoint_logs_within_request_span() {
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
         let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
             Some(&[("test.example.com", "test-key")]),
-        ));
-
+        );
+        config.reload_enabled = false;
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(Arc::new(config)));
 
         let request = Request::builder()
             .method("POST")
@@ -1469,36 +14229,68 @@ key = "test-key"
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
             .body(Body::from(
-                r#"{"domain":"test.example.com","ip":"not-an-ip-address"}"#,
+                r#"{"domain":"test.example.com","ip":"203.0.113.1"}"#,
             ))
             .unwrap();
 
+        let (_guard, logs) = capture_logs();
         let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Invalid IPv4 address"));
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let span_of = |message: &str| {
+            let line = logs.lines().find(|line| line.contains(message)).unwrap();
+            let start = line.find("update{").unwrap();
+            line[start..start + line[start..].find('}').unwrap() + 1].to_string()
+        };
+        let received = span_of("Received update request");
+        let updated = span_of("DNS record updated successfully");
+        assert!(received.contains("request_id="));
+        assert!(received.contains("domain=test.example.com"));
+        assert_eq!(received, updated);
+    }
+
+    const TWO_VIEWS: &str = concat!(
+        "server:\n",
+        "  local-data: \"other.example.com. IN A 192.168.1.9\"\n",
+        "view:\n",
+        "  name: \"lan\"\n",
+        "  local-data: \"test.example.com. IN A 192.168.1.1\"\n",
+        "view:\n",
+        "  name: \"vpn\"\n",
+        "  local-data: \"test.example.com. IN A 10.8.0.1\"\n",
+        "remote-control:\n",
+        "  control-enable: yes\n",
+    );
+
+    #[test]
+    fn test_view_range() {
+        let lan = view_range(TWO_VIEWS, "lan").unwrap();
+        assert!(TWO_VIEWS[lan.clone()].starts_with("view:\n  name: \"lan\""));
+        assert!(TWO_VIEWS[lan].contains("192.168.1.1"));
+        let vpn = view_range(TWO_VIEWS, "vpn").unwrap();
+        assert!(TWO_VIEWS[vpn.clone()].contains("10.8.0.1"));
+        assert!(!TWO_VIEWS[vpn].contains("control-enable"));
+        assert!(view_range(TWO_VIEWS, "guest").is_none());
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_malformed_ipv4_address() {
-        use axum::body::Body;
+    async fn test_update_endpoint_targets_section() {
         use axum::http::{Request, StatusCode};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let unbound_file = NamedTempFile::new().unwrap();
+        fs::write(unbound_file.path(), TWO_VIEWS).unwrap();
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
             Some(&[("test.example.com", "test-key")]),
-        ));
-
+        );
+        config.reload_enabled = false;
+        config.trailing_dot = true;
+        config.domains[0].section = Some("vpn".to_string());
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(AppState::new(Arc::new(config)));
 
         let request = Request::builder()
             .method("POST")
@@ -1509,95 +14301,207 @@ key = "test-key"
                 "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
             ))
             .body(Body::from(
-                r#"{"domain":"test.example.com","ip":"256.1.2.3"}"#,
+                r#"{"domain":"test.example.com","ip":"10.8.0.2"}"#,
             ))
             .unwrap();
 
         let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Invalid IPv4 address"));
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert_eq!(
+            content,
+            TWO_VIEWS.replace("10.8.0.1", "10.8.0.2"),
+            "only the vpn view should change"
+        );
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_ipv6_rejected() {
-        use axum::body::Body;
+    async fn test_update_coalesces_identical_concurrent_requests() {
         use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
         use tower::ServiceExt;
 
-        let unbound_file = create_unbound_config(Some(&[("ipv6.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
+        let mut config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
-            Some(&[("ipv6.example.com", "ipv6-key")]),
-        ));
-
+            Some(&[("test.example.com", "test-key")]),
+        );
+        config.coalesce_updates = true;
+        // Keep the first update in progress while the others arrive
+        config.post_reload_delay_ms = 200;
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
-
-        let request = Request::builder()
-            .method("POST")
-            .uri("/update")
-            .header("content-type", "application/json")
-            .header("authorization", "Bearer ipv6-key")
-            .extension(ConnectInfo(
-                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            ))
-            .body(Body::from(
-                r#"{"domain":"ipv6.example.com","ip":"2001:db8::1"}"#,
-            ))
-            .unwrap();
-
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            .with_state(state);
+
+        let request = |ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain":"test.example.com","ip":"{}"}}"#,
+                    ip
+                )))
+                .unwrap()
+        };
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
-            .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Invalid IPv4 address"));
+        let responses = futures_util::future::join_all(
+            (0..10).map(|_| app.clone().oneshot(request("203.0.113.1"))),
+        )
+        .await;
+        let mut bodies = Vec::new();
+        for response in responses {
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            bodies.push(
+                axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert!(bodies.iter().all(|body| *body == bodies[0]));
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+
+        // Once finished, the same update runs again (now a no-op without a reload), and a
+        // different one is not merged
+        let responses = futures_util::future::join_all([
+            app.clone().oneshot(request("203.0.113.1")),
+            app.clone().oneshot(request("203.0.113.2")),
+        ])
+        .await;
+        assert!(
+            responses
+                .into_iter()
+                .all(|response| response.unwrap().status() == StatusCode::OK)
+        );
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
     }
 
     #[tokio::test]
-    async fn test_update_endpoint_empty_ip_address() {
-        use axum::body::Body;
+    async fn test_update_idempotency_key_replays_response() {
         use axum::http::{Request, StatusCode};
+        use std::sync::atomic::{AtomicUsize, Ordering};
         use tower::ServiceExt;
 
         let unbound_file = create_unbound_config(Some(&[("test.example.com", "192.168.1.1")]));
-
-        let config = Arc::new(create_test_config(
+        let config = create_test_config(
             Some(unbound_file.path().to_path_buf()),
             Some(&[("test.example.com", "test-key")]),
-        ));
+        );
 
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        let mut state = AppState::new(Arc::new(config));
+        state.reloader = Arc::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
         let app = Router::new()
             .route("/update", post(update_handler))
-            .with_state(config);
+            .with_state(state);
+
+        let request_for = |idempotency_key: &str, ip: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/update")
+                .header("content-type", "application/json")
+                .header("authorization", "Bearer test-key")
+                .header("idempotency-key", idempotency_key)
+                .extension(ConnectInfo(
+                    "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
+                ))
+                .body(Body::from(format!(
+                    r#"{{"domain":"test.example.com","ip":"{}"}}"#,
+                    ip
+                )))
+                .unwrap()
+        };
+        let request = |idempotency_key: &str| request_for(idempotency_key, "203.0.113.1");
 
-        let request = Request::builder()
-            .method("POST")
-            .uri("/update")
-            .header("content-type", "application/json")
-            .header("authorization", "Bearer test-key")
-            .extension(ConnectInfo(
-                "127.0.0.1:12345".parse::<SocketAddr>().unwrap(),
-            ))
-            .body(Body::from(r#"{"domain":"test.example.com","ip":""}"#))
+        let first = app.clone().oneshot(request("abc")).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let first_body = axum::body::to_bytes(first.into_body(), usize::MAX)
+            .await
             .unwrap();
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
 
-        let response = app.oneshot(request).await.unwrap();
-        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        // Put the old record back; a replay must not write the file again.
+        fs::write(
+            unbound_file.path(),
+            "local-data: \"test.example.com. IN A 192.168.1.1\"\n",
+        )
+        .unwrap();
 
-        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        let replay = app.clone().oneshot(request("abc")).await.unwrap();
+        assert_eq!(replay.status(), StatusCode::OK);
+        let replay_body = axum::body::to_bytes(replay.into_body(), usize::MAX)
             .await
             .unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        assert!(body_str.contains("Invalid IPv4 address"));
+        assert_eq!(replay_body, first_body);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("192.168.1.1"));
+
+        // Reusing the key for a different update is refused rather than replayed
+        let reused = app
+            .clone()
+            .oneshot(request_for("abc", "203.0.113.9"))
+            .await
+            .unwrap();
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(!content.contains("203.0.113.9"));
+
+        let fresh = app.oneshot(request("def")).await.unwrap();
+        assert_eq!(fresh.status(), StatusCode::OK);
+        assert_eq!(reloads.load(Ordering::SeqCst), 2);
+        let content = fs::read_to_string(unbound_file.path()).unwrap();
+        assert!(content.contains("203.0.113.1"));
+    }
+
+    #[test]
+    fn test_idempotency_cache_drops_oldest_past_limit() {
+        let cache = IdempotencyCache::default();
+        let ttl = Duration::from_secs(300);
+        let start = Instant::now();
+        let entry = |offset: usize| IdempotentResponse {
+            request: String::new(),
+            response: CachedResponse {
+                stored_at: start + Duration::from_millis(offset as u64),
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::new(),
+            },
+        };
+        for i in 0..=MAX_IDEMPOTENT_RESPONSES {
+            cache.insert(i.to_string(), entry(i), ttl);
+        }
+
+        let now = start + Duration::from_secs(1);
+        assert_eq!(
+            cache.responses.lock().unwrap().len(),
+            MAX_IDEMPOTENT_RESPONSES
+        );
+        assert!(cache.get("0", ttl, now).is_none());
+        assert!(cache.get("1", ttl, now).is_some());
+        assert!(
+            cache
+                .get(&MAX_IDEMPOTENT_RESPONSES.to_string(), ttl, now)
+                .is_some()
+        );
     }
 }